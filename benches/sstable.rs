@@ -1,7 +1,10 @@
 use criterion::{criterion_group, criterion_main, Criterion, Throughput};
 use sstable::avl::AVLTree;
+use sstable::options::{Options, ReaderBackend};
+use sstable::sst::SST;
 use rand::{thread_rng, Rng};
 use std::iter;
+use std::sync::Arc;
 
 fn generate_random_string(length: usize) -> String {
     let mut rng = thread_rng();
@@ -78,5 +81,36 @@ fn avl_unset_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, avl_set_benchmark, avl_get_benchmark, avl_unset_benchmark);
+fn sst_get_benchmark(c: &mut Criterion) {
+    let path = std::env::temp_dir().join("sstable_bench.sst");
+    let _ = std::fs::remove_file(&path);
+
+    // Pre-fill the table with random strings
+    let writer = SST::new(path.clone(), Arc::new(Options::default()));
+    let mut records = Vec::new();
+    for _ in 0..10_000 {
+        let key = generate_random_string(6);
+        let value = generate_random_string(100);
+        records.push((writer.set(&key, &value).unwrap(), key));
+    }
+
+    let mut group = c.benchmark_group("SST Operations");
+    group.throughput(Throughput::Elements(records.len() as u64));
+
+    for (name, reader) in [("buffered get ops/sec", ReaderBackend::Buffered), ("mmap get ops/sec", ReaderBackend::Mmap)] {
+        let sst = SST::new(path.clone(), Arc::new(Options { reader, ..Options::default() }));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for (offset, key) in &records {
+                    sst.get(key, *offset).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+criterion_group!(benches, avl_set_benchmark, avl_get_benchmark, avl_unset_benchmark, sst_get_benchmark);
 criterion_main!(benches); 
\ No newline at end of file
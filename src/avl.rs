@@ -1,13 +1,24 @@
 use std::cmp::Ordering;
-use std::sync::{Arc, RwLock};
-use std::thread::sleep;
-use std::time::Duration;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::Error;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, Condvar, LazyLock, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::mem::size_of;
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::db::Db;
 use crate::idx::IDX;
+use crate::sst::SSTValue;
 
+/// Starts at the clock in nanoseconds, so a restarted process doesn't hand out the numbers of
+/// the one before.
+static NEXT_SEQUENCE: LazyLock<AtomicU64> =
+    LazyLock::new(|| AtomicU64::new(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64));
 
-const SIZE_TO_MOVE_AVL_TO_DISK: f64 = 10f64;
-
+fn next_sequence() -> u64 {
+    NEXT_SEQUENCE.fetch_add(1, AtomicOrdering::Relaxed)
+}
 
 #[derive(Debug)]
 pub struct AVLNode {
@@ -15,6 +26,13 @@ pub struct AVLNode {
     pub right: Option<Box<AVLNode>>,
     pub key: String,
     pub value: String,
+    /// Merge operands waiting for an older value, oldest first.
+    /// A node with operands has no value of its own.
+    pub operands: Vec<String>,
+    /// A tombstone, the key was deleted and has neither a value nor operands.
+    pub deleted: bool,
+    /// Changes with every write of the key, no two writes in memory share one.
+    pub sequence: u64,
     pub height: i32,
 }
 
@@ -30,14 +48,39 @@ impl AVLNode {
             right,
             key: key.to_string(),
             value: value.to_string(),
+            operands: Vec::new(),
+            deleted: false,
+            sequence: next_sequence(),
             height: 1,
         }
     }
+
+    /// Approximate bytes the node takes in memory, children excluded.
+    fn size(&self) -> usize {
+        size_of::<AVLNode>()
+            + self.key.len()
+            + self.value.len()
+            + self.operands.iter().map(String::len).sum::<usize>()
+    }
 }
 
-#[derive(Debug)]
 pub struct AVLTree {
     pub root: Option<Box<AVLNode>>,
+    comparator: Arc<dyn Comparator>,
+    /// Kept up to date by every write, so it never needs a walk over the tree.
+    size: usize,
+    count: usize,
+}
+
+impl fmt::Debug for AVLTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AVLTree")
+            .field("root", &self.root)
+            .field("comparator", &self.comparator.name())
+            .field("size", &self.size)
+            .field("count", &self.count)
+            .finish()
+    }
 }
 
 impl Default for AVLTree {
@@ -48,18 +91,34 @@ impl Default for AVLTree {
 
 impl AVLTree {
     pub fn new() -> AVLTree {
-        AVLTree { root: None }
+        Self::with_comparator(Arc::new(BytewiseComparator))
+    }
+
+    pub fn with_comparator(comparator: Arc<dyn Comparator>) -> AVLTree {
+        AVLTree { root: None, comparator, size: 0, count: 0 }
     }
 
     pub fn clear(&mut self) {
         self.root = None;
+        self.size = 0;
+        self.count = 0;
+    }
+
+    /// Approximate bytes the keys, values and operands take in memory.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of keys.
+    pub fn count(&self) -> usize {
+        self.count
     }
 
     pub fn get(&self, key: &str) -> Option<&AVLNode> {
         let mut root_node = self.root.as_ref();
 
         while let Some(current_node) = root_node {
-            match key.cmp(current_node.key.as_str()) {
+            match self.comparator.compare(key, current_node.key.as_str()) {
                 Ordering::Equal => {
                     return Some(current_node);
                 }
@@ -75,17 +134,75 @@ impl AVLTree {
         None
     }
 
-    pub fn feel_from_idx(&mut self, idx: &IDX) -> &AVLTree {
-        let iter = idx.iter().unwrap();
-        for i in iter {
-            self.set(i.key.as_str(), i.value.as_str())
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut AVLNode> {
+        let mut root_node = self.root.as_mut();
+
+        while let Some(current_node) = root_node {
+            match self.comparator.compare(key, current_node.key.as_str()) {
+                Ordering::Equal => {
+                    return Some(current_node);
+                }
+                Ordering::Less => {
+                    root_node = current_node.left.as_mut();
+                }
+                Ordering::Greater => {
+                    root_node = current_node.right.as_mut();
+                }
+            }
         }
-        self
+
+        None
+    }
+
+    /// Queues a merge operand for the key.
+    /// Operands on top of a value or a tombstone are expected to be folded by the caller instead.
+    pub fn merge(&mut self, key: &str, operand: &str) {
+        if self.get(key).is_none() {
+            self.set(key, "");
+        }
+
+        if let Some(node) = self.get_mut(key) {
+            node.operands.push(operand.to_string());
+            node.sequence = next_sequence();
+            self.size += operand.len();
+        }
+    }
+
+    pub fn feel_from_idx(&mut self, idx: &IDX) -> Result<&AVLTree, Error> {
+        for record in idx.records()? {
+            let record = record?;
+            match record.value {
+                SSTValue::Merge(operands) => {
+                    for operand in operands {
+                        self.merge(&record.key, &operand);
+                    }
+                }
+                SSTValue::Tombstone => self.delete(&record.key),
+                value => {
+                    let value = idx.sst().resolve(&record.key, value)?;
+                    self.set(&record.key, &value);
+                }
+            }
+        }
+        Ok(self)
     }
     
 
+    /// Leaves a tombstone, which hides the older values of the key once it is flushed.
+    pub fn delete(&mut self, key: &str) {
+        self.set(key, "");
+        if let Some(node) = self.get_mut(key) {
+            node.deleted = true;
+        }
+    }
+
+    /// Forgets the key, unlike `delete` its older values show again.
     pub fn unset(&mut self, key: &str) {
-        self.root = Self::remove(self.root.take(), key);
+        if let Some(node) = self.get(key) {
+            self.size -= node.size();
+            self.count -= 1;
+        }
+        self.root = Self::remove(self.root.take(), key, self.comparator.as_ref());
     }
 
     fn get_largest_node(node: &mut Option<Box<AVLNode>>) -> Option<Box<AVLNode>> {
@@ -99,15 +216,15 @@ impl AVLTree {
         None
     }
 
-    fn remove(node: Option<Box<AVLNode>>, key: &str) -> Option<Box<AVLNode>> {
+    fn remove(node: Option<Box<AVLNode>>, key: &str, comparator: &dyn Comparator) -> Option<Box<AVLNode>> {
         match node {
             Some(mut n) => {
-                match key.cmp(&n.key) {
+                match comparator.compare(key, &n.key) {
                     Ordering::Less => {
-                        n.left = Self::remove(n.left.take(), key);
+                        n.left = Self::remove(n.left.take(), key, comparator);
                     }
                     Ordering::Greater => {
-                        n.right = Self::remove(n.right.take(), key);
+                        n.right = Self::remove(n.right.take(), key, comparator);
                     }
                     Ordering::Equal => {
                         if n.right.is_none() {
@@ -129,21 +246,29 @@ impl AVLTree {
     }
 
     pub fn set(&mut self, key: &str, value: &str) {
-        self.root = Self::insert(self.root.take(), key, value);
+        match self.get(key) {
+            Some(node) => self.size -= node.size(),
+            None => self.count += 1,
+        }
+        self.size += size_of::<AVLNode>() + key.len() + value.len();
+        self.root = Self::insert(self.root.take(), key, value, self.comparator.as_ref());
     }
 
-    fn insert(node: Option<Box<AVLNode>>, key: &str, value: &str) -> Option<Box<AVLNode>> {
+    fn insert(node: Option<Box<AVLNode>>, key: &str, value: &str, comparator: &dyn Comparator) -> Option<Box<AVLNode>> {
         match node {
             Some(mut n) => {
-                match key.cmp(&n.key) {
+                match comparator.compare(key, &n.key) {
                     Ordering::Less => {
-                        n.left = Self::insert(n.left.take(), key, value);
+                        n.left = Self::insert(n.left.take(), key, value, comparator);
                     }
                     Ordering::Greater => {
-                        n.right = Self::insert(n.right.take(), key, value);
+                        n.right = Self::insert(n.right.take(), key, value, comparator);
                     }
                     Ordering::Equal => {
                         n.value = value.to_string();
+                        n.operands.clear();
+                        n.deleted = false;
+                        n.sequence = next_sequence();
                         return Some(n);
                     }
                 }
@@ -238,43 +363,77 @@ impl AVLTreeSingleton {
             instance: RwLock::new(AVLTree::new()),
         }
     }
+
+    pub fn with_comparator(comparator: Arc<dyn Comparator>) -> AVLTreeSingleton {
+        AVLTreeSingleton {
+            instance: RwLock::new(AVLTree::with_comparator(comparator)),
+        }
+    }
     
     pub fn get_instance(&self) -> &RwLock<AVLTree> {
         &self.instance
     }
 }
 
-fn calculate_size(node: &Option<Box<AVLNode>>) -> usize {
-    match node {
-        Some(n) => {
-            let node_size = size_of::<AVLNode>()
-                + n.key.len()
-                + n.value.len();
-            node_size + calculate_size(&n.left) + calculate_size(&n.right)
+/// Names of the column families whose memtable is over its limits and waits to be flushed.
+#[derive(Default)]
+pub struct FlushSignal {
+    pending: Mutex<BTreeSet<String>>,
+    condvar: Condvar,
+    closed: AtomicBool,
+}
+
+impl FlushSignal {
+    pub fn notify(&self, column_family: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.insert(column_family.to_string()) {
+            self.condvar.notify_all();
         }
-        None => 0,
+    }
+
+    /// Blocks until a flush is requested and takes every pending request.
+    ///
+    /// Once closed it returns right away, with an empty set when nothing is pending.
+    pub fn wait(&self) -> BTreeSet<String> {
+        let mut pending = self.condvar.wait_while(self.pending.lock().unwrap(), |pending| {
+            pending.is_empty() && !self.closed.load(AtomicOrdering::Relaxed)
+        }).unwrap();
+        std::mem::take(&mut *pending)
+    }
+
+    /// Wakes up `wait` for good, see `Db::shutdown`.
+    pub fn close(&self) {
+        let _pending = self.pending.lock().unwrap();
+        self.closed.store(true, AtomicOrdering::Relaxed);
+        self.condvar.notify_all();
     }
 }
 
-pub fn check_size(tree: Arc<AVLTreeSingleton>) {
-    let tree = tree.get_instance();
+/// Flushes memtables as they fill up, until the database shuts down and nothing is pending.
+pub fn check_size(db: Arc<Db>) {
     loop {
-        sleep(Duration::from_secs(5));
-        let current_tree = tree.read();
-        let megabytes =  calculate_size(&current_tree.unwrap().root) as f64 / 1_048_576_f64;
-        println!("AVL Tree Size > {megabytes:.2} MB");
-        if megabytes > SIZE_TO_MOVE_AVL_TO_DISK {
-            println!("AVL Tree Size has reached the limit, lets save it to the disk");
-            
-            let mut tree = tree.write().unwrap();
-            let idx = IDX::new(None);
-            match idx.fill_from_avl(&tree) {
-                Ok(_) => {}
-                Err(e) => println!("Failed to fill AVL tree: {}", e),
+        let pending = db.flush_signal().wait();
+        if pending.is_empty() {
+            tracing::info!("Flush thread stopped");
+            return;
+        }
+
+        for name in pending {
+            let Some(column_family) = db.column_family(&name) else { continue };
+
+            tracing::info!("AVL Tree of {name} has reached the limit, lets save it to the disk");
+            match column_family.flush_immutables() {
+                Ok(flushed) => tracing::info!("{flushed} AVL Trees were saved to the disk"),
+                Err(e) => {
+                    tracing::error!("Failed to fill AVL tree: {}", e);
+                    // The memtables stay queued, try again later unless the database shuts down
+                    if db.wait_for_shutdown(Duration::from_secs(1)) {
+                        tracing::warn!("Flush thread stopped, the memtables of {name} are left to the write-ahead log");
+                        return;
+                    }
+                    db.flush_signal().notify(&name);
+                }
             };
-            
-            tree.clear();
-            println!("AVL Tree was saved to the disk");
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,280 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::db::Db;
+
+static NEXT_CHECKPOINT: AtomicU64 = AtomicU64::new(0);
+
+/// A file of a backup, `path` is relative to the database directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupFile {
+    pub path: String,
+    pub crc32: u32,
+    pub size: u64,
+}
+
+impl BackupFile {
+    /// Name of the copy in the shared directory, the same file is only stored once.
+    fn shared_name(&self) -> String {
+        let file_name = self.path.rsplit('/').next().unwrap();
+        format!("{:08x}_{}_{}", self.crc32, self.size, file_name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub id: u64,
+    /// Seconds since the epoch.
+    pub timestamp: u64,
+    pub files: Vec<BackupFile>,
+}
+
+impl BackupInfo {
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+}
+
+/// Incremental backups of a database into one directory.
+///
+/// Tables never change once written, so every backup only copies the files no earlier backup
+/// has, into `shared/`. `meta/<id>` lists the files of a backup with their checksums:
+///
+/// ```text
+/// timestamp=1700000000
+/// file=sessions/1700000000.sst 8d2f10aa 4096
+/// ```
+///
+/// Creating, purging and restoring hold a lock on `LOCK`, so engines in several threads or
+/// processes can share the directory.
+pub struct BackupEngine {
+    dir: PathBuf,
+}
+
+impl BackupEngine {
+    const META_DIR: &'static str = "meta";
+    const SHARED_DIR: &'static str = "shared";
+    const LOCK_FILE: &'static str = "LOCK";
+    /// Prefix of the checkpoints backups are taken from, inside the database directory so
+    /// the files can be hard linked.
+    pub const CHECKPOINT_PREFIX: &'static str = ".backup_checkpoint_";
+
+    pub fn open(dir: impl AsRef<Path>) -> Result<BackupEngine, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join(Self::META_DIR))?;
+        fs::create_dir_all(dir.join(Self::SHARED_DIR))?;
+        Ok(BackupEngine { dir })
+    }
+
+    /// Held until the returned file is dropped, shared by readers of the backups.
+    fn lock(&self, exclusive: bool) -> Result<File, Error> {
+        let file = File::options().create(true).truncate(false).write(true).open(self.dir.join(Self::LOCK_FILE))?;
+        if exclusive { file.lock()? } else { file.lock_shared()? }
+        Ok(file)
+    }
+
+    /// Backs up a checkpoint of the database, see `Db::checkpoint`.
+    pub fn create_backup(&self, db: &Db) -> Result<BackupInfo, Error> {
+        let _lock = self.lock(true)?;
+        let id = self.list_backups()?.last().map_or(1, |backup| backup.id + 1);
+
+        let checkpoint = db.path().join(format!(
+            "{}{}_{}",
+            Self::CHECKPOINT_PREFIX,
+            std::process::id(),
+            NEXT_CHECKPOINT.fetch_add(1, Ordering::Relaxed),
+        ));
+        db.checkpoint(&checkpoint)?;
+
+        let result = self.store_checkpoint(id, &checkpoint);
+        fs::remove_dir_all(&checkpoint)?;
+        result
+    }
+
+    fn store_checkpoint(&self, id: u64, checkpoint: &Path) -> Result<BackupInfo, Error> {
+        let mut files = Vec::new();
+        let mut copied = 0;
+        for (path, source) in Self::files(checkpoint)? {
+            let (crc32, size) = checksum(&mut File::open(&source)?, &mut io::sink())?;
+            let file = BackupFile { path, crc32, size };
+
+            let shared = self.dir.join(Self::SHARED_DIR).join(file.shared_name());
+            if !shared.exists() {
+                /* Copied aside and renamed, so a crash never leaves a file that looks complete */
+                let tmp_path = shared.with_extension("tmp");
+                fs::copy(&source, &tmp_path)?;
+                fs::rename(&tmp_path, &shared)?;
+                copied += 1;
+            }
+            files.push(file);
+        }
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let backup = BackupInfo { id, timestamp, files };
+        self.store_meta(&backup)?;
+
+        tracing::info!("Backup {} created, {} of {} files copied", backup.id, copied, backup.files.len());
+        Ok(backup)
+    }
+
+    /// Files of the checkpoint by their path relative to it, family directories are one level deep.
+    fn files(checkpoint: &Path) -> Result<Vec<(String, PathBuf)>, Error> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(checkpoint)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if path.is_dir() {
+                for entry in fs::read_dir(&path)? {
+                    let file = entry?.path();
+                    files.push((format!("{}/{}", name, file.file_name().unwrap().to_string_lossy()), file));
+                }
+            } else {
+                files.push((name, path));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn store_meta(&self, backup: &BackupInfo) -> Result<(), Error> {
+        let path = self.dir.join(Self::META_DIR).join(backup.id.to_string());
+        let tmp_path = path.with_extension("tmp");
+
+        let mut content = format!("timestamp={}\n", backup.timestamp);
+        for file in &backup.files {
+            content.push_str(&format!("file={} {:08x} {}\n", file.path, file.crc32, file.size));
+        }
+
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    fn load_meta(&self, id: u64) -> Result<BackupInfo, Error> {
+        let content = match fs::read_to_string(self.dir.join(Self::META_DIR).join(id.to_string())) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(Error::new(ErrorKind::NotFound, format!("Backup {id} does not exist")));
+            }
+            Err(e) => return Err(e),
+        };
+        let malformed = || Error::new(ErrorKind::InvalidData, format!("Malformed meta file of backup {id}"));
+
+        let mut timestamp = None;
+        let mut files = Vec::new();
+        for line in content.lines() {
+            match line.split_once('=') {
+                Some(("timestamp", value)) => timestamp = Some(value.parse().map_err(|_| malformed())?),
+                Some(("file", value)) => {
+                    let mut fields = value.rsplitn(3, ' ');
+                    let (Some(size), Some(crc32), Some(path)) = (fields.next(), fields.next(), fields.next()) else {
+                        return Err(malformed());
+                    };
+                    files.push(BackupFile {
+                        path: path.to_string(),
+                        crc32: u32::from_str_radix(crc32, 16).map_err(|_| malformed())?,
+                        size: size.parse().map_err(|_| malformed())?,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        Ok(BackupInfo { id, timestamp: timestamp.ok_or_else(malformed)?, files })
+    }
+
+    /// Every backup, oldest first.
+    pub fn list_backups(&self) -> Result<Vec<BackupInfo>, Error> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(self.dir.join(Self::META_DIR))? {
+            if let Some(id) = entry?.file_name().to_str().and_then(|name| name.parse::<u64>().ok()) {
+                ids.push(id);
+            }
+        }
+        ids.sort();
+        ids.into_iter().map(|id| self.load_meta(id)).collect()
+    }
+
+    /// Keeps the `keep` newest backups and removes the shared files only older ones used.
+    /// Returns how many backups were removed.
+    pub fn purge_old_backups(&self, keep: usize) -> Result<usize, Error> {
+        let _lock = self.lock(true)?;
+        let backups = self.list_backups()?;
+        let purged = backups.len().saturating_sub(keep);
+
+        for backup in &backups[..purged] {
+            fs::remove_file(self.dir.join(Self::META_DIR).join(backup.id.to_string()))?;
+        }
+
+        let used = backups[purged..].iter()
+            .flat_map(|backup| backup.files.iter().map(BackupFile::shared_name))
+            .collect::<HashSet<_>>();
+        for entry in fs::read_dir(self.dir.join(Self::SHARED_DIR))? {
+            let entry = entry?;
+            if !used.contains(entry.file_name().to_string_lossy().as_ref()) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(purged)
+    }
+
+    /// Copies the backup into `dest`, which must not exist yet, checking every file against
+    /// its checksum on the way. `dest` only shows up once every file is in place.
+    pub fn restore(&self, id: u64, dest: impl AsRef<Path>) -> Result<(), Error> {
+        let dest = dest.as_ref();
+        if dest.exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", dest.display())));
+        }
+        let _lock = self.lock(false)?;
+        let backup = self.load_meta(id)?;
+
+        /* Like `Db::checkpoint`, a leftover is never removed */
+        let tmp = PathBuf::from(format!("{}.tmp", dest.display()));
+        if let Some(parent) = tmp.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::create_dir(&tmp).map_err(|e| Error::new(e.kind(), format!("Failed to create {}: {}", tmp.display(), e)))?;
+        let result = (|| {
+            for file in &backup.files {
+                let target = tmp.join(&file.path);
+                fs::create_dir_all(target.parent().unwrap())?;
+
+                let mut source = File::open(self.dir.join(Self::SHARED_DIR).join(file.shared_name()))?;
+                let mut writer = BufWriter::new(File::create(&target)?);
+                let (crc32, size) = checksum(&mut source, &mut writer)?;
+                writer.flush()?;
+
+                if crc32 != file.crc32 || size != file.size {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("Checksum mismatch in {} of backup {id}", file.path)));
+                }
+            }
+            fs::rename(&tmp, dest)
+        })();
+
+        if result.is_err() {
+            let _ = fs::remove_dir_all(&tmp);
+        }
+        result
+    }
+}
+
+/// Copies `source` into `writer` and returns its CRC32 and length.
+fn checksum(source: &mut File, writer: &mut impl Write) -> Result<(u32, u64), Error> {
+    let mut reader = BufReader::new(source);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut size = 0;
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        writer.write_all(&buf[..read])?;
+        size += read as u64;
+    }
+    Ok((hasher.finalize(), size))
+}
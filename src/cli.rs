@@ -1,43 +1,498 @@
-use std::env;
-use crate::idx;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use crate::backup::BackupEngine;
+use crate::column_family::ColumnFamily;
+use crate::comparator::{self, BytewiseComparator, Comparator};
+use crate::db::{Db, DEFAULT_COLUMN_FAMILY};
+use crate::export::{self, Format};
+use crate::idx::IDX;
+use crate::manifest::Manifest;
+use crate::merge::U64AddOperator;
+use crate::migrate;
+use crate::options::Options;
+use crate::repl;
+use crate::sst::SSTValue;
+use crate::vlog::ValueLog;
 
+/// Exit code of `get` for a missing key, errors exit with 1 and bad arguments with 2.
+const EXIT_NOT_FOUND: u8 = 3;
 
-fn cli() {
-    let args: Vec<String> = env::args().collect();
+/// Works on a data directory directly, so the server must not run on it meanwhile.
+#[derive(Parser)]
+#[command(name = "sstable-cli", version, about)]
+#[command(after_help = "Exit codes: 0 success, 1 error, 2 invalid arguments, 3 key not found.")]
+pub struct Cli {
+    /// Data directory of the database.
+    #[arg(short, long, default_value = ".", global = true)]
+    dir: PathBuf,
+    /// Column family to work on.
+    #[arg(short, long, default_value = DEFAULT_COLUMN_FAMILY, global = true)]
+    family: String,
+    /// Machine-readable output, one JSON object per line for listings.
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    Get { key: String },
+    Set { key: String, value: String },
+    Delete { key: String },
+    /// Live keys from `start` up to, but excluding, `end`.
+    Scan {
+        start: Option<String>,
+        end: Option<String>,
+        #[arg(short, long)]
+        limit: Option<usize>,
+    },
+    Prefix {
+        prefix: String,
+        #[arg(short, long)]
+        limit: Option<usize>,
+    },
+    /// Layout and statistics of one table, by its `.idx` or `.sst` path. Does not need `--dir`.
+    #[command(alias = "dump-table")]
+    SstDump {
+        table: PathBuf,
+        /// Also lists every record with its offsets, as it is stored.
+        #[arg(short, long)]
+        entries: bool,
+    },
+    ListTables,
+    Stats,
+    /// Merges the tables holding keys from `start` to `end`, see `ColumnFamily::compact_range`.
+    Compact { start: Option<String>, end: Option<String> },
+    /// Reads every table and value log pointer of the family.
+    Verify,
+    Export {
+        /// jsonl or csv.
+        format: String,
+    },
+    Import {
+        /// jsonl or csv.
+        format: String,
+        /// `-` reads standard input.
+        file: String,
+    },
+    #[command(subcommand)]
+    Backup(BackupCommand),
+    /// Rewrites the database into a new directory and compares every entry afterwards, see
+    /// `migrate::migrate`. Reads `--dir`, which nothing may write to meanwhile.
+    Migrate { dest: PathBuf },
+    /// Interactive prompt with history and tab completion, see `help` inside it.
+    Repl {
+        /// Overrides `--dir`.
+        dir: Option<PathBuf>,
+    },
+}
 
-    if args.len() <= 1 {
-        panic!("No arguments provided, Use 'set' or 'get'");
+#[derive(Subcommand)]
+enum BackupCommand {
+    Create { backup_dir: PathBuf },
+    List { backup_dir: PathBuf },
+    /// Keeps the `keep` newest backups.
+    Purge { backup_dir: PathBuf, keep: usize },
+    Restore { backup_dir: PathBuf, id: u64, dest: PathBuf },
+}
+
+pub fn cli() -> ExitCode {
+    let cli = Cli::parse();
+    match run(&cli) {
+        Ok(code) => code,
+        Err(e) => {
+            if cli.json {
+                println!("{}", json!({ "error": e.to_string() }));
+            } else {
+                eprintln!("Error: {}", e);
+            }
+            ExitCode::FAILURE
+        }
     }
+}
+
+fn open(cli: &Cli) -> Result<(Db, Arc<ColumnFamily>), Error> {
+    open_dir(&cli.dir, &cli.family)
+}
+
+fn open_dir(dir: &Path, family: &str) -> Result<(Db, Arc<ColumnFamily>), Error> {
+    /* Same merge operator as the server, or merge records could not be read. No background
+       thread could end a write stall here, writes compact after flushing instead */
+    let options = Options {
+        merge_operator: Some(Arc::new(U64AddOperator)),
+        level0_slowdown_writes_trigger: usize::MAX,
+        level0_stop_writes_trigger: usize::MAX,
+        ..Options::default()
+    };
+    let db = Db::open(dir, options)?;
+    let column_family = db.column_family(family)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Column family {} does not exist", family)))?;
+    Ok((db, column_family))
+}
+
+/// Writes the memtables to tables and compacts them, the work the server's background threads do.
+pub(crate) fn flush_and_compact(column_family: &ColumnFamily) -> Result<(), Error> {
+    column_family.flush(true)?;
+    IDX::compact(column_family.path(), column_family.options());
+    column_family.refresh_level0_files();
+    Ok(())
+}
 
+fn run(cli: &Cli) -> Result<ExitCode, Error> {
+    let mut out = io::stdout().lock();
 
-    if !["set", "get"].contains(&args[1].as_str()) {
-        panic!("Invalid arguments! Use 'set' or 'get'");
+    match &cli.command {
+        Command::Get { key } => {
+            let (_, column_family) = open(cli)?;
+            match column_family.get(key)? {
+                Some(value) if cli.json => writeln!(out, "{}", json!({ "key": key, "value": value }))?,
+                Some(value) => writeln!(out, "{}", value)?,
+                None => {
+                    if cli.json {
+                        writeln!(out, "{}", json!({ "key": key, "value": null }))?;
+                    } else {
+                        eprintln!("Key {} not found", key);
+                    }
+                    return Ok(ExitCode::from(EXIT_NOT_FOUND));
+                }
+            }
+        }
+        Command::Set { key, value } => {
+            let (_, column_family) = open(cli)?;
+            column_family.set(key, value)?;
+            // There is no flush thread here, writes would wait in the log until the next open
+            flush_and_compact(&column_family)?;
+            print_done(&mut out, cli.json)?;
+        }
+        Command::Delete { key } => {
+            let (_, column_family) = open(cli)?;
+            column_family.delete(key)?;
+            flush_and_compact(&column_family)?;
+            print_done(&mut out, cli.json)?;
+        }
+        Command::Scan { start, end, limit } => {
+            let (_, column_family) = open(cli)?;
+            let comparator = &column_family.options().comparator;
+            let entries = IDX::merged(column_family.path(), column_family.options())?
+                .filter(|entry| entry.as_ref().map_or(true, |(key, _)| start.as_ref().is_none_or(|start| comparator.compare(key, start) != Ordering::Less)))
+                .take_while(|entry| entry.as_ref().map_or(true, |(key, _)| end.as_ref().is_none_or(|end| comparator.compare(key, end) == Ordering::Less)));
+            print_entries(&mut out, cli.json, entries, *limit)?;
+        }
+        Command::Prefix { prefix, limit } => {
+            let (_, column_family) = open(cli)?;
+            let entries = IDX::merged(column_family.path(), column_family.options())?
+                .filter(|entry| entry.as_ref().map_or(true, |(key, _)| key.starts_with(prefix.as_str())));
+            print_entries(&mut out, cli.json, entries, *limit)?;
+        }
+        Command::SstDump { table, entries } => sst_dump(table, *entries, cli.json, &mut out)?,
+        Command::ListTables => {
+            let (_, column_family) = open(cli)?;
+            for table in IDX::tables(column_family.path(), &HashSet::new()) {
+                let id = table.path.file_stem().unwrap().to_string_lossy().to_string();
+                let idx_size = fs::metadata(&table.path)?.len();
+                match cli.json {
+                    true => writeln!(out, "{}", json!({
+                        "id": id, "timestamp": table.timestamp, "generation": table.generation,
+                        "sst_bytes": table.size, "idx_bytes": idx_size,
+                    }))?,
+                    false => writeln!(out, "{}\tgeneration {}\t{} bytes\t{} index bytes", id, table.generation, table.size, idx_size)?,
+                }
+            }
+        }
+        Command::Stats => {
+            let (db, _) = open(cli)?;
+            print_stats(&db, cli.json, &mut out)?;
+        }
+        Command::Compact { start, end } => {
+            let (_, column_family) = open(cli)?;
+            let progress = column_family.compact_range(start.as_deref(), end.as_deref(), &|_| {})?;
+            match cli.json {
+                true => writeln!(out, "{}", json!({
+                    "tables": progress.tables, "input_bytes": progress.input_bytes, "output_bytes": progress.output_bytes,
+                }))?,
+                false => writeln!(out, "{} tables compacted, {} bytes to {} bytes", progress.tables, progress.input_bytes, progress.output_bytes)?,
+            }
+        }
+        Command::Verify => {
+            let (_, column_family) = open(cli)?;
+            let mut failed = false;
+            for table in IDX::tables(column_family.path(), &HashSet::new()) {
+                let id = table.path.file_stem().unwrap().to_string_lossy().to_string();
+                let result = IDX::from(table.path, Arc::clone(column_family.options())).and_then(|idx| idx.verify());
+                failed |= result.is_err();
+                match (cli.json, result) {
+                    (true, Ok(records)) => writeln!(out, "{}", json!({ "table": id, "ok": true, "records": records }))?,
+                    (true, Err(e)) => writeln!(out, "{}", json!({ "table": id, "ok": false, "error": e.to_string() }))?,
+                    (false, Ok(records)) => writeln!(out, "{}\tok\t{} records", id, records)?,
+                    (false, Err(e)) => writeln!(out, "{}\tfailed\t{}", id, e)?,
+                }
+            }
+            if failed {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Export { format } => {
+            let format = format.parse::<Format>()?;
+            let (_, column_family) = open(cli)?;
+            let exported = export::export(&column_family, format, &mut out)?;
+            eprintln!("{} keys exported", exported);
+        }
+        Command::Import { format, file } => {
+            let format = format.parse::<Format>()?;
+            let (db, column_family) = open(cli)?;
+            let imported = match file.as_str() {
+                "-" => export::import(&db, &cli.family, format, io::stdin().lock())?,
+                file => export::import(&db, &cli.family, format, fs::File::open(file)?)?,
+            };
+            flush_and_compact(&column_family)?;
+            match cli.json {
+                true => writeln!(out, "{}", json!({ "imported": imported }))?,
+                false => writeln!(out, "{} keys imported", imported)?,
+            }
+        }
+        Command::Backup(command) => backup(cli, command, &mut out)?,
+        Command::Migrate { dest } => {
+            let options = Options { merge_operator: Some(Arc::new(U64AddOperator)), ..Options::default() };
+            for family in migrate::migrate(&cli.dir, dest, options)? {
+                match cli.json {
+                    true => writeln!(out, "{}", json!({ "family": family.name, "tables": family.tables, "entries": family.entries }))?,
+                    false => writeln!(out, "{}\t{} tables\t{} entries migrated and verified", family.name, family.tables, family.entries)?,
+                }
+            }
+        }
+        Command::Repl { dir } => {
+            drop(out);
+            let (db, column_family) = open_dir(dir.as_ref().unwrap_or(&cli.dir), &cli.family)?;
+            repl::repl(&db, column_family)?;
+        }
     }
 
-    if &args[1] == "set" && args.len() != 4 {
-        panic!("Invalid arguments! Use set 'key' 'value'");
-    } else if &args[1] == "get" && args.len() != 3 {
-        panic!("Invalid arguments! Use get 'key'");
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Tables only have the legacy layout: `.idx` entries of key length, key and `.sst` offset, and
+/// `.sst` records of key length, key, flagged value length and payload. There is no footer, no
+/// blocks, no filter and no sequence numbers to show.
+fn sst_dump(table: &Path, entries: bool, json: bool, out: &mut impl Write) -> Result<(), Error> {
+    let idx = IDX::from(table.with_extension("idx"), Arc::new(Options::default()))?;
+    let id = idx.path().file_stem().unwrap().to_string_lossy().to_string();
+    let (timestamp, generation) = IDX::get_timestamp_from_filename(idx.path());
+    let idx_bytes = fs::metadata(idx.path())?.len();
+    let sst_bytes = fs::metadata(&idx.sst().path)?.len();
+
+    /* Keys are checked in the order the table was written with, the named family directories sit below the manifest */
+    let comparator_name = match idx.sst().comparator()? {
+        Some(name) => name,
+        None => {
+            let dir = idx.path().parent().unwrap_or(Path::new("."));
+            let manifest = match Manifest::load(dir)? {
+                Some(manifest) => Some(manifest),
+                None => dir.parent().map(Manifest::load).transpose()?.flatten(),
+            };
+            manifest.map_or_else(|| BytewiseComparator.name().to_string(), |manifest| manifest.comparator)
+        }
+    };
+    let comparator = comparator::builtin(&comparator_name);
+
+    let mut iter = idx.records()?;
+    let (mut records, mut unreadable, mut out_of_order) = (0, 0, 0);
+    let (mut inline, mut pointers, mut merges, mut tombstones, mut value_log_bytes) = (0, 0, 0, 0, 0);
+    let (mut first_key, mut last_key) = (None, None::<String>);
+    let mut listed = Vec::new();
+    for record in iter.by_ref() {
+        records += 1;
+        match &record {
+            Ok(record) => {
+                match &record.value {
+                    SSTValue::Inline(_) => inline += 1,
+                    SSTValue::Pointer(pointer) => {
+                        pointers += 1;
+                        value_log_bytes += pointer.len as u64;
+                    }
+                    SSTValue::Merge(_) => merges += 1,
+                    SSTValue::Tombstone => tombstones += 1,
+                }
+                if let (Some(comparator), Some(last_key)) = (&comparator, &last_key) {
+                    if comparator.compare(last_key, &record.key) != Ordering::Less {
+                        out_of_order += 1;
+                    }
+                }
+                first_key.get_or_insert_with(|| record.key.clone());
+                last_key = Some(record.key.clone());
+            }
+            Err(_) => unreadable += 1,
+        }
+        // Only the listing needs the records once the summary is out
+        if entries {
+            listed.push(record);
+        }
     }
+    let idx_read = iter.position();
 
-    let idx = idx::IDX::new(None);
+    let mut sst_iter = idx.sst().iter()?;
+    let sst_records = sst_iter.by_ref().count();
+    let sst_read = sst_iter.offset();
+    let readable = records - unreadable;
 
+    let summary = json!({
+        "table": id,
+        "timestamp": timestamp,
+        "generation": generation,
+        "layout": "legacy",
+        "comparator": comparator_name,
+        "idx_bytes": idx_bytes,
+        "sst_bytes": sst_bytes,
+        "entries": records,
+        "first_key": first_key,
+        "last_key": last_key,
+        "inline": inline,
+        "pointers": pointers,
+        "merges": merges,
+        "tombstones": tombstones,
+        "value_log_bytes": value_log_bytes,
+        "sst_records": sst_records,
+        /* Records the index does not point to, left behind by an interrupted write */
+        "unindexed_sst_records": sst_records.saturating_sub(readable),
+        "unreadable_entries": unreadable,
+        /* Unknown for a custom comparator, its order can't be checked here */
+        "out_of_order_keys": comparator.as_ref().map(|_| out_of_order),
+        "trailing_idx_bytes": idx_bytes - idx_read,
+        "trailing_sst_bytes": sst_bytes - sst_read,
+    });
+    match json {
+        true => writeln!(out, "{}", summary)?,
+        false => {
+            writeln!(out, "{}", id)?;
+            for (name, value) in summary.as_object().unwrap().iter().filter(|(name, _)| *name != "table") {
+                writeln!(out, "  {}: {}", name, value)?;
+            }
+        }
+    }
 
-    if args[2].len() as u8 > u8::MAX || !args[2].chars().all(|x| x.is_alphabetic()) {
-        panic!("Key must be alphabetic and less then 11 chars");
+    if entries {
+        if !json {
+            writeln!(out, "idx_offset\tsst_offset\tkey\tkind\tvalue")?;
+        }
+        for record in listed {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    match json {
+                        true => writeln!(out, "{}", json!({ "error": e.to_string() }))?,
+                        false => writeln!(out, "error\t{}", e)?,
+                    }
+                    continue;
+                }
+            };
+            let (kind, value) = match record.value {
+                SSTValue::Inline(value) => ("inline", json!(value)),
+                SSTValue::Pointer(pointer) => ("pointer", json!({ "segment": pointer.segment, "offset": pointer.offset, "len": pointer.len })),
+                SSTValue::Merge(operands) => ("merge", json!(operands)),
+                SSTValue::Tombstone => ("tombstone", json!(null)),
+            };
+            match json {
+                true => writeln!(out, "{}", json!({
+                    "idx_offset": record.idx_offset, "sst_offset": record.sst_offset, "key": record.key, "kind": kind, "value": value,
+                }))?,
+                false => writeln!(out, "{}\t{}\t{}\t{}\t{}", record.idx_offset, record.sst_offset, record.key, kind, value)?,
+            }
+        }
     }
+    Ok(())
+}
+
+/// Table and value log sizes of every column family.
+pub(crate) fn print_stats(db: &Db, json: bool, out: &mut impl Write) -> Result<(), Error> {
+    for column_family in db.column_families() {
+        let tables = IDX::tables(column_family.path(), &HashSet::new());
+        let segments = column_family.value_log().segments();
+        let value_log_bytes = segments.iter()
+            .map(|segment| fs::metadata(column_family.path().join(ValueLog::segment_path(*segment))).map_or(0, |metadata| metadata.len()))
+            .sum::<u64>();
 
-    if &args[1] == "set" {
-        match idx.set_key(args[2].as_str(), args[3].as_str()) {
-            Ok(key) => println!("Key set {:?}", key),
-            Err(e) => panic!("{}", e),
+        let stats = json!({
+            "family": column_family.name(),
+            "tables": tables.len(),
+            "level0_tables": column_family.level0_files(),
+            "table_bytes": tables.iter().map(|table| table.size).sum::<u64>(),
+            "value_log_segments": segments.len(),
+            "value_log_bytes": value_log_bytes,
+        });
+        match json {
+            true => writeln!(out, "{}", stats)?,
+            false => {
+                writeln!(out, "{}", column_family.name())?;
+                for (name, value) in stats.as_object().unwrap().iter().filter(|(name, _)| *name != "family") {
+                    writeln!(out, "  {}: {}", name, value)?;
+                }
+            }
         }
-    } else if &args[1] == "get" {
-        match idx.get_value(args[2].as_str()) {
-            Ok(value) => println!("Value get {:?}", value),
-            Err(e) => panic!("{}", e),
-        };
+    }
+    Ok(())
+}
+
+fn backup(cli: &Cli, command: &BackupCommand, out: &mut impl Write) -> Result<(), Error> {
+    match command {
+        BackupCommand::Create { backup_dir } => {
+            let (db, _) = open(cli)?;
+            let backup = BackupEngine::open(backup_dir)?.create_backup(&db)?;
+            match cli.json {
+                true => writeln!(out, "{}", json!({ "id": backup.id, "files": backup.files.len(), "size": backup.size() }))?,
+                false => writeln!(out, "Backup {} created with {} files, {} bytes", backup.id, backup.files.len(), backup.size())?,
+            }
+        }
+        BackupCommand::List { backup_dir } => {
+            for backup in BackupEngine::open(backup_dir)?.list_backups()? {
+                match cli.json {
+                    true => writeln!(out, "{}", json!({
+                        "id": backup.id, "timestamp": backup.timestamp, "files": backup.files.len(), "size": backup.size(),
+                    }))?,
+                    false => writeln!(out, "{}\t{}\t{} files\t{} bytes", backup.id, backup.timestamp, backup.files.len(), backup.size())?,
+                }
+            }
+        }
+        BackupCommand::Purge { backup_dir, keep } => {
+            let purged = BackupEngine::open(backup_dir)?.purge_old_backups(*keep)?;
+            match cli.json {
+                true => writeln!(out, "{}", json!({ "purged": purged }))?,
+                false => writeln!(out, "{} backups purged", purged)?,
+            }
+        }
+        BackupCommand::Restore { backup_dir, id, dest } => {
+            BackupEngine::open(backup_dir)?.restore(*id, dest)?;
+            print_done(out, cli.json)?;
+        }
+    }
+    Ok(())
+}
 
+fn print_done(out: &mut impl Write, json: bool) -> Result<(), Error> {
+    match json {
+        true => writeln!(out, "{}", json!({ "ok": true })),
+        false => writeln!(out, "OK"),
     }
+}
 
+fn print_entries(
+    out: &mut impl Write,
+    json: bool,
+    entries: impl Iterator<Item = Result<(String, String), Error>>,
+    limit: Option<usize>,
+) -> Result<(), Error> {
+    for entry in entries.take(limit.unwrap_or(usize::MAX)) {
+        let (key, value) = entry?;
+        match json {
+            true => writeln!(out, "{}", json!({ "key": key, "value": value }))?,
+            false => writeln!(out, "{}\t{}", key, value)?,
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,739 @@
+use std::cmp;
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread::sleep;
+use std::time::Instant;
+use crate::avl::{AVLTree, AVLTreeSingleton, FlushSignal};
+use crate::idx::{IDXRecord, IDX};
+use crate::metrics::{Metrics, StallReason};
+use crate::options::Options;
+use crate::snapshot::Snapshot;
+use crate::sst::{self, SSTValue, SST};
+use crate::vlog::ValueLog;
+use crate::wal::Wal;
+use crate::write_batch::BatchOp;
+
+/// How far a `ColumnFamily::compact_range` got.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactionProgress {
+    /// Tables overlapping the range, with every table between them.
+    pub tables: usize,
+    pub compacted_tables: usize,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    pub done: bool,
+    pub error: Option<String>,
+}
+
+/// A table written by `ColumnFamily::flush`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlushedTable {
+    /// File name of the table without extension.
+    pub id: String,
+    /// Bytes of the `.sst` file.
+    pub size: u64,
+}
+
+/// A keyspace with its own memtable, tables, value log and options.
+/// Tells the writes of a key apart, see `ColumnFamily::get_versioned`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Version {
+    /// Sequence number of a write still in a memtable.
+    Memtable(u64),
+    /// Name of the table holding the newest record of the key and the offset of that record.
+    /// Tables never change once written, but flushes and compactions move records into new
+    /// ones, so the version of a key changes with them too.
+    Table(String, u64),
+}
+
+pub struct ColumnFamily {
+    name: String,
+    path: PathBuf,
+    memtable: AVLTreeSingleton,
+    /// Full memtables waiting for the flush thread, oldest first.
+    immutables: RwLock<VecDeque<Arc<AVLTree>>>,
+    value_log: ValueLog,
+    options: Arc<Options>,
+    flush_signal: Arc<FlushSignal>,
+    metrics: Arc<Metrics>,
+    /// Shared with the other families of the database.
+    wal: Arc<Wal>,
+    level0_files: AtomicUsize,
+    /// Woken up whenever a flush or a compaction may have ended a write stall.
+    stall: (Mutex<()>, Condvar),
+    /// Tables picked by a running compaction, the condvar is notified when some are released.
+    compacting: (Mutex<HashSet<PathBuf>>, Condvar),
+    /// Held while value log segments may be removed.
+    collecting_garbage: Mutex<()>,
+    /// See `lock_conditional_writes`.
+    conditional_writes: Mutex<()>,
+    /// Held while immutables are written, so each of them is flushed once.
+    flushing: Mutex<()>,
+}
+
+impl ColumnFamily {
+    /// Tables are kept in `path`, which is created if it is missing.
+    /// A full memtable is reported to `flush_signal` under the family name.
+    pub fn open(
+        name: &str,
+        path: &Path,
+        options: Arc<Options>,
+        flush_signal: Arc<FlushSignal>,
+        metrics: Arc<Metrics>,
+        wal: Arc<Wal>,
+    ) -> Result<ColumnFamily, Error> {
+        fs::create_dir_all(path)?;
+        remove_stale_dirs(path, Snapshot::DIR_PREFIX);
+        for idx_file in IDX::idx_files(path) {
+            IDX::from(idx_file, Arc::clone(&options))?.sst().check_comparator()?;
+        }
+        wal.add_family(name);
+
+        Ok(ColumnFamily {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+            memtable: AVLTreeSingleton::with_comparator(Arc::clone(&options.comparator)),
+            immutables: RwLock::new(VecDeque::new()),
+            value_log: ValueLog::open(path, Arc::clone(&options)),
+            options,
+            flush_signal,
+            metrics,
+            wal,
+            level0_files: AtomicUsize::new(IDX::level0_files(path)),
+            stall: (Mutex::new(()), Condvar::new()),
+            compacting: (Mutex::new(HashSet::new()), Condvar::new()),
+            collecting_garbage: Mutex::new(()),
+            conditional_writes: Mutex::new(()),
+            flushing: Mutex::new(()),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn memtable(&self) -> &AVLTreeSingleton {
+        &self.memtable
+    }
+
+    pub fn value_log(&self) -> &ValueLog {
+        &self.value_log
+    }
+
+    pub fn options(&self) -> &Arc<Options> {
+        &self.options
+    }
+
+    pub fn immutable_memtables(&self) -> usize {
+        self.immutables.read().unwrap().len()
+    }
+
+    /// Tables written by a flush and not compacted yet, as of the last flush or compaction.
+    pub fn level0_files(&self) -> usize {
+        self.level0_files.load(Ordering::Relaxed)
+    }
+
+    pub fn is_memtable_full(&self, tree: &AVLTree) -> bool {
+        tree.size() >= self.options.memtable_size_limit
+            || self.options.memtable_count_limit.is_some_and(|limit| tree.count() >= limit)
+    }
+
+    /// Called after every write with the memtable still locked.
+    ///
+    /// A full memtable is replaced by an empty one and queued for the flush thread.
+    pub(crate) fn switch_memtable_if_full(&self, tree: &mut AVLTree) {
+        if self.is_memtable_full(tree) {
+            self.switch_memtable(tree);
+        }
+    }
+
+    fn switch_memtable(&self, tree: &mut AVLTree) {
+        let full = std::mem::replace(tree, AVLTree::with_comparator(Arc::clone(&self.options.comparator)));
+        self.immutables.write().unwrap().push_back(Arc::new(full));
+        self.wal.memtable_switched(&self.name);
+        self.flush_signal.notify(&self.name);
+    }
+
+    /// Switches the memtable even when it is not full. An empty memtable is left alone.
+    ///
+    /// With `wait` every queued memtable is written before returning, together with the
+    /// last table written, otherwise they are left to the flush thread and `None` comes back.
+    pub fn flush(&self, wait: bool) -> Result<Option<FlushedTable>, Error> {
+        if !wait {
+            let mut tree = self.memtable.get_instance().write().unwrap();
+            if tree.root.is_some() {
+                self.switch_memtable(&mut tree);
+            }
+            return Ok(None);
+        }
+
+        // Taken first, so the flush thread can't pick up the memtable and leave us without its table
+        let _flushing = self.flushing.lock().unwrap();
+        self.flush_memtables()
+    }
+
+    /// Expects `flushing` to be held.
+    fn flush_memtables(&self) -> Result<Option<FlushedTable>, Error> {
+        {
+            let mut tree = self.memtable.get_instance().write().unwrap();
+            if tree.root.is_some() {
+                self.switch_memtable(&mut tree);
+            }
+        }
+
+        let mut last = None;
+        while let Some(idx) = self.flush_oldest()? {
+            let size = fs::metadata(idx.path().with_extension("sst"))?.len();
+            let id = idx.path().file_stem().unwrap().to_string_lossy().to_string();
+            last = Some(FlushedTable { id, size });
+        }
+        Ok(last)
+    }
+
+    /// Writes the queued memtables to tables, oldest first, and returns how many were written.
+    ///
+    /// A memtable leaves the queue only once its table is complete, so reads never miss it.
+    pub fn flush_immutables(&self) -> Result<usize, Error> {
+        let _flushing = self.flushing.lock().unwrap();
+        let mut flushed = 0;
+        while self.flush_oldest()?.is_some() {
+            flushed += 1;
+        }
+        Ok(flushed)
+    }
+
+    /// Expects `flushing` to be held.
+    fn flush_oldest(&self) -> Result<Option<IDX>, Error> {
+        let Some(oldest) = self.immutables.read().unwrap().front().cloned() else { return Ok(None) };
+
+        let idx = IDX::new(&self.path, None, Arc::clone(&self.options));
+        idx.fill_from_avl(&oldest, &self.value_log)?;
+        self.immutables.write().unwrap().pop_front();
+        self.wal.memtable_flushed(&self.name);
+
+        self.metrics.record_flush();
+        self.refresh_level0_files();
+        Ok(Some(idx))
+    }
+
+    /// Recounts the flushed tables and wakes up stopped writers.
+    pub fn refresh_level0_files(&self) {
+        self.level0_files.store(IDX::level0_files(&self.path), Ordering::Relaxed);
+
+        let _guard = self.stall.0.lock().unwrap();
+        self.stall.1.notify_all();
+    }
+
+    pub(crate) fn compacting(&self) -> MutexGuard<'_, HashSet<PathBuf>> {
+        self.compacting.0.lock().unwrap()
+    }
+
+    /// Waits for running compactions to publish their tables and keeps new ones from starting
+    /// until the guard is dropped.
+    fn idle_compactions(&self) -> MutexGuard<'_, HashSet<PathBuf>> {
+        let (compacting, released) = &self.compacting;
+        released.wait_while(compacting.lock().unwrap(), |compacting| !compacting.is_empty()).unwrap()
+    }
+
+    pub(crate) fn release_tables(&self, tables: &[PathBuf]) {
+        let mut compacting = self.compacting();
+        for table in tables {
+            compacting.remove(table);
+        }
+        self.compacting.1.notify_all();
+    }
+
+    /// Merges every table holding keys from `start` to `end`, both included, into one table,
+    /// so data the compaction filter drops is gone right away. `None` leaves that side open.
+    ///
+    /// Tables in between are merged too, the output has to take their place in the table
+    /// order. Only tables are compacted, what is still in the memtables stays there.
+    /// Tables are not in levels, so there is no target or bottom level to compact into:
+    /// the output replaces its inputs wherever they were. Tombstones are only dropped when the
+    /// oldest table is among them.
+    /// `progress` is called whenever there is something new to tell.
+    pub fn compact_range(
+        &self,
+        start: Option<&str>,
+        end: Option<&str>,
+        progress: &dyn Fn(&CompactionProgress),
+    ) -> Result<CompactionProgress, Error> {
+        let comparator = &self.options.comparator;
+        let mut report = CompactionProgress::default();
+
+        let inputs = {
+            // Key ranges are read from the tables, so none of them may be replaced meanwhile
+            let mut compacting = self.idle_compactions();
+            let tables = IDX::tables(&self.path, &compacting);
+
+            let mut overlapping = Vec::new();
+            for (i, table) in tables.iter().enumerate() {
+                let idx = IDX::from(table.path.clone(), Arc::clone(&self.options))?;
+                let Some((first, last)) = idx.key_range()? else { continue };
+
+                let after_start = start.is_none_or(|start| comparator.compare(&last, start) != cmp::Ordering::Less);
+                let before_end = end.is_none_or(|end| comparator.compare(&first, end) != cmp::Ordering::Greater);
+                if after_start && before_end {
+                    overlapping.push(i);
+                }
+            }
+
+            let (Some(&first), Some(&last)) = (overlapping.first(), overlapping.last()) else {
+                report.done = true;
+                progress(&report);
+                return Ok(report);
+            };
+
+            let span = &tables[first..=last];
+            report.tables = span.len();
+            report.input_bytes = span.iter().map(|table| table.size).sum();
+
+            let inputs = span.iter().map(|table| table.path.clone()).collect::<Vec<_>>();
+            compacting.extend(inputs.iter().cloned());
+            inputs
+        };
+        progress(&report);
+
+        let result = IDX::compact_tables(&self.path, &inputs, &self.options);
+        self.refresh_level0_files();
+        self.release_tables(&inputs);
+
+        let output = result?;
+        report.compacted_tables = inputs.len();
+        report.output_bytes = output.map_or(0, |path| fs::metadata(path.with_extension("sst")).map_or(0, |metadata| metadata.len()));
+        report.done = true;
+        progress(&report);
+        Ok(report)
+    }
+
+    /// Flushes the memtables and links every table and value log segment into `dest`, which
+    /// has to exist. Returns how many files were linked or copied.
+    ///
+    /// Flushes, compactions and value log garbage collection wait until it is done, so the
+    /// files all belong to the same moment. The write-ahead log is left out, writes that land
+    /// in the memtable after the flush are not part of the checkpoint.
+    pub fn checkpoint(&self, dest: &Path) -> Result<usize, Error> {
+        let _collecting = self.collecting_garbage.lock().unwrap();
+        let _flushing = self.flushing.lock().unwrap();
+        self.flush_memtables()?;
+        let _compacting = self.idle_compactions();
+        self.link_files(dest)
+    }
+
+    /// Same as `checkpoint` without flushing: the memtables are written to tables in `dest`
+    /// only, after the linked ones, so the family itself gains no table. See `Snapshot`.
+    pub(crate) fn freeze(&self, dest: &Path) -> Result<usize, Error> {
+        let _collecting = self.collecting_garbage.lock().unwrap();
+        /* Held so no immutable moves from the queue to a table before it is written */
+        let _flushing = self.flushing.lock().unwrap();
+        let _compacting = self.idle_compactions();
+        let mut files = self.link_files(dest)?;
+
+        let tree = self.memtable.get_instance().read().map_err(|_| Error::other("memtable lock is poisoned"))?;
+        let immutables = self.immutables.read().unwrap().iter().cloned().collect::<Vec<_>>();
+        for memtable in immutables.iter().map(|immutable| immutable.as_ref()).chain([&*tree]) {
+            if memtable.root.is_some() {
+                IDX::new(dest, None, Arc::clone(&self.options)).fill_inline_from_avl(memtable)?;
+                files += 2;
+            }
+        }
+        Ok(files)
+    }
+
+    /// Links every table and value log segment into `dest`, expects the family to hold still.
+    fn link_files(&self, dest: &Path) -> Result<usize, Error> {
+        let mut files = Vec::new();
+        for idx_file in IDX::idx_files(&self.path) {
+            files.push(idx_file.with_extension("sst"));
+            files.push(idx_file);
+        }
+        let active = self.value_log.active_segment();
+        for segment in self.value_log.segments() {
+            files.push(self.path.join(ValueLog::segment_path(segment)));
+        }
+
+        for file in &files {
+            let target = dest.join(file.file_name().unwrap());
+            let active_segment = *file == self.path.join(ValueLog::segment_path(active));
+            // The active segment is still appended to, a link would share those appends
+            if active_segment || fs::hard_link(file, &target).is_err() {
+                fs::copy(file, &target)?;
+            }
+        }
+        Ok(files.len())
+    }
+
+    /// Links tables built by `SstWriter` into the family and returns their new ids.
+    ///
+    /// Every file is checked first: keys in comparator order and no values in a value log.
+    /// Ingested tables are newer than everything already written, the memtables are flushed
+    /// beforehand so their older data can't shadow the ingested keys. Later files of `paths`
+    /// are newer than earlier ones. The files are hard linked, or copied across file systems.
+    pub fn ingest_external_files(&self, paths: &[PathBuf]) -> Result<Vec<String>, Error> {
+        let paths = paths.iter().map(|path| path.with_extension("idx")).collect::<Vec<_>>();
+        for path in &paths {
+            self.check_external_file(path)?;
+        }
+
+        let _flushing = self.flushing.lock().unwrap();
+        self.flush_memtables()?;
+
+        // The timestamp of a table is its place in the table order, these come after every other
+        let first = IDX::next_timestamp(&self.path);
+
+        let mut ids = Vec::new();
+        for (path, timestamp) in paths.iter().zip(first..) {
+            let id = timestamp.to_string();
+            // The `.idx` goes last, a table is only seen once it exists
+            for extension in ["sst", "idx"] {
+                let target = self.path.join(format!("{id}.{extension}"));
+                let source = path.with_extension(extension);
+                if fs::hard_link(&source, &target).is_err() {
+                    fs::copy(&source, &target)?;
+                }
+            }
+            tracing::info!("External table {} was ingested as {}", path.to_string_lossy(), id);
+            ids.push(id);
+        }
+
+        self.refresh_level0_files();
+        Ok(ids)
+    }
+
+    fn check_external_file(&self, path: &Path) -> Result<(), Error> {
+        let invalid = |reason: &str| Error::new(ErrorKind::InvalidInput, format!("{} {}", path.display(), reason));
+        if !path.exists() || !path.with_extension("sst").exists() {
+            return Err(Error::new(ErrorKind::NotFound, format!("{} is not a table", path.display())));
+        }
+
+        let idx = IDX::from(path.to_path_buf(), Arc::clone(&self.options))?;
+        idx.sst().check_comparator()?;
+        let mut last_key: Option<String> = None;
+        let damaged = |e: Error| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e));
+        /* A damaged record is an error of the file, not a panic of the server */
+        let mut records = idx.records()?;
+        for record in records.by_ref() {
+            let IDXRecord { key, value, .. } = record.map_err(damaged)?;
+            if let SSTValue::Pointer(_) = value {
+                return Err(invalid("has values in a value log"));
+            }
+            if let Some(last_key) = &last_key {
+                if self.options.comparator.compare(last_key, &key) != cmp::Ordering::Less {
+                    return Err(invalid("has keys out of order"));
+                }
+            }
+            last_key = Some(key);
+        }
+        if records.position() != fs::metadata(path)?.len() {
+            return Err(damaged(Error::other("ends with a cut off entry")));
+        }
+
+        match last_key {
+            Some(_) => Ok(()),
+            None => Err(invalid("has no entries")),
+        }
+    }
+
+    /// Returns why writes have to be held back, and whether they have to stop altogether.
+    pub fn write_stall(&self) -> Option<(StallReason, bool)> {
+        let immutables = self.immutable_memtables();
+        if immutables >= self.options.max_immutable_memtables {
+            return Some((StallReason::ImmutableMemtables, true));
+        }
+
+        let level0_files = self.level0_files();
+        if level0_files >= self.options.level0_stop_writes_trigger {
+            return Some((StallReason::Level0Files, true));
+        }
+
+        if immutables >= self.options.immutable_memtables_slowdown_trigger {
+            return Some((StallReason::ImmutableMemtables, false));
+        }
+
+        if level0_files >= self.options.level0_slowdown_writes_trigger {
+            return Some((StallReason::Level0Files, false));
+        }
+
+        None
+    }
+
+    /// Delays a write while flushes or compactions fall behind.
+    ///
+    /// Fails with `ErrorKind::TimedOut` when writes stay stopped for longer than
+    /// `Options::write_stall_timeout`.
+    pub(crate) fn wait_for_write_stall(&self) -> Result<(), Error> {
+        let Some((reason, stop)) = self.write_stall() else { return Ok(()) };
+        let started = Instant::now();
+
+        if !stop {
+            self.metrics.record_slowdown(reason);
+            sleep(self.options.write_slowdown_delay);
+            self.metrics.record_stall_time(started.elapsed());
+            return Ok(());
+        }
+
+        self.metrics.record_stop(reason);
+        let mut guard = self.stall.0.lock().unwrap();
+        while let Some((reason, true)) = self.write_stall() {
+            let Some(remaining) = self.options.write_stall_timeout.checked_sub(started.elapsed()) else {
+                self.metrics.record_stall_timeout();
+                self.metrics.record_stall_time(started.elapsed());
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("Writes to {} are stopped by too many {}", self.name, reason.as_str()),
+                ));
+            };
+            guard = self.stall.1.wait_timeout(guard, remaining).unwrap().0;
+        }
+
+        self.metrics.record_stall_time(started.elapsed());
+        Ok(())
+    }
+
+    fn in_memory(&self, tree: &AVLTree, key: &str) -> bool {
+        tree.get(key).is_some() || self.immutables.read().unwrap().iter().any(|immutable| immutable.get(key).is_some())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        Ok(self.get_versioned(key)?.map(|(value, _)| value))
+    }
+
+    /// Same as `get`, with the version of the newest write of the key. A key written again
+    /// gets a new version even when the value is the same.
+    pub fn get_versioned(&self, key: &str) -> Result<Option<(String, Version)>, Error> {
+        // Compaction gives way to reads while this is held
+        let _foreground = self.options.rate_limiter.as_ref().map(|rate_limiter| rate_limiter.foreground());
+
+        let tree = self.memtable.get_instance();
+        let tree = tree.read().map_err(|_| Error::other("memtable lock is poisoned"))?;
+
+        let mut operands = Vec::new();
+        let mut version = None;
+        if let Some(node) = tree.get(key) {
+            if node.deleted && node.operands.is_empty() {
+                return Ok(None);
+            }
+            if node.operands.is_empty() {
+                return Ok(Some((node.value.clone(), Version::Memtable(node.sequence))));
+            }
+            operands = node.operands.clone();
+            version = Some(Version::Memtable(node.sequence));
+        }
+
+        for immutable in self.immutables.read().unwrap().iter().rev() {
+            if let Some(node) = immutable.get(key) {
+                let version = version.get_or_insert(Version::Memtable(node.sequence)).clone();
+                if node.operands.is_empty() {
+                    let base = (!node.deleted).then(|| node.value.clone());
+                    let value = IDX::fold_operands(key, base, &operands, &self.options)?;
+                    return Ok(value.map(|value| (value, version)));
+                }
+                operands.splice(0..0, node.operands.iter().cloned());
+            }
+        }
+
+        let (index_value, location) = IDX::search_key_with_location(&self.path, key, operands, &self.options)?;
+        let version = version.or(location.map(|(table, offset)| Version::Table(table, offset)));
+        Ok(index_value.zip(version).map(|(index_value, version)| (index_value.value, version)))
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<(), Error> {
+        self.set_versioned(key, value).map(drop)
+    }
+
+    /// Same as `set`, returns the version of the write.
+    pub fn set_versioned(&self, key: &str, value: &str) -> Result<Version, Error> {
+        IDX::check_key(key)?;
+        SST::check_value(value)?;
+        self.wait_for_write_stall()?;
+
+        let tree = self.memtable.get_instance();
+        let mut tree = tree.write().map_err(|_| Error::other("memtable lock is poisoned"))?;
+        self.wal.append(&[BatchOp::Put { family: self.name.clone(), key: key.to_string(), value: value.to_string() }])?;
+        tree.set(key, value);
+        let version = Version::Memtable(tree.get(key).unwrap().sequence);
+        self.switch_memtable_if_full(&mut tree);
+        Ok(version)
+    }
+
+    /// Stores `operand` to be folded into the value by the configured merge operator.
+    ///
+    /// An operand on top of a memtable value is folded right away, otherwise it is kept
+    /// until a read or compaction meets the older value.
+    pub fn merge(&self, key: &str, operand: &str) -> Result<(), Error> {
+        let operator = self.options.merge_operator.as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::Unsupported, "No merge operator configured"))?;
+        IDX::check_key(key)?;
+        SST::check_value(operand)?;
+        operator.check_operand(key, operand)?;
+        self.wait_for_write_stall()?;
+
+        let tree = self.memtable.get_instance();
+        let mut tree = tree.write().map_err(|_| Error::other("memtable lock is poisoned"))?;
+
+        let folded = match tree.get(key) {
+            Some(node) if node.deleted => Some(operator.full_merge(key, None, &[operand.to_string()])?),
+            Some(node) if node.operands.is_empty() => Some(operator.full_merge(key, Some(&node.value), &[operand.to_string()])?),
+            _ => None,
+        };
+        self.wal.append(&[BatchOp::Merge { family: self.name.clone(), key: key.to_string(), operand: operand.to_string() }])?;
+        match folded {
+            Some(value) => tree.set(key, &value),
+            None => tree.merge(key, operand),
+        }
+        self.switch_memtable_if_full(&mut tree);
+        Ok(())
+    }
+
+    /// Writes a tombstone for the key, hiding its older values in immutable memtables and tables.
+    pub fn delete(&self, key: &str) -> Result<(), Error> {
+        IDX::check_key(key)?;
+        self.wait_for_write_stall()?;
+
+        let tree = self.memtable.get_instance();
+        let mut tree = tree.write().map_err(|_| Error::other("memtable lock is poisoned"))?;
+        self.wal.append(&[BatchOp::Delete { family: self.name.clone(), key: key.to_string() }])?;
+        tree.delete(key);
+        self.switch_memtable_if_full(&mut tree);
+        Ok(())
+    }
+
+    /// Held by front-ends across a read and the write that depends on it, so a precondition
+    /// still holds when the write lands. Plain writes don't take it.
+    pub fn lock_conditional_writes(&self) -> MutexGuard<'_, ()> {
+        self.conditional_writes.lock().unwrap()
+    }
+
+    /// Keeps the records whose key's newest table record still points at them. Expects the
+    /// tables to hold still, a table that can't be read fails the check instead of counting
+    /// its values as dead.
+    fn live_in_value_log(&self, segment: u64, records: Vec<(String, String, u64)>) -> Result<Vec<(String, String, u64)>, Error> {
+        /* Every table is opened once and asked for the keys no newer table answered */
+        let mut pending = records.iter().map(|(key, _, _)| key.as_str()).collect::<HashSet<_>>();
+        let mut live_offsets = HashSet::new();
+        for file in IDX::idx_files(&self.path) {
+            if pending.is_empty() {
+                break;
+            }
+            let idx = IDX::from(file, Arc::clone(&self.options))?;
+            let mut answered = Vec::new();
+            for key in &pending {
+                match idx.get_entry(key) {
+                    Ok(SSTValue::Pointer(pointer)) if pointer.segment == segment => {
+                        live_offsets.insert((key.to_string(), pointer.offset));
+                        answered.push(*key);
+                    }
+                    Ok(_) => answered.push(*key),
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            for key in answered {
+                pending.remove(key);
+            }
+        }
+
+        Ok(records.into_iter().filter(|(key, _, offset)| live_offsets.contains(&(key.clone(), *offset))).collect())
+    }
+
+    /// Reclaims the first sealed value log segment with enough dead values.
+    ///
+    /// Its live values are written back to the memtable and flushed, which moves them to
+    /// the head of the log, and only then is the segment file removed. Flushes and compactions
+    /// wait meanwhile, a table replaced under the check would hide the pointers of its output.
+    /// Returns the number of rewritten values.
+    pub fn collect_value_log_garbage(&self) -> Result<usize, Error> {
+        let _collecting = self.collecting_garbage.lock().unwrap();
+        let _flushing = self.flushing.lock().unwrap();
+        let _compacting = self.idle_compactions();
+        let active = self.value_log.active_segment();
+
+        for segment_id in self.value_log.segments().into_iter().filter(|segment| *segment < active) {
+            let segment = self.value_log.segment(segment_id);
+
+            let mut total_bytes = 0;
+            let mut candidates = Vec::new();
+            {
+                let tree = self.memtable.get_instance();
+                let tree = tree.read().map_err(|_| Error::other("memtable lock is poisoned"))?;
+
+                for record in segment.iter()? {
+                    let SSTValue::Inline(value) = record.value else { continue };
+                    total_bytes += value.len();
+
+                    if !self.in_memory(&tree, &record.key) {
+                        candidates.push((record.key, value, record.offset));
+                    }
+                }
+            }
+            let live = self.live_in_value_log(segment_id, candidates)?;
+
+            let live_bytes = live.iter().map(|(_, value, _)| value.len()).sum::<usize>();
+            if total_bytes > 0 && (1.0 - live_bytes as f64 / total_bytes as f64) < self.options.value_log_gc_ratio {
+                continue;
+            }
+
+            let rewritten = {
+                // Writes that happened since the scan make values dead, never live again
+                let tree = self.memtable.get_instance();
+                let mut tree = tree.write().map_err(|_| Error::other("memtable lock is poisoned"))?;
+                let mut rewritten = 0;
+                for (key, value, _) in self.live_in_value_log(segment_id, live)? {
+                    if !self.in_memory(&tree, &key) {
+                        tree.set(&key, &value);
+                        rewritten += 1;
+                    }
+                }
+                rewritten
+            };
+
+            /* Rewritten values skip the write-ahead log, they have to be in a table before the segment goes */
+            if rewritten > 0 {
+                self.flush_memtables()?;
+            }
+
+            fs::remove_file(&segment.path)?;
+            sst::forget_map(&segment.path);
+            tracing::info!("Value log segment was removed > {}", segment.path.to_string_lossy());
+            return Ok(rewritten);
+        }
+
+        Ok(0)
+    }
+}
+
+/// Removes the directories in `dir` named `<prefix><pid>_<n>` whose process died. Another
+/// process, or another handle in this one, may still be using the others.
+pub(crate) fn remove_stale_dirs(dir: &Path, prefix: &str) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else { continue };
+        let pid = name.strip_prefix(prefix)
+            .and_then(|rest| rest.split_once('_'))
+            .and_then(|(pid, _)| pid.parse::<u32>().ok());
+        if !path.is_dir() || pid.is_none_or(process_is_alive) {
+            continue;
+        }
+        match fs::remove_dir_all(&path) {
+            Ok(()) => tracing::info!("Stale directory was removed > {}", path.to_string_lossy()),
+            Err(e) => tracing::warn!("Failed to remove stale directory {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Whether `pid` runs, or might: without `/proc` there is no telling, so the answer is yes.
+fn process_is_alive(pid: u32) -> bool {
+    if pid == std::process::id() {
+        return true;
+    }
+    let proc = Path::new("/proc");
+    !proc.is_dir() || proc.join(pid.to_string()).exists()
+}
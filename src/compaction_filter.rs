@@ -0,0 +1,23 @@
+/// What compaction should do with an entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Keep,
+    /// Drops the entry from the compacted table, no tombstone is written, so a value of the
+    /// key in an older table outside the compaction becomes visible again.
+    Remove,
+    ChangeValue(String),
+}
+
+/// Lets compaction drop or rewrite entries by custom rules.
+///
+/// Removing an entry only drops it from the compacted files, an older file outside
+/// the compaction can still hold a value for the key. Filters that remove keys for good
+/// have to remove every version of them, which only holds once all tables were compacted.
+pub trait CompactionFilter: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Called for every value written by a compaction. `level` is the compaction
+    /// generation of the new file, the `N` of `<timestamp>_N`.
+    /// Merge operands are not values yet and are never passed in.
+    fn filter(&self, level: u32, key: &str, value: &str) -> Decision;
+}
@@ -0,0 +1,177 @@
+use std::path::PathBuf;
+use crate::options::Options;
+
+/// A table as seen by a compaction strategy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableInfo {
+    pub path: PathBuf,
+    pub timestamp: u64,
+    /// How many compactions the data went through, the `N` of `<timestamp>_N`.
+    pub generation: u32,
+    /// Bytes of the `.sst` file.
+    pub size: u64,
+    /// Already picked by a running compaction.
+    pub busy: bool,
+}
+
+/// Decides which tables are merged together.
+///
+/// `pick` gets the tables of one column family oldest first and returns groups of
+/// indexes into them. Every group is merged into one table that takes the place of
+/// its inputs, so a group has to be a run of neighbours without busy tables, and
+/// groups must not share tables.
+pub trait CompactionStrategy: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn pick(&self, tables: &[TableInfo], options: &Options) -> Vec<Vec<usize>>;
+}
+
+/// Merges neighbouring pairs of tables under `Options::compaction_size_limit`, so data
+/// moves up a generation at a time. Lowest read amplification, highest write amplification.
+///
+/// Two small tables are always left alone, there is no point in merging the last ones
+/// while flushes keep adding new tables next to them.
+pub struct LeveledCompaction;
+
+impl CompactionStrategy for LeveledCompaction {
+    fn name(&self) -> &str {
+        "leveled"
+    }
+
+    fn pick(&self, tables: &[TableInfo], options: &Options) -> Vec<Vec<usize>> {
+        let eligible = |table: &TableInfo| !table.busy && table.size < options.compaction_size_limit;
+
+        let mut remaining = tables.iter().filter(|table| eligible(table)).count();
+        let mut groups = Vec::new();
+        let mut i = 0;
+        while i + 1 < tables.len() && remaining > 2 {
+            if eligible(&tables[i]) && eligible(&tables[i + 1]) {
+                groups.push(vec![i, i + 1]);
+                remaining -= 2;
+                i += 2;
+            } else {
+                i += 1;
+            }
+        }
+        groups
+    }
+}
+
+/// Merges runs of tables of about the same size, so every byte is rewritten about once
+/// per size tier. Suits write-heavy workloads that can live with more tables to search.
+///
+/// Classic STCS buckets any tables of similar size, here a bucket is a run of neighbours.
+pub struct SizeTieredCompaction {
+    /// Fewest tables in a bucket worth merging.
+    pub min_threshold: usize,
+    /// Most tables merged at once.
+    pub max_threshold: usize,
+    /// A table joins a bucket while its size is within these shares of the bucket average.
+    pub bucket_low: f64,
+    pub bucket_high: f64,
+}
+
+impl Default for SizeTieredCompaction {
+    fn default() -> Self {
+        SizeTieredCompaction { min_threshold: 4, max_threshold: 32, bucket_low: 0.5, bucket_high: 1.5 }
+    }
+}
+
+impl CompactionStrategy for SizeTieredCompaction {
+    fn name(&self) -> &str {
+        "size_tiered"
+    }
+
+    fn pick(&self, tables: &[TableInfo], _options: &Options) -> Vec<Vec<usize>> {
+        let mut groups = Vec::new();
+        let mut bucket: Vec<usize> = Vec::new();
+        let mut bucket_size = 0u64;
+
+        for (i, table) in tables.iter().enumerate() {
+            let fits = !table.busy && !bucket.is_empty() && bucket.len() < self.max_threshold && {
+                let average = bucket_size as f64 / bucket.len() as f64;
+                table.size as f64 >= average * self.bucket_low && table.size as f64 <= average * self.bucket_high
+            };
+
+            if !fits {
+                if bucket.len() >= self.min_threshold {
+                    groups.push(std::mem::take(&mut bucket));
+                }
+                bucket.clear();
+                bucket_size = 0;
+            }
+
+            if table.busy {
+                continue;
+            }
+            bucket.push(i);
+            bucket_size += table.size;
+        }
+
+        if bucket.len() >= self.min_threshold {
+            groups.push(bucket);
+        }
+        groups
+    }
+}
+
+/// RocksDB style universal compaction over the tables as sorted runs, newest first.
+///
+/// When the older data is small next to the newest table everything is merged to bound
+/// space amplification, otherwise the newest tables are merged while each next one is
+/// not much larger than all of them together.
+pub struct UniversalCompaction {
+    /// Nothing is picked while there are fewer tables.
+    pub trigger: usize,
+    /// Percent a table may be larger than the ones before it and still be merged with them.
+    pub size_ratio: u64,
+    pub min_merge_width: usize,
+    pub max_merge_width: usize,
+    /// Everything is merged once the newer tables take this percent of the oldest one.
+    pub max_size_amplification_percent: u64,
+}
+
+impl Default for UniversalCompaction {
+    fn default() -> Self {
+        UniversalCompaction {
+            trigger: 4,
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: usize::MAX,
+            max_size_amplification_percent: 200,
+        }
+    }
+}
+
+impl CompactionStrategy for UniversalCompaction {
+    fn name(&self) -> &str {
+        "universal"
+    }
+
+    fn pick(&self, tables: &[TableInfo], _options: &Options) -> Vec<Vec<usize>> {
+        if tables.len() < self.trigger.max(2) || tables.iter().any(|table| table.busy) {
+            return Vec::new();
+        }
+
+        let newer_size = tables[1..].iter().map(|table| table.size).sum::<u64>();
+        if newer_size * 100 >= tables[0].size * self.max_size_amplification_percent {
+            return vec![(0..tables.len()).collect()];
+        }
+
+        let mut picked = vec![tables.len() - 1];
+        let mut picked_size = tables[tables.len() - 1].size;
+        for i in (0..tables.len() - 1).rev() {
+            if picked.len() >= self.max_merge_width || tables[i].size * 100 > picked_size * (100 + self.size_ratio) {
+                break;
+            }
+            picked.push(i);
+            picked_size += tables[i].size;
+        }
+
+        if picked.len() < self.min_merge_width {
+            return Vec::new();
+        }
+        picked.reverse();
+        vec![picked]
+    }
+}
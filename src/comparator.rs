@@ -0,0 +1,70 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+/// Defines the order of keys in the memtable and in table files.
+///
+/// It only orders keys `IDX::check_key` accepts, whatever the comparator: alphanumeric and
+/// shorter than 11 bytes. Binary keys such as big-endian integers can't be stored, write
+/// them as decimal for `NumericComparator`, or as fixed-width digits for the bytewise ones.
+pub trait Comparator: Send + Sync {
+    /// Stored in the manifest, a database can only be opened with the comparator it was created with.
+    fn name(&self) -> &str;
+
+    fn compare(&self, a: &str, b: &str) -> Ordering;
+}
+
+/// The comparator of this crate with that `name`, `None` for custom ones.
+pub fn builtin(name: &str) -> Option<Arc<dyn Comparator>> {
+    match name {
+        "bytewise" => Some(Arc::new(BytewiseComparator)),
+        "reverse_bytewise" => Some(Arc::new(ReverseBytewiseComparator)),
+        "numeric" => Some(Arc::new(NumericComparator)),
+        _ => None,
+    }
+}
+
+/// Plain `str` order, the default.
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        "bytewise"
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Reversed `str` order, newest first for timestamp keys.
+pub struct ReverseBytewiseComparator;
+
+impl Comparator for ReverseBytewiseComparator {
+    fn name(&self) -> &str {
+        "reverse_bytewise"
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+/// Orders decimal keys by their value, so `9` comes before `10`. Key validation caps them
+/// at ten digits. Keys that are not numbers go after all numbers, in `str` order.
+/// Equal numbers like `007` and `7` are still different keys, ordered as `str`.
+pub struct NumericComparator;
+
+impl Comparator for NumericComparator {
+    fn name(&self) -> &str {
+        "numeric"
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a_value), Ok(b_value)) => a_value.cmp(&b_value).then_with(|| a.cmp(b)),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => a.cmp(b),
+        }
+    }
+}
@@ -0,0 +1,396 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use sstable::comparator;
+use sstable::compaction_strategy::{CompactionStrategy, LeveledCompaction, SizeTieredCompaction, UniversalCompaction};
+use sstable::merge::U64AddOperator;
+use sstable::options::{Options, ReaderBackend};
+use sstable::rate_limiter::RateLimiter;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    Leveled,
+    SizeTiered,
+    Universal,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reader {
+    Buffered,
+    Mmap,
+}
+
+/// Settings of the server. Later sources win: defaults, the TOML file, environment, flags.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: String,
+    /// Address of the Redis protocol listener, only served when built with the `resp` feature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resp_bind: Option<String>,
+    pub data_dir: PathBuf,
+    /// Checkpoints, backups and restores requested over HTTP are kept inside this directory.
+    pub admin_dir: PathBuf,
+    /// `RUST_LOG` still takes precedence when it is set.
+    pub log_level: String,
+    pub log_format: LogFormat,
+    /// Key order, `bytewise`, `reverse_bytewise` or `numeric`. Fixed once the database exists.
+    pub comparator: String,
+    pub memtable_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memtable_count_limit: Option<usize>,
+    pub immutable_memtables_slowdown_trigger: usize,
+    pub max_immutable_memtables: usize,
+    pub level0_slowdown_writes_trigger: usize,
+    pub level0_stop_writes_trigger: usize,
+    pub write_slowdown_delay_ms: u64,
+    pub write_stall_timeout_ms: u64,
+    pub compaction_strategy: Strategy,
+    pub compaction_size_limit: u64,
+    pub max_background_compactions: usize,
+    pub max_subcompactions: usize,
+    pub compaction_interval_ms: u64,
+    pub value_log_gc_interval_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_log_threshold: Option<usize>,
+    pub value_log_segment_size: u64,
+    pub reader: Reader,
+    /// Tables the `mmap` reader keeps mapped.
+    pub map_cache_size: usize,
+    /// Bytes per second for flushes and compactions, 0 turns the limiter off.
+    pub rate_limit: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let options = Options::default();
+        Config {
+            bind: "127.0.0.1:8000".to_string(),
+            resp_bind: None,
+            data_dir: PathBuf::from("."),
+            admin_dir: PathBuf::from("admin"),
+            log_level: "info".to_string(),
+            log_format: LogFormat::Full,
+            comparator: options.comparator.name().to_string(),
+            memtable_size: options.memtable_size_limit,
+            memtable_count_limit: options.memtable_count_limit,
+            immutable_memtables_slowdown_trigger: options.immutable_memtables_slowdown_trigger,
+            max_immutable_memtables: options.max_immutable_memtables,
+            level0_slowdown_writes_trigger: options.level0_slowdown_writes_trigger,
+            level0_stop_writes_trigger: options.level0_stop_writes_trigger,
+            write_slowdown_delay_ms: options.write_slowdown_delay.as_millis() as u64,
+            write_stall_timeout_ms: options.write_stall_timeout.as_millis() as u64,
+            compaction_strategy: Strategy::Leveled,
+            compaction_size_limit: options.compaction_size_limit,
+            max_background_compactions: options.max_background_compactions,
+            max_subcompactions: options.max_subcompactions,
+            compaction_interval_ms: options.compaction_interval.as_millis() as u64,
+            value_log_gc_interval_secs: options.value_log_gc_interval.as_secs(),
+            value_log_threshold: options.value_log_threshold,
+            value_log_segment_size: options.value_log_segment_size,
+            reader: Reader::Buffered,
+            map_cache_size: options.map_cache_size,
+            rate_limit: 0,
+        }
+    }
+}
+
+/// Every flag can also be set through the environment, as `SSTABLE_` and the flag in upper
+/// snake case: `SSTABLE_DATA_DIR` for `--data-dir`.
+#[derive(Parser)]
+#[command(about = "Serves the database over HTTP")]
+struct Args {
+    /// TOML file with any of the settings below, in snake_case.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    #[arg(long)]
+    bind: Option<String>,
+    #[arg(long)]
+    resp_bind: Option<String>,
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    #[arg(long)]
+    admin_dir: Option<PathBuf>,
+    #[arg(long)]
+    log_level: Option<String>,
+    #[arg(long)]
+    log_format: Option<LogFormat>,
+    #[arg(long)]
+    comparator: Option<String>,
+    #[arg(long)]
+    memtable_size: Option<usize>,
+    #[arg(long)]
+    memtable_count_limit: Option<usize>,
+    #[arg(long)]
+    immutable_memtables_slowdown_trigger: Option<usize>,
+    #[arg(long)]
+    max_immutable_memtables: Option<usize>,
+    #[arg(long)]
+    level0_slowdown_writes_trigger: Option<usize>,
+    #[arg(long)]
+    level0_stop_writes_trigger: Option<usize>,
+    #[arg(long)]
+    write_slowdown_delay_ms: Option<u64>,
+    #[arg(long)]
+    write_stall_timeout_ms: Option<u64>,
+    #[arg(long)]
+    compaction_strategy: Option<Strategy>,
+    #[arg(long)]
+    compaction_size_limit: Option<u64>,
+    #[arg(long)]
+    max_background_compactions: Option<usize>,
+    #[arg(long)]
+    max_subcompactions: Option<usize>,
+    #[arg(long)]
+    compaction_interval_ms: Option<u64>,
+    #[arg(long)]
+    value_log_gc_interval_secs: Option<u64>,
+    #[arg(long)]
+    value_log_threshold: Option<usize>,
+    #[arg(long)]
+    value_log_segment_size: Option<u64>,
+    #[arg(long)]
+    reader: Option<Reader>,
+    #[arg(long)]
+    map_cache_size: Option<usize>,
+    #[arg(long)]
+    rate_limit: Option<u64>,
+}
+
+/// A setting as it is read from an environment variable.
+trait FromEnv: Sized {
+    fn from_env(value: &str) -> Option<Self>;
+}
+
+macro_rules! from_str_env {
+    ($($type:ty),*) => {
+        $(impl FromEnv for $type {
+            fn from_env(value: &str) -> Option<Self> {
+                FromStr::from_str(value).ok()
+            }
+        })*
+    };
+}
+from_str_env!(String, PathBuf, usize, u64);
+
+macro_rules! value_enum_env {
+    ($($type:ty),*) => {
+        $(impl FromEnv for $type {
+            fn from_env(value: &str) -> Option<Self> {
+                ValueEnum::from_str(value, true).ok()
+            }
+        })*
+    };
+}
+value_enum_env!(LogFormat, Strategy, Reader);
+
+/// The setting `name` from the variable `SSTABLE_<NAME>`, if it is set.
+fn from_env<T: FromEnv>(env: &impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>, Error> {
+    let variable = format!("SSTABLE_{}", name.to_ascii_uppercase());
+    env(&variable)
+        .map(|value| T::from_env(&value).ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Invalid {variable}: {value:?}"))))
+        .transpose()
+}
+
+impl Config {
+    /// Reads the command line, the environment and the config file it points to.
+    pub fn load() -> Result<Config, Error> {
+        Self::from_args(Args::parse(), |name| std::env::var(name).ok())
+    }
+
+    /// `env` looks up environment variables by name.
+    fn from_args(args: Args, env: impl Fn(&str) -> Option<String>) -> Result<Config, Error> {
+        let path = match args.config {
+            Some(path) => Some(path),
+            None => from_env(&env, "config")?,
+        };
+        let mut config = match &path {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .map_err(|e| Error::new(e.kind(), format!("Failed to read {}: {}", path.display(), e)))?;
+                toml::from_str(&content)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid config {}: {}", path.display(), e)))?
+            }
+            None => Config::default(),
+        };
+
+        /* A flag wins over its environment variable */
+        macro_rules! apply {
+            ($($field:ident),*) => {
+                $(match args.$field {
+                    Some(value) => config.$field = value,
+                    None => if let Some(value) = from_env(&env, stringify!($field))? {
+                        config.$field = value;
+                    },
+                })*
+            };
+        }
+        macro_rules! apply_optional {
+            ($($field:ident),*) => {
+                $(match args.$field {
+                    Some(value) => config.$field = Some(value),
+                    None => if let Some(value) = from_env(&env, stringify!($field))? {
+                        config.$field = Some(value);
+                    },
+                })*
+            };
+        }
+        apply!(bind, data_dir, admin_dir, log_level, log_format, comparator, memtable_size, immutable_memtables_slowdown_trigger,
+            max_immutable_memtables, level0_slowdown_writes_trigger, level0_stop_writes_trigger, write_slowdown_delay_ms,
+            write_stall_timeout_ms, compaction_strategy, compaction_size_limit, max_background_compactions, max_subcompactions,
+            compaction_interval_ms, value_log_gc_interval_secs, value_log_segment_size, reader, map_cache_size, rate_limit);
+        apply_optional!(resp_bind, memtable_count_limit, value_log_threshold);
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Refuses settings the engine can't run with, like intervals that would spin a thread.
+    fn validate(&self) -> Result<(), Error> {
+        let positive = [
+            ("memtable_size", self.memtable_size as u64),
+            ("max_immutable_memtables", self.max_immutable_memtables as u64),
+            ("max_background_compactions", self.max_background_compactions as u64),
+            ("max_subcompactions", self.max_subcompactions as u64),
+            ("compaction_interval_ms", self.compaction_interval_ms),
+            ("value_log_gc_interval_secs", self.value_log_gc_interval_secs),
+            ("value_log_segment_size", self.value_log_segment_size),
+            ("map_cache_size", self.map_cache_size as u64),
+            ("level0_stop_writes_trigger", self.level0_stop_writes_trigger as u64),
+        ];
+        if let Some((name, _)) = positive.iter().find(|(_, value)| *value == 0) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{name} must be greater than 0")));
+        }
+        if comparator::builtin(&self.comparator).is_none() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Unknown comparator {:?}", self.comparator)));
+        }
+        Ok(())
+    }
+
+    pub fn options(&self) -> Options {
+        let compaction_strategy: Arc<dyn CompactionStrategy> = match self.compaction_strategy {
+            Strategy::Leveled => Arc::new(LeveledCompaction),
+            Strategy::SizeTiered => Arc::new(SizeTieredCompaction::default()),
+            Strategy::Universal => Arc::new(UniversalCompaction::default()),
+        };
+        Options {
+            comparator: comparator::builtin(&self.comparator).expect("comparator is validated on load"),
+            merge_operator: Some(Arc::new(U64AddOperator)),
+            rate_limiter: (self.rate_limit > 0).then(|| Arc::new(RateLimiter::new(self.rate_limit))),
+            reader: match self.reader {
+                Reader::Buffered => ReaderBackend::Buffered,
+                Reader::Mmap => ReaderBackend::Mmap,
+            },
+            map_cache_size: self.map_cache_size,
+            memtable_size_limit: self.memtable_size,
+            memtable_count_limit: self.memtable_count_limit,
+            immutable_memtables_slowdown_trigger: self.immutable_memtables_slowdown_trigger,
+            max_immutable_memtables: self.max_immutable_memtables,
+            level0_slowdown_writes_trigger: self.level0_slowdown_writes_trigger,
+            level0_stop_writes_trigger: self.level0_stop_writes_trigger,
+            write_slowdown_delay: Duration::from_millis(self.write_slowdown_delay_ms),
+            write_stall_timeout: Duration::from_millis(self.write_stall_timeout_ms),
+            compaction_strategy,
+            compaction_size_limit: self.compaction_size_limit,
+            max_background_compactions: self.max_background_compactions,
+            max_subcompactions: self.max_subcompactions,
+            compaction_interval: Duration::from_millis(self.compaction_interval_ms),
+            value_log_gc_interval: Duration::from_secs(self.value_log_gc_interval_secs),
+            value_log_threshold: self.value_log_threshold,
+            value_log_segment_size: self.value_log_segment_size,
+            ..Options::default()
+        }
+    }
+
+    /// The config as a TOML file would hold it.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use super::*;
+
+    fn load_with_env(args: &[&str], env: &[(&str, &str)]) -> Result<Config, Error> {
+        let env = env.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect::<HashMap<_, _>>();
+        let args = Args::try_parse_from(std::iter::once("test").chain(args.iter().copied())).unwrap();
+        Config::from_args(args, |name| env.get(name).cloned())
+    }
+
+    fn load(args: &[&str]) -> Result<Config, Error> {
+        load_with_env(args, &[])
+    }
+
+    #[test]
+    fn flags_win_over_environment_over_file() {
+        let file = env::temp_dir().join(format!("sstable_config_{}.toml", std::process::id()));
+        fs::write(&file, "bind = \"file:1\"\nlog_level = \"file\"\nmemtable_size = 1\n").unwrap();
+        let file = file.to_str().unwrap();
+
+        let environment = [("SSTABLE_CONFIG", file), ("SSTABLE_LOG_LEVEL", "env"), ("SSTABLE_MEMTABLE_SIZE", "2"), ("SSTABLE_READER", "mmap")];
+        let config = load_with_env(&["--memtable-size", "3"], &environment).unwrap();
+
+        assert_eq!(config.bind, "file:1");
+        assert_eq!(config.log_level, "env");
+        assert_eq!(config.memtable_size, 3);
+        assert!(matches!(config.reader, Reader::Mmap));
+        // Untouched settings keep their defaults
+        assert_eq!(config.max_subcompactions, Config::default().max_subcompactions);
+
+        let error = load_with_env(&[], &[("SSTABLE_MEMTABLE_SIZE", "big")]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn unknown_settings_are_refused() {
+        let error = toml::from_str::<Config>("bind = \"127.0.0.1:1\"\nmemtable_sise = 1\n").unwrap_err();
+        assert!(error.to_string().contains("memtable_sise"));
+    }
+
+    #[test]
+    fn zero_sizes_and_intervals_are_refused() {
+        for flag in ["--compaction-interval-ms", "--memtable-size", "--max-background-compactions", "--value-log-segment-size"] {
+            let error = load(&[flag, "0"]).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput, "{flag}");
+        }
+        assert!(load(&["--compaction-interval-ms", "1"]).is_ok());
+        assert_eq!(load(&["--comparator", "reverse"]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn settings_reach_the_options() {
+        let config = load(&[
+            "--comparator", "numeric", "--level0-stop-writes-trigger", "5", "--write-stall-timeout-ms", "250",
+            "--value-log-segment-size", "4096", "--map-cache-size", "8",
+        ]).unwrap();
+        let options = config.options();
+        assert_eq!(options.comparator.name(), "numeric");
+        assert_eq!(options.level0_stop_writes_trigger, 5);
+        assert_eq!(options.write_stall_timeout, Duration::from_millis(250));
+        assert_eq!(options.value_log_segment_size, 4096);
+        assert_eq!(options.map_cache_size, 8);
+        // The limiter is off unless a rate is set, like in `Options`
+        assert!(options.rate_limiter.is_none());
+        assert!(load(&["--rate-limit", "1024"]).unwrap().options().rate_limiter.is_some());
+    }
+}
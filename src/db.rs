@@ -0,0 +1,484 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+use crate::avl::FlushSignal;
+use crate::backup::BackupEngine;
+use crate::column_family::{self, ColumnFamily, CompactionProgress, FlushedTable};
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::idx::IDX;
+use crate::manifest::Manifest;
+use crate::metrics::Metrics;
+use crate::options::Options;
+use crate::sst::SST;
+use crate::wal::Wal;
+use crate::write_batch::{BatchOp, WriteBatch};
+
+/// Name of the column family that always exists and lives in the database directory itself.
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+
+/// Finished manual compactions whose progress is kept, the oldest are evicted first.
+pub const MAX_FINISHED_COMPACTIONS: usize = 64;
+
+/// Compactions started by `Db::start_compact_range`, by id.
+#[derive(Default)]
+struct ManualCompactions {
+    next_id: usize,
+    progress: BTreeMap<usize, Arc<Mutex<CompactionProgress>>>,
+}
+
+impl ManualCompactions {
+    fn insert(&mut self, progress: Arc<Mutex<CompactionProgress>>) -> usize {
+        let finished = self.progress.iter()
+            .filter(|(_, progress)| progress.lock().unwrap().done)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in &finished[..finished.len().saturating_sub(MAX_FINISHED_COMPACTIONS - 1)] {
+            self.progress.remove(id);
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.progress.insert(id, progress);
+        id
+    }
+}
+
+/// State of a key while a `WriteBatch` is staged, before anything is written.
+enum Staged {
+    Value(String),
+    Operands(Vec<String>),
+    Deleted,
+    Absent,
+}
+
+/// Ties the column families of one directory together behind one handle.
+///
+/// Named families are kept in subdirectories and listed in the `MANIFEST`.
+pub struct Db {
+    path: PathBuf,
+    options: Arc<Options>,
+    column_families: RwLock<BTreeMap<String, Arc<ColumnFamily>>>,
+    flush_signal: Arc<FlushSignal>,
+    metrics: Arc<Metrics>,
+    wal: Arc<Wal>,
+    /// Progress of the compactions started by `start_compact_range`.
+    manual_compactions: Mutex<ManualCompactions>,
+    /// Set by `shutdown`, the condvar wakes up background threads sleeping in `wait_for_shutdown`.
+    shutdown: (Mutex<bool>, Condvar),
+}
+
+impl Db {
+    /// Opens the database in `path`, every named column family gets the default options.
+    pub fn open(path: impl AsRef<Path>, options: Options) -> Result<Db, Error> {
+        Self::open_with_column_families(path, options, HashMap::new())
+    }
+
+    /// Fails when the database was created with another comparator.
+    pub fn open_with_column_families(
+        path: impl AsRef<Path>,
+        options: Options,
+        mut family_options: HashMap<String, Options>,
+    ) -> Result<Db, Error> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+        column_family::remove_stale_dirs(&path, BackupEngine::CHECKPOINT_PREFIX);
+
+        let comparator = options.comparator.name().to_string();
+        let manifest = Manifest::load(&path)?;
+        let created_with = match &manifest {
+            Some(manifest) => Some(manifest.comparator.as_str()),
+            // Tables written before the manifest existed are in `str` order
+            None if !IDX::idx_files(&path).is_empty() => Some(BytewiseComparator.name()),
+            None => None,
+        };
+
+        if let Some(created_with) = created_with.filter(|created_with| *created_with != comparator.as_str()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Database was created with the {created_with} comparator, not {comparator}"),
+            ));
+        }
+
+        let options = Arc::new(options);
+        let flush_signal = Arc::new(FlushSignal::default());
+        let metrics = Arc::new(Metrics::default());
+        let wal = Arc::new(Wal::open(&path)?);
+        let mut column_families = BTreeMap::new();
+        column_families.insert(
+            DEFAULT_COLUMN_FAMILY.to_string(),
+            Arc::new(ColumnFamily::open(DEFAULT_COLUMN_FAMILY, &path, Arc::clone(&options), Arc::clone(&flush_signal), Arc::clone(&metrics), Arc::clone(&wal))?),
+        );
+
+        let names = manifest.as_ref().map(|manifest| manifest.column_families.clone()).unwrap_or_default();
+        for name in names {
+            let options = match family_options.remove(&name) {
+                Some(options) => Arc::new(options),
+                None => Arc::clone(&options),
+            };
+            Self::check_comparator(&name, &options, &comparator)?;
+            let column_family = ColumnFamily::open(&name, &path.join(&name), options, Arc::clone(&flush_signal), Arc::clone(&metrics), Arc::clone(&wal))?;
+            column_families.insert(name.clone(), Arc::new(column_family));
+        }
+
+        let db = Db { path, options, column_families: RwLock::new(column_families),
+            flush_signal,
+            metrics,
+            wal,
+            manual_compactions: Mutex::new(ManualCompactions::default()),
+            shutdown: (Mutex::new(false), Condvar::new()),
+        };
+        if manifest.is_none() {
+            db.store_manifest(&db.column_families.read().unwrap())?;
+        }
+        db.replay_wal()?;
+
+        Ok(db)
+    }
+
+    /// Puts the writes the tables missed back into the memtables and flushes them, after which
+    /// the replayed segments are removed.
+    fn replay_wal(&self) -> Result<(), Error> {
+        let batches = self.wal.replay()?;
+        if batches.is_empty() {
+            self.wal.remove_replayed();
+            return Ok(());
+        }
+
+        let mut replayed = 0;
+        for batch in batches {
+            /* Families dropped without the log knowing, by an older version, are skipped, and so
+               are keys older versions logged without checking them */
+            let mut known = WriteBatch::new();
+            for op in batch.ops().iter().filter(|op| self.column_family(op.family()).is_some()) {
+                match IDX::check_key(op.key()) {
+                    Ok(()) => {
+                        known.push(op.clone());
+                    }
+                    Err(e) => tracing::warn!("Skipped logged write of {:?} in {}: {}", op.key(), op.family(), e),
+                }
+            }
+            replayed += known.len();
+            self.apply(&known, false)?;
+        }
+        for column_family in self.column_families() {
+            column_family.flush(true)?;
+        }
+        self.wal.remove_replayed();
+        tracing::info!("Write-ahead log replayed, {replayed} writes recovered");
+        Ok(())
+    }
+
+    fn check_comparator(name: &str, options: &Options, comparator: &str) -> Result<(), Error> {
+        /* All families share the manifest, so they share its comparator too */
+        if options.comparator.name() != comparator {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Column family {name} has to use the {comparator} comparator"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn store_manifest(&self, column_families: &BTreeMap<String, Arc<ColumnFamily>>) -> Result<(), Error> {
+        self.manifest(column_families).store(&self.path)
+    }
+
+    fn manifest(&self, column_families: &BTreeMap<String, Arc<ColumnFamily>>) -> Manifest {
+        Manifest {
+            comparator: self.options.comparator.name().to_string(),
+            column_families: column_families.keys().filter(|name| *name != DEFAULT_COLUMN_FAMILY).cloned().collect(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn options(&self) -> &Arc<Options> {
+        &self.options
+    }
+
+    /// Where column families report memtables that are ready to be flushed.
+    pub fn flush_signal(&self) -> &FlushSignal {
+        &self.flush_signal
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Asks the background threads to stop: the flush thread once nothing is pending, the
+    /// compaction threads after the jobs they already picked. Reads and writes keep working,
+    /// memtables are only flushed by `flush` from now on.
+    pub fn shutdown(&self) {
+        *self.shutdown.0.lock().unwrap() = true;
+        self.shutdown.1.notify_all();
+        self.flush_signal.close();
+    }
+
+    /// Sleeps for `timeout` or until `shutdown` is called, returns whether it was.
+    pub fn wait_for_shutdown(&self, timeout: Duration) -> bool {
+        let stopped = self.shutdown.1.wait_timeout_while(self.shutdown.0.lock().unwrap(), timeout, |stopped| !*stopped).unwrap().0;
+        *stopped
+    }
+
+    /// Creates an empty column family in a subdirectory named after it.
+    pub fn create_column_family(&self, name: &str, options: Options) -> Result<Arc<ColumnFamily>, Error> {
+        let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid column family name: {name:?}")));
+        }
+        Self::check_comparator(name, &options, self.options.comparator.name())?;
+
+        let mut column_families = self.column_families.write().map_err(|_| Error::other("column family lock is poisoned"))?;
+        if column_families.contains_key(name) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("Column family {name} already exists")));
+        }
+
+        let column_family = Arc::new(ColumnFamily::open(
+            name,
+            &self.path.join(name),
+            Arc::new(options),
+            Arc::clone(&self.flush_signal),
+            Arc::clone(&self.metrics),
+            Arc::clone(&self.wal),
+        )?);
+        column_families.insert(name.to_string(), Arc::clone(&column_family));
+        if let Err(e) = self.store_manifest(&column_families) {
+            column_families.remove(name);
+            return Err(e);
+        }
+
+        Ok(column_family)
+    }
+
+    /// Forgets the column family and removes its directory with everything in it.
+    pub fn drop_column_family(&self, name: &str) -> Result<(), Error> {
+        if name == DEFAULT_COLUMN_FAMILY {
+            return Err(Error::new(ErrorKind::InvalidInput, "The default column family can not be dropped"));
+        }
+
+        let mut column_families = self.column_families.write().map_err(|_| Error::other("column family lock is poisoned"))?;
+        let column_family = column_families.remove(name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Column family {name} does not exist")))?;
+        self.store_manifest(&column_families)?;
+        self.wal.drop_family(name)?;
+
+        fs::remove_dir_all(column_family.path())
+    }
+
+    pub fn column_family(&self, name: &str) -> Option<Arc<ColumnFamily>> {
+        self.column_families.read().unwrap().get(name).cloned()
+    }
+
+    /// Every column family, the default one included, ordered by name.
+    pub fn column_families(&self) -> Vec<Arc<ColumnFamily>> {
+        self.column_families.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn default_column_family(&self) -> Arc<ColumnFamily> {
+        self.column_family(DEFAULT_COLUMN_FAMILY).unwrap()
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        self.default_column_family().get(key)
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<(), Error> {
+        self.default_column_family().set(key, value)
+    }
+
+    /// See `ColumnFamily::merge`.
+    pub fn merge(&self, key: &str, operand: &str) -> Result<(), Error> {
+        self.default_column_family().merge(key, operand)
+    }
+
+    /// See `ColumnFamily::delete`.
+    pub fn delete(&self, key: &str) -> Result<(), Error> {
+        self.default_column_family().delete(key)
+    }
+
+    /// Creates `dest` as a copy of the database that `open` accepts, out of hard links to the
+    /// tables wherever possible. See `ColumnFamily::checkpoint` for what it contains.
+    ///
+    /// The copy is put together next to `dest` and renamed, so `dest` is either complete or missing.
+    pub fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<usize, Error> {
+        let dest = dest.as_ref();
+        if dest.exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", dest.display())));
+        }
+        /* Never removes what is already there, a leftover from a crash has to be cleaned up by hand */
+        let tmp = PathBuf::from(format!("{}.tmp", dest.display()));
+        if let Some(parent) = tmp.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::create_dir(&tmp).map_err(|e| Error::new(e.kind(), format!("Failed to create {}: {}", tmp.display(), e)))?;
+
+        /* Column families can't be created or dropped meanwhile */
+        let column_families = self.column_families.read().map_err(|_| Error::other("column family lock is poisoned"))?;
+        let result = (|| {
+            let mut files = 0;
+            for (name, column_family) in column_families.iter() {
+                let family_dest = match name.as_str() {
+                    DEFAULT_COLUMN_FAMILY => tmp.clone(),
+                    _ => tmp.join(name),
+                };
+                fs::create_dir_all(&family_dest)?;
+                files += column_family.checkpoint(&family_dest)?;
+            }
+            self.manifest(&column_families).store(&tmp)?;
+            fs::rename(&tmp, dest)?;
+            Ok(files)
+        })();
+
+        if result.is_err() {
+            let _ = fs::remove_dir_all(&tmp);
+        }
+        result
+    }
+
+    /// See `ColumnFamily::ingest_external_files`.
+    pub fn ingest_external_files(&self, paths: &[PathBuf]) -> Result<Vec<String>, Error> {
+        self.default_column_family().ingest_external_files(paths)
+    }
+
+    /// See `ColumnFamily::flush`.
+    pub fn flush(&self, wait: bool) -> Result<Option<FlushedTable>, Error> {
+        self.default_column_family().flush(wait)
+    }
+
+    /// See `ColumnFamily::compact_range`.
+    pub fn compact_range(&self, start: Option<&str>, end: Option<&str>) -> Result<CompactionProgress, Error> {
+        self.default_column_family().compact_range(start, end, &|_| {})
+    }
+
+    /// Runs `ColumnFamily::compact_range` on a thread of its own and returns an id for `manual_compaction`.
+    pub fn start_compact_range(&self, family: &str, start: Option<String>, end: Option<String>) -> Result<usize, Error> {
+        let column_family = self.column_family(family)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Column family {family} does not exist")))?;
+
+        let shared = Arc::new(Mutex::new(CompactionProgress::default()));
+        let id = self.manual_compactions.lock().unwrap().insert(Arc::clone(&shared));
+
+        thread::spawn(move || {
+            let update = |progress: &CompactionProgress| *shared.lock().unwrap() = progress.clone();
+            if let Err(e) = column_family.compact_range(start.as_deref(), end.as_deref(), &update) {
+                let mut progress = shared.lock().unwrap();
+                progress.done = true;
+                progress.error = Some(e.to_string());
+            }
+        });
+        Ok(id)
+    }
+
+    /// `None` for unknown ids and for finished compactions that were evicted, see `MAX_FINISHED_COMPACTIONS`.
+    pub fn manual_compaction(&self, id: usize) -> Option<CompactionProgress> {
+        let manual_compactions = self.manual_compactions.lock().unwrap();
+        manual_compactions.progress.get(&id).map(|progress| progress.lock().unwrap().clone())
+    }
+
+    /// Applies every write of the batch, or none of them when one fails.
+    ///
+    /// The memtables of all touched families are locked together, in name order, so
+    /// readers never see part of a batch. The batch is one record of the write-ahead log,
+    /// after a crash it is replayed whole or not at all.
+    pub fn write(&self, batch: &WriteBatch) -> Result<(), Error> {
+        self.apply(batch, true)
+    }
+
+    /// `log` is off while the batch is replayed from the write-ahead log.
+    fn apply(&self, batch: &WriteBatch, log: bool) -> Result<(), Error> {
+        let mut families = BTreeMap::new();
+        for op in batch.ops() {
+            if !families.contains_key(op.family()) {
+                let column_family = self.column_family(op.family()).ok_or_else(|| {
+                    Error::new(ErrorKind::NotFound, format!("Column family {} does not exist", op.family()))
+                })?;
+                families.insert(op.family().to_string(), column_family);
+            }
+            /* A key the tables can't hold would fail every flush, and every replay of the log */
+            IDX::check_key(op.key())?;
+            match op {
+                BatchOp::Put { value, .. } => SST::check_value(value)?,
+                BatchOp::Merge { key, operand, .. } => {
+                    SST::check_value(operand)?;
+                    if let Some(operator) = &families[op.family()].options().merge_operator {
+                        operator.check_operand(key, operand)?;
+                    }
+                }
+                BatchOp::Delete { .. } => {}
+            }
+        }
+
+        if log {
+            for column_family in families.values() {
+                column_family.wait_for_write_stall()?;
+            }
+        }
+
+        let mut trees = BTreeMap::new();
+        for (name, column_family) in &families {
+            let tree = column_family.memtable().get_instance().write().map_err(|_| Error::other("memtable lock is poisoned"))?;
+            trees.insert(name.as_str(), tree);
+        }
+
+        /* Merges can fail, so everything is staged before the first memtable is touched */
+        let mut staged: HashMap<(&str, &str), Staged> = HashMap::new();
+        for op in batch.ops() {
+            let slot = (op.family(), op.key());
+            let current = staged.remove(&slot).unwrap_or_else(|| match trees[op.family()].get(op.key()) {
+                Some(node) if node.deleted => Staged::Deleted,
+                Some(node) if node.operands.is_empty() => Staged::Value(node.value.clone()),
+                Some(node) => Staged::Operands(node.operands.clone()),
+                None => Staged::Absent,
+            });
+
+            let next = match op {
+                BatchOp::Put { value, .. } => Staged::Value(value.clone()),
+                BatchOp::Delete { .. } => Staged::Deleted,
+                BatchOp::Merge { key, operand, .. } => {
+                    let operator = families[op.family()].options().merge_operator.as_ref()
+                        .ok_or_else(|| Error::new(ErrorKind::Unsupported, "No merge operator configured"))?;
+                    match current {
+                        Staged::Value(value) => Staged::Value(operator.full_merge(key, Some(&value), std::slice::from_ref(operand))?),
+                        Staged::Operands(mut operands) => {
+                            operands.push(operand.clone());
+                            Staged::Operands(operands)
+                        }
+                        Staged::Deleted => Staged::Value(operator.full_merge(key, None, std::slice::from_ref(operand))?),
+                        Staged::Absent => Staged::Operands(vec![operand.clone()]),
+                    }
+                }
+            };
+            staged.insert(slot, next);
+        }
+
+        if log {
+            self.wal.append(batch.ops())?;
+        }
+        for ((family, key), state) in staged {
+            let tree = trees.get_mut(family).unwrap();
+            match state {
+                Staged::Value(value) => tree.set(key, &value),
+                Staged::Operands(operands) => {
+                    tree.unset(key);
+                    for operand in operands {
+                        tree.merge(key, &operand);
+                    }
+                }
+                Staged::Deleted => tree.delete(key),
+                Staged::Absent => {}
+            }
+        }
+
+        for (name, tree) in trees.iter_mut() {
+            families[*name].switch_memtable_if_full(tree);
+        }
+
+        Ok(())
+    }
+}
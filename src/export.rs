@@ -0,0 +1,116 @@
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+use crate::column_family::ColumnFamily;
+use crate::db::Db;
+use crate::snapshot::Snapshot;
+use crate::write_batch::WriteBatch;
+
+/// Writes per batch while importing.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// Line formats of an export, both hold one key and its value per record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// `{"key":"a","value":"1"}` per line.
+    JsonLines,
+    /// A `key,value` header, then quoted as RFC 4180 asks.
+    Csv,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::JsonLines => "application/x-ndjson",
+            Format::Csv => "text/csv",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Format, Error> {
+        match format {
+            "jsonl" | "ndjson" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("Unknown format {format:?}, use jsonl or csv"))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: String,
+}
+
+/// Writes every live key of a snapshot of the family and returns how many were written.
+pub fn export(column_family: &ColumnFamily, format: Format, writer: impl Write) -> Result<usize, Error> {
+    let snapshot = Snapshot::new(column_family)?;
+    let mut exported = 0;
+
+    match format {
+        Format::JsonLines => {
+            let mut writer = writer;
+            for entry in snapshot.entries()? {
+                let (key, value) = entry?;
+                serde_json::to_writer(&mut writer, &Record { key, value })?;
+                writer.write_all(b"\n")?;
+                exported += 1;
+            }
+            writer.flush()?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer.write_record(["key", "value"])?;
+            for entry in snapshot.entries()? {
+                let (key, value) = entry?;
+                writer.write_record([key, value])?;
+                exported += 1;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(exported)
+}
+
+/// Loads records written by `export` into the family through write batches, and returns how
+/// many were imported. On an error the batches before it stay written.
+///
+/// Full memtables are flushed on the way, so it also works without the flush thread.
+pub fn import(db: &Db, family: &str, format: Format, reader: impl Read) -> Result<usize, Error> {
+    let column_family = db.column_family(family)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Column family {family} does not exist")))?;
+
+    let records: Box<dyn Iterator<Item = Result<Record, Error>>> = match format {
+        Format::JsonLines => Box::new(BufReader::new(reader).lines()
+            .filter(|line| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+            .map(|line| Ok(serde_json::from_str(&line?)?))),
+        Format::Csv => Box::new(csv::Reader::from_reader(reader).into_deserialize().map(|record| Ok(record?))),
+    };
+
+    let mut imported = 0;
+    let mut batch = WriteBatch::new();
+    for record in records {
+        let record = record?;
+        batch.put(family, &record.key, &record.value);
+
+        if batch.len() >= IMPORT_BATCH_SIZE {
+            imported += write(db, &column_family, &batch)?;
+            batch = WriteBatch::new();
+        }
+    }
+    imported += write(db, &column_family, &batch)?;
+
+    Ok(imported)
+}
+
+fn write(db: &Db, column_family: &ColumnFamily, batch: &WriteBatch) -> Result<usize, Error> {
+    db.write(batch)?;
+    if column_family.immutable_memtables() > 0 {
+        column_family.flush_immutables()?;
+    }
+    Ok(batch.len())
+}
@@ -1,12 +1,24 @@
+use std::collections::HashMap;
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::path::{Component, PathBuf};
 use std::sync::Arc;
 use axum::{
-    extract::State,
-    http::StatusCode,
+    body::{Body, Bytes},
+    extract::{Extension, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
-use crate::avl::AVLTreeSingleton;
-use crate::idx::IDX;
+use sstable::backup::BackupEngine;
+use sstable::column_family::{ColumnFamily, Version};
+use sstable::db::{Db, DEFAULT_COLUMN_FAMILY};
+use sstable::export::{self, Format};
+use sstable::idx::IDX;
+use sstable::write_batch::{BatchOp, WriteBatch};
 
 #[derive(Serialize)]
 pub struct Message {
@@ -14,6 +26,44 @@ pub struct Message {
     error: Option<String>,
 }
 
+fn column_family(db: &Db, family: Option<Path<String>>) -> Result<Arc<ColumnFamily>, StatusCode> {
+    /* Routes without a family segment use the default one */
+    let name = family.map_or(DEFAULT_COLUMN_FAMILY.to_string(), |Path(name)| name);
+    db.column_family(&name).ok_or(StatusCode::NOT_FOUND)
+}
+
+fn status_code(kind: ErrorKind) -> StatusCode {
+    match kind {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+        ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+        // Writes are stopped until flushes and compactions catch up
+        ErrorKind::TimedOut => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Directory the paths given to `/admin/checkpoint` and `/admin/backup` are resolved in.
+#[derive(Clone)]
+pub struct AdminDir(pub PathBuf);
+
+impl AdminDir {
+    /// Refuses absolute paths and `..`, so a request can't reach outside the directory.
+    fn resolve(&self, path: &str) -> Result<PathBuf, StatusCode> {
+        let path = std::path::Path::new(path);
+        let inside = path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if path.as_os_str().is_empty() || !inside {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Ok(self.0.join(path))
+    }
+}
+
+/// Runs `write` off the runtime threads, writes can sleep through a write stall.
+async fn blocking<T: Send + 'static>(write: impl FnOnce() -> T + Send + 'static) -> Result<T, StatusCode> {
+    tokio::task::spawn_blocking(write).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct SetRequest {
     key: String,
@@ -21,16 +71,16 @@ pub struct SetRequest {
 }
 
 pub async fn set(
-    State(tree_singleton): State<Arc<AVLTreeSingleton>>,
+    State(db): State<Arc<Db>>,
+    family: Option<Path<String>>,
     Json(request): Json<SetRequest>,
 ) -> Result<Json<Message>, StatusCode> {
-    
-    let tree = tree_singleton.get_instance();
-    let mut tree = tree.write().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    tree.set(&request.key, &request.value);
-    
+    let db = column_family(&db, family)?;
+    let SetRequest { key, value } = request;
+    let value = blocking(move || db.set(&key, &value).map(|()| value)).await?.map_err(|e| status_code(e.kind()))?;
+
     Ok(Json(Message {
-        value: Some(request.value),
+        value: Some(value),
         error: None,
     }))
 }
@@ -41,19 +91,12 @@ pub struct GetRequest {
 }
 
 pub async fn get(
-    State(tree_singleton): State<Arc<AVLTreeSingleton>>,
+    State(db): State<Arc<Db>>,
+    family: Option<Path<String>>,
     Json(request): Json<GetRequest>,
 ) -> Result<Json<Message>, StatusCode> {
-    let tree = tree_singleton.get_instance();
-    let tree = tree.write().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let mut result = tree.get(&request.key).map(|node| node.value.clone());
-    
-    if result.is_none() {
-
-        if let Some(index_value) = IDX::search_key_in_all_files(&request.key) {
-            result = Some(index_value.value);
-        }
-    }
+    let db = column_family(&db, family)?;
+    let result = db.get(&request.key).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     let error = result.is_none().then(|| "Key not found".to_string());
 
@@ -63,22 +106,701 @@ pub async fn get(
     }))
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct MergeRequest {
+    key: String,
+    value: String,
+}
+
+pub async fn merge(
+    State(db): State<Arc<Db>>,
+    family: Option<Path<String>>,
+    Json(request): Json<MergeRequest>,
+) -> Result<Json<Message>, StatusCode> {
+    let db = column_family(&db, family)?;
+    let error = match blocking(move || db.merge(&request.key, &request.value)).await? {
+        Ok(()) => None,
+        Err(e) if e.kind() == ErrorKind::TimedOut => return Err(StatusCode::SERVICE_UNAVAILABLE),
+        Err(e) => Some(e.to_string()),
+    };
+
+    Ok(Json(Message {
+        value: None,
+        error,
+    }))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct DeleteRequest {
     key: String,
 }
 
 pub async fn delete(
-    State(tree_singleton): State<Arc<AVLTreeSingleton>>,
+    State(db): State<Arc<Db>>,
+    family: Option<Path<String>>,
     Json(request): Json<DeleteRequest>,
 ) -> Result<Json<Message>, StatusCode> {
-    let tree = tree_singleton.get_instance();
-    let mut tree = tree.write().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let db = column_family(&db, family)?;
+    blocking(move || db.delete(&request.key)).await?.map_err(|e| status_code(e.kind()))?;
+
+    Ok(Json(Message {
+        value: None,
+        error: None,
+    }))
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Raw,
+    Base64,
+}
+
+#[derive(Deserialize)]
+pub struct KvQuery {
+    #[serde(default)]
+    encoding: Encoding,
+}
+
+type KvError = (StatusCode, String);
+
+/// The family and key of `/kv/:key` and `/cf/:family/kv/:key`.
+fn kv_target(db: &Db, params: &HashMap<String, String>) -> Result<(Arc<ColumnFamily>, String), KvError> {
+    let name = params.get("family").map_or(DEFAULT_COLUMN_FAMILY, String::as_str);
+    let column_family = db.column_family(name)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Column family {name} does not exist")))?;
+
+    let key = params["key"].clone();
+    IDX::check_key(&key).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok((column_family, key))
+}
+
+fn kv_error(e: io::Error) -> KvError {
+    (status_code(e.kind()), e.to_string())
+}
+
+/// `blocking` for the `/kv` routes, reads go to disk when the memtables miss.
+async fn kv_blocking<T: Send + 'static>(read: impl FnOnce() -> io::Result<T> + Send + 'static) -> Result<T, KvError> {
+    blocking(read).await.map_err(|status| (status, String::new()))?.map_err(kv_error)
+}
+
+/// The version of the write with the CRC32 of the value, so writing a value again gives a new
+/// tag and no tag names two values. Flushes and compactions change the tag as well, see `Version`.
+fn etag(value: &str, version: &Version) -> String {
+    let crc32 = crc32fast::hash(value.as_bytes());
+    match version {
+        Version::Memtable(sequence) => format!("\"m{sequence:x}-{crc32:08x}\""),
+        Version::Table(table, offset) => format!("\"t{table}-{offset:x}-{crc32:08x}\""),
+    }
+}
+
+/// Whether an `If-Match` or `If-None-Match` list names the tag, `*` names any existing value.
+fn etag_matches(header: &HeaderValue, etag: Option<&str>) -> bool {
+    let (Some(etag), Ok(header)) = (etag, header.to_str()) else { return false };
+    header.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+fn check_preconditions(headers: &HeaderMap, current: Option<&(String, Version)>) -> Result<(), KvError> {
+    let etag = current.map(|(value, version)| etag(value, version));
+    let failed = headers.get(header::IF_MATCH).is_some_and(|header| !etag_matches(header, etag.as_deref()))
+        || headers.get(header::IF_NONE_MATCH).is_some_and(|header| etag_matches(header, etag.as_deref()));
+    match failed {
+        true => Err((StatusCode::PRECONDITION_FAILED, "Precondition failed".to_string())),
+        false => Ok(()),
+    }
+}
+
+/// The value as the body, base64 encoded with `?encoding=base64`. `HEAD` answers the same
+/// without the body, and `If-None-Match` with a current tag gives 304.
+pub async fn get_kv(
+    State(db): State<Arc<Db>>,
+    Path(params): Path<HashMap<String, String>>,
+    Query(query): Query<KvQuery>,
+    headers: HeaderMap,
+) -> Result<Response, KvError> {
+    let (column_family, key) = kv_target(&db, &params)?;
+    let (value, version) = kv_blocking(move || column_family.get_versioned(&key)).await?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Key not found".to_string()))?;
+
+    let etag = etag(&value, &version);
+    if headers.get(header::IF_NONE_MATCH).is_some_and(|header| etag_matches(header, Some(&etag))) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+
+    let (content_type, body) = match query.encoding {
+        Encoding::Raw => ("application/octet-stream", value),
+        Encoding::Base64 => ("text/plain; charset=utf-8", BASE64.encode(value)),
+    };
+    Ok(([(header::CONTENT_TYPE, content_type.to_string()), (header::ETAG, etag)], body).into_response())
+}
+
+/// Stores the body, decoded from base64 with `?encoding=base64`. Values are strings, so the
+/// decoded bytes have to be UTF-8. `If-Match` and `If-None-Match: *` make the write conditional.
+pub async fn put_kv(
+    State(db): State<Arc<Db>>,
+    Path(params): Path<HashMap<String, String>>,
+    Query(query): Query<KvQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, KvError> {
+    let (column_family, key) = kv_target(&db, &params)?;
+    let bytes = match query.encoding {
+        Encoding::Raw => body.to_vec(),
+        Encoding::Base64 => BASE64.decode(body.trim_ascii()).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid base64: {e}")))?,
+    };
+    let value = String::from_utf8(bytes).map_err(|_| (StatusCode::BAD_REQUEST, "Value is not UTF-8".to_string()))?;
+
+    let etag = blocking(move || {
+        let _guard = column_family.lock_conditional_writes();
+        if headers.contains_key(header::IF_MATCH) || headers.contains_key(header::IF_NONE_MATCH) {
+            check_preconditions(&headers, column_family.get_versioned(&key).map_err(kv_error)?.as_ref())?;
+        }
+        let version = column_family.set_versioned(&key, &value).map_err(kv_error)?;
+        Ok::<_, KvError>(etag(&value, &version))
+    }).await.map_err(|status| (status, String::new()))??;
+
+    Ok((StatusCode::NO_CONTENT, [(header::ETAG, etag)]).into_response())
+}
+
+pub async fn delete_kv(
+    State(db): State<Arc<Db>>,
+    Path(params): Path<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<StatusCode, KvError> {
+    let (column_family, key) = kv_target(&db, &params)?;
+
+    blocking(move || {
+        let _guard = column_family.lock_conditional_writes();
+        let current = column_family.get_versioned(&key).map_err(kv_error)?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "Key not found".to_string()))?;
+        check_preconditions(&headers, Some(&current))?;
+
+        column_family.delete(&key).map_err(kv_error)?;
+        Ok(StatusCode::NO_CONTENT)
+    }).await.map_err(|status| (status, String::new()))?
+}
+
+pub async fn create_column_family(
+    State(db): State<Arc<Db>>,
+    Path(family): Path<String>,
+) -> Result<Json<Message>, StatusCode> {
+    db.create_column_family(&family, db.options().as_ref().clone()).map_err(|e| status_code(e.kind()))?;
+
+    Ok(Json(Message {
+        value: None,
+        error: None,
+    }))
+}
 
-    tree.unset(&request.key);
+pub async fn drop_column_family(
+    State(db): State<Arc<Db>>,
+    Path(family): Path<String>,
+) -> Result<Json<Message>, StatusCode> {
+    db.drop_column_family(&family).map_err(|e| status_code(e.kind()))?;
 
     Ok(Json(Message {
         value: None,
         error: None,
     }))
 }
+
+fn default_family() -> String {
+    DEFAULT_COLUMN_FAMILY.to_string()
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum BatchRequestOp {
+    Set {
+        #[serde(default = "default_family")]
+        family: String,
+        key: String,
+        value: String,
+    },
+    Delete {
+        #[serde(default = "default_family")]
+        family: String,
+        key: String,
+    },
+    Merge {
+        #[serde(default = "default_family")]
+        family: String,
+        key: String,
+        value: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BatchRequest {
+    ops: Vec<BatchRequestOp>,
+}
+
+pub async fn batch(
+    State(db): State<Arc<Db>>,
+    Json(request): Json<BatchRequest>,
+) -> Result<Json<Message>, StatusCode> {
+    let mut batch = WriteBatch::new();
+    for op in request.ops {
+        batch.push(match op {
+            BatchRequestOp::Set { family, key, value } => BatchOp::Put { family, key, value },
+            BatchRequestOp::Delete { family, key } => BatchOp::Delete { family, key },
+            BatchRequestOp::Merge { family, key, value } => BatchOp::Merge { family, key, operand: value },
+        });
+    }
+
+    let error = match blocking(move || db.write(&batch)).await? {
+        Ok(()) => None,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::TimedOut) => return Err(status_code(e.kind())),
+        Err(e) => Some(e.to_string()),
+    };
+
+    Ok(Json(Message {
+        value: None,
+        error,
+    }))
+}
+
+pub async fn metrics(State(db): State<Arc<Db>>) -> String {
+    sstable::metrics::render(&db)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RateLimitRequest {
+    bytes_per_second: u64,
+}
+
+pub async fn get_rate_limit(
+    State(db): State<Arc<Db>>,
+) -> Result<Json<Message>, StatusCode> {
+    let rate_limiter = db.options().rate_limiter.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(Message {
+        value: Some(rate_limiter.bytes_per_second().to_string()),
+        error: None,
+    }))
+}
+
+pub async fn set_rate_limit(
+    State(db): State<Arc<Db>>,
+    Json(request): Json<RateLimitRequest>,
+) -> Result<Json<Message>, StatusCode> {
+    let rate_limiter = db.options().rate_limiter.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    rate_limiter.set_bytes_per_second(request.bytes_per_second);
+
+    Ok(Json(Message {
+        value: Some(request.bytes_per_second.to_string()),
+        error: None,
+    }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CompactRangeRequest {
+    #[serde(default = "default_family")]
+    family: String,
+    start: Option<String>,
+    end: Option<String>,
+}
+
+/// Starts the compaction in the background, `value` is the id to poll its progress with.
+/// There is no target level, see `ColumnFamily::compact_range`.
+pub async fn compact_range(
+    State(db): State<Arc<Db>>,
+    Json(request): Json<CompactRangeRequest>,
+) -> Result<(StatusCode, Json<Message>), StatusCode> {
+    let id = db.start_compact_range(&request.family, request.start, request.end).map_err(|e| status_code(e.kind()))?;
+
+    Ok((StatusCode::ACCEPTED, Json(Message {
+        value: Some(id.to_string()),
+        error: None,
+    })))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CompactionProgressResponse {
+    tables: usize,
+    compacted_tables: usize,
+    input_bytes: u64,
+    output_bytes: u64,
+    done: bool,
+    error: Option<String>,
+}
+
+/// 404 for unknown ids and for finished compactions evicted by newer ones.
+pub async fn compact_range_progress(
+    State(db): State<Arc<Db>>,
+    Path(id): Path<usize>,
+) -> Result<Json<CompactionProgressResponse>, StatusCode> {
+    let progress = db.manual_compaction(id).ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(CompactionProgressResponse {
+        tables: progress.tables,
+        compacted_tables: progress.compacted_tables,
+        input_bytes: progress.input_bytes,
+        output_bytes: progress.output_bytes,
+        done: progress.done,
+        error: progress.error,
+    }))
+}
+
+fn wait_default() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FlushRequest {
+    #[serde(default = "default_family")]
+    family: String,
+    #[serde(default = "wait_default")]
+    wait: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FlushResponse {
+    /// `None` when there was nothing to flush or the flush was not waited for.
+    table: Option<String>,
+    size: u64,
+}
+
+/// The body is optional, without one the default family is flushed and waited for.
+pub async fn flush(
+    State(db): State<Arc<Db>>,
+    request: Option<Json<FlushRequest>>,
+) -> Result<Json<FlushResponse>, StatusCode> {
+    let Json(request) = request.unwrap_or(Json(FlushRequest { family: default_family(), wait: true }));
+    let column_family = db.column_family(&request.family).ok_or(StatusCode::NOT_FOUND)?;
+
+    /* Writing a table blocks, keep it off the runtime threads */
+    let flushed = tokio::task::spawn_blocking(move || column_family.flush(request.wait))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| status_code(e.kind()))?;
+
+    Ok(Json(FlushResponse {
+        table: flushed.as_ref().map(|table| table.id.clone()),
+        size: flushed.map_or(0, |table| table.size),
+    }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CheckpointRequest {
+    path: String,
+}
+
+/// `path` is relative to the `AdminDir`.
+pub async fn checkpoint(
+    State(db): State<Arc<Db>>,
+    Extension(admin_dir): Extension<AdminDir>,
+    Json(request): Json<CheckpointRequest>,
+) -> Result<Json<Message>, StatusCode> {
+    let path = admin_dir.resolve(&request.path)?;
+    /* Flushes and waits for compactions, keep it off the runtime threads */
+    tokio::task::spawn_blocking(move || db.checkpoint(&path))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| status_code(e.kind()))?;
+
+    Ok(Json(Message {
+        value: Some(request.path),
+        error: None,
+    }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BackupRequest {
+    dir: String,
+    /// Backups to keep afterwards, older ones are purged.
+    keep: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BackupResponse {
+    id: u64,
+    timestamp: u64,
+    files: usize,
+    size: u64,
+}
+
+/// `dir` is relative to the `AdminDir`.
+pub async fn backup(
+    State(db): State<Arc<Db>>,
+    Extension(admin_dir): Extension<AdminDir>,
+    Json(request): Json<BackupRequest>,
+) -> Result<Json<BackupResponse>, StatusCode> {
+    let dir = admin_dir.resolve(&request.dir)?;
+    let backup = tokio::task::spawn_blocking(move || {
+        let engine = BackupEngine::open(&dir)?;
+        let backup = engine.create_backup(&db)?;
+        if let Some(keep) = request.keep {
+            engine.purge_old_backups(keep)?;
+        }
+        Ok::<_, std::io::Error>(backup)
+    })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| status_code(e.kind()))?;
+
+    Ok(Json(BackupResponse {
+        id: backup.id,
+        timestamp: backup.timestamp,
+        files: backup.files.len(),
+        size: backup.size(),
+    }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RestoreRequest {
+    dir: String,
+    id: u64,
+    /// A new directory, the running database is never overwritten.
+    dest: String,
+}
+
+/// `dir` and `dest` are relative to the `AdminDir`.
+pub async fn restore_backup(
+    Extension(admin_dir): Extension<AdminDir>,
+    Json(request): Json<RestoreRequest>,
+) -> Result<Json<Message>, StatusCode> {
+    let (dir, dest) = (admin_dir.resolve(&request.dir)?, admin_dir.resolve(&request.dest)?);
+    tokio::task::spawn_blocking(move || BackupEngine::open(&dir)?.restore(request.id, &dest))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| status_code(e.kind()))?;
+
+    Ok(Json(Message {
+        value: Some(request.dest),
+        error: None,
+    }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransferQuery {
+    format: String,
+    #[serde(default = "default_family")]
+    family: String,
+}
+
+/// Hands what the export writes over to the response body.
+struct ChannelWriter(tokio::sync::mpsc::Sender<Result<Vec<u8>, io::Error>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.blocking_send(Ok(buf.to_vec())).map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "Client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Streams a snapshot of the family, see `export::export`.
+pub async fn export(
+    State(db): State<Arc<Db>>,
+    Query(query): Query<TransferQuery>,
+) -> Result<Response, StatusCode> {
+    let format = query.format.parse::<Format>().map_err(|e| status_code(e.kind()))?;
+    let column_family = db.column_family(&query.family).ok_or(StatusCode::NOT_FOUND)?;
+
+    let (sender, receiver) = tokio::sync::mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        let writer = BufWriter::with_capacity(64 * 1024, ChannelWriter(sender.clone()));
+        if let Err(e) = export::export(&column_family, format, writer) {
+            /* The status is sent already, all that is left is to cut the body short */
+            let _ = sender.blocking_send(Err(e));
+        }
+    });
+
+    let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+    Ok(([(header::CONTENT_TYPE, format.content_type())], Body::from_stream(stream)).into_response())
+}
+
+/// Reads the request body as it arrives, on the blocking import thread.
+struct ChannelReader {
+    receiver: tokio::sync::mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+        }
+
+        let read = buf.len().min(self.chunk.len());
+        buf[..read].copy_from_slice(&self.chunk.split_to(read));
+        Ok(read)
+    }
+}
+
+/// Loads a streamed body into the family, see `export::import`. `value` is the number of keys imported.
+pub async fn import(
+    State(db): State<Arc<Db>>,
+    Query(query): Query<TransferQuery>,
+    body: Body,
+) -> Result<Json<Message>, StatusCode> {
+    let format = query.format.parse::<Format>().map_err(|e| status_code(e.kind()))?;
+    db.column_family(&query.family).ok_or(StatusCode::NOT_FOUND)?;
+
+    let (sender, receiver) = tokio::sync::mpsc::channel(16);
+    let import = tokio::task::spawn_blocking(move || {
+        export::import(&db, &query.family, format, ChannelReader { receiver, chunk: Bytes::new() })
+    });
+
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if sender.send(chunk).await.is_err() {
+            // The import stopped early, its error is reported below
+            break;
+        }
+    }
+    drop(sender);
+
+    let (value, error) = match import.await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        Ok(imported) => (Some(imported.to_string()), None),
+        Err(e) if e.kind() == ErrorKind::TimedOut => return Err(status_code(e.kind())),
+        Err(e) => (None, Some(e.to_string())),
+    };
+
+    Ok(Json(Message {
+        value,
+        error,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use sstable::options::Options;
+    use super::*;
+
+    fn open(name: &str) -> (Arc<Db>, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("sstable_handlers_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        (Arc::new(Db::open(&dir, Options::default()).unwrap()), dir)
+    }
+
+    fn key(key: &str) -> Path<HashMap<String, String>> {
+        Path(HashMap::from([("key".to_string(), key.to_string())]))
+    }
+
+    fn if_match(header: header::HeaderName, etag: &str) -> HeaderMap {
+        HeaderMap::from_iter([(header, HeaderValue::from_str(etag).unwrap())])
+    }
+
+    async fn get(db: &Arc<Db>, name: &str, headers: HeaderMap) -> Result<Response, KvError> {
+        get_kv(State(Arc::clone(db)), key(name), Query(KvQuery { encoding: Encoding::Raw }), headers).await
+    }
+
+    async fn put(db: &Arc<Db>, name: &str, value: &str, headers: HeaderMap) -> StatusCode {
+        put_tagged(db, name, value, headers).await.0
+    }
+
+    /// The status and the tag the write answered with.
+    async fn put_tagged(db: &Arc<Db>, name: &str, value: &str, headers: HeaderMap) -> (StatusCode, Option<String>) {
+        let query = Query(KvQuery { encoding: Encoding::Raw });
+        match put_kv(State(Arc::clone(db)), key(name), query, headers, Bytes::from(value.to_string())).await {
+            Ok(response) => (response.status(), response.headers().get(header::ETAG).map(|tag| tag.to_str().unwrap().to_string())),
+            Err((status, _)) => (status, None),
+        }
+    }
+
+    async fn current_tag(db: &Arc<Db>, name: &str) -> String {
+        get(db, name, HeaderMap::new()).await.unwrap().headers()[header::ETAG].to_str().unwrap().to_string()
+    }
+
+    async fn delete(db: &Arc<Db>, name: &str, headers: HeaderMap) -> StatusCode {
+        delete_kv(State(Arc::clone(db)), key(name), headers).await.unwrap_or_else(|(status, _)| status)
+    }
+
+    #[test]
+    fn etags_follow_the_write() {
+        let version = Version::Memtable(26);
+        assert_eq!(etag("abc", &version), "\"m1a-352441c2\"");
+        assert_eq!(etag("abc", &Version::Table("100_1".to_string(), 16)), "\"t100_1-10-352441c2\"");
+        assert_ne!(etag("abc", &version), etag("abc", &Version::Memtable(27)));
+        assert_ne!(etag("abc", &version), etag("abd", &version));
+
+        let tag = etag("abc", &version);
+        assert!(etag_matches(&HeaderValue::from_static("*"), Some(&tag)));
+        assert!(etag_matches(&HeaderValue::from_str(&format!("\"x\", W/{tag}")).unwrap(), Some(&tag)));
+        assert!(!etag_matches(&HeaderValue::from_static("\"x\""), Some(&tag)));
+        // No value matches nothing, not even `*`
+        assert!(!etag_matches(&HeaderValue::from_static("*"), None));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn preconditions_give_the_status_codes() {
+        let (db, dir) = open("preconditions");
+
+        assert_eq!(get(&db, "a", HeaderMap::new()).await.unwrap_err().0, StatusCode::NOT_FOUND);
+        assert_eq!(put(&db, "a", "1", if_match(header::IF_MATCH, "*")).await, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(put(&db, "a", "1", if_match(header::IF_NONE_MATCH, "*")).await, StatusCode::NO_CONTENT);
+        assert_eq!(put(&db, "a", "2", if_match(header::IF_NONE_MATCH, "*")).await, StatusCode::PRECONDITION_FAILED);
+
+        let response = get(&db, "a", HeaderMap::new()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let tag = response.headers()[header::ETAG].to_str().unwrap().to_string();
+        assert_eq!(get(&db, "a", if_match(header::IF_NONE_MATCH, &tag)).await.unwrap().status(), StatusCode::NOT_MODIFIED);
+
+        // A stale tag fails, the current one lets the write through
+        assert_eq!(put(&db, "a", "2", if_match(header::IF_MATCH, "\"m0-00000000\"")).await, StatusCode::PRECONDITION_FAILED);
+        let (status, written) = put_tagged(&db, "a", "2", if_match(header::IF_MATCH, &tag)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(written, Some(current_tag(&db, "a").await));
+        assert_eq!(db.get("a").unwrap().as_deref(), Some("2"));
+
+        assert_eq!(delete(&db, "a", if_match(header::IF_MATCH, &tag)).await, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(delete(&db, "a", if_match(header::IF_MATCH, &written.unwrap())).await, StatusCode::NO_CONTENT);
+        assert_eq!(delete(&db, "a", HeaderMap::new()).await, StatusCode::NOT_FOUND);
+
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn writing_a_value_back_gives_a_new_tag() {
+        let (db, dir) = open("value_written_back");
+
+        let (_, first) = put_tagged(&db, "a", "A", HeaderMap::new()).await;
+        let first = first.unwrap();
+        put(&db, "a", "B", HeaderMap::new()).await;
+        put(&db, "a", "A", HeaderMap::new()).await;
+        assert_ne!(current_tag(&db, "a").await, first);
+        assert_eq!(put(&db, "a", "C", if_match(header::IF_MATCH, &first)).await, StatusCode::PRECONDITION_FAILED);
+
+        // The same holds once the writes are in tables
+        db.flush(true).unwrap();
+        let flushed = current_tag(&db, "a").await;
+        put(&db, "a", "B", HeaderMap::new()).await;
+        db.flush(true).unwrap();
+        put(&db, "a", "A", HeaderMap::new()).await;
+        db.flush(true).unwrap();
+        assert_ne!(current_tag(&db, "a").await, flushed);
+        assert_eq!(put(&db, "a", "C", if_match(header::IF_MATCH, &flushed)).await, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(db.get("a").unwrap().as_deref(), Some("A"));
+
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deleting_a_key_in_a_table_removes_it() {
+        let (db, dir) = open("delete_table");
+        db.set("a", "1").unwrap();
+        db.flush(true).unwrap();
+
+        assert_eq!(delete(&db, "a", HeaderMap::new()).await, StatusCode::NO_CONTENT);
+        assert_eq!(db.get("a").unwrap(), None);
+        assert_eq!(delete(&db, "a", HeaderMap::new()).await, StatusCode::NOT_FOUND);
+
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,12 +1,28 @@
-use std::cmp::Ordering;
+use std::cmp::{self, Ordering};
+use std::collections::HashSet;
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::thread::sleep;
-use std::time::{Duration, SystemTime};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
 use crate::avl::{AVLNode, AVLTree};
+use crate::column_family::ColumnFamily;
+use crate::compaction_filter::Decision;
+use crate::compaction_strategy::TableInfo;
+use crate::db::Db;
+use crate::options::Options;
+use crate::rate_limiter::IoPriority;
 use crate::sst;
+use crate::sst::SSTValue;
+use crate::vlog::{ValueLog, ValuePointer};
+
+/// Keys from the first bound up to, but excluding, the second one, `None` is unbounded.
+pub type KeyRange = (Option<String>, Option<String>);
+
+/// Name of a table and the offset of a record in its `.sst`.
+pub type RecordLocation = (String, u64);
 
 pub struct IDX {
     path: PathBuf,
@@ -29,18 +45,47 @@ pub struct IDXValue {
     pub value: String,
 }
 
+/// A record of a table together with where it is stored, see `IDX::records`.
+#[derive(Debug)]
+pub struct IDXRecord {
+    /// Offset of the index entry in the `.idx` file.
+    pub idx_offset: u64,
+    pub key: String,
+    /// Offset of the record in the `.sst` file.
+    pub sst_offset: u64,
+    pub value: SSTValue,
+}
+
 impl IDX {
     pub const KEY_LEN: usize = 1;
 
-    fn get_timestamp_from_filename(path: &Path) -> u64 {
-        path.file_stem()
+    pub(crate) fn get_timestamp_from_filename(path: &Path) -> (u64, u32) {
+        /* Compacted files are named `<timestamp>_<generation>` */
+        let file_stem = path.file_stem()
             .and_then(|stem| stem.to_str())
-            .and_then(|name| name.parse::<u64>().ok())
-            .unwrap_or(0)
+            .unwrap_or_default();
+        let mut parts = file_stem.split('_');
+
+        let timestamp = parts.next().and_then(|name| name.parse::<u64>().ok()).unwrap_or(0);
+        let generation = parts.next().and_then(|name| name.parse::<u32>().ok()).unwrap_or(0);
+        (timestamp, generation)
     }
 
-    pub fn search_key_in_all_files(key: &str) -> Option<IDXValue> {
-        let mut idx_files = std::fs::read_dir(".")
+    /// Timestamp of a new table in `dir`: the current time, unless a table is from then or later.
+    ///
+    /// Flushes can come faster than once a second, tables are ingested ahead of the clock and
+    /// compacted ones keep the timestamp of their oldest input, so a new table always takes
+    /// one past the newest, or it would sort below older data.
+    pub(crate) fn next_timestamp(dir: &Path) -> u64 {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        Self::idx_files(dir).iter()
+            .map(|path| Self::get_timestamp_from_filename(path).0 + 1)
+            .fold(now, cmp::max)
+    }
+
+    pub(crate) fn idx_files(dir: &Path) -> Vec<PathBuf> {
+        /* Newest first */
+        let mut idx_files = std::fs::read_dir(dir)
             .unwrap()
             .filter_map(|res| res.ok())
             .map(|dir_entry| dir_entry.path())
@@ -52,71 +97,164 @@ impl IDX {
                 }
             })
             .collect::<Vec<_>>();
-        
+
         idx_files.sort_by(|a, b| {
             let a_timestamp = Self::get_timestamp_from_filename(a);
             let b_timestamp = Self::get_timestamp_from_filename(b);
             b_timestamp.cmp(&a_timestamp)
         });
 
+        idx_files
+    }
 
-        let mut value : Option<IDXValue> = None;
+    /// Looks the key up newest file first. Merge records met on the way are stacked
+    /// under `operands` and folded into the first value found below them.
+    pub fn search_key_in_all_files(dir: &Path, key: &str, operands: Vec<String>, options: &Arc<Options>) -> Result<Option<IDXValue>, Error> {
+        Ok(Self::search_key_with_location(dir, key, operands, options)?.0)
+    }
 
-        for file in idx_files {
-            let idx = Self::from(file).unwrap();
-            value = match idx.get_value(key) {
-                Ok(value) => {
-                    Some(value)
-                },
+    /// Same as `search_key_in_all_files`, together with the name of the newest table holding
+    /// a record of the key and the offset of that record.
+    pub fn search_key_with_location(
+        dir: &Path,
+        key: &str,
+        mut operands: Vec<String>,
+        options: &Arc<Options>,
+    ) -> Result<(Option<IDXValue>, Option<RecordLocation>), Error> {
+        let mut location = None;
+        for file in Self::idx_files(dir) {
+            let idx = Self::from(file, Arc::clone(options))?;
+            let Ok(Some(offset)) = Self::check_key(key).and_then(|_| idx.find_offset(key)) else { continue };
+            let value = match idx.sst.get_entry(key, offset) {
+                Ok(value) => value,
                 Err(_) => continue,
             };
+            location.get_or_insert_with(|| (idx.path.file_stem().unwrap().to_string_lossy().to_string(), offset));
+
+            let value = match value {
+                SSTValue::Merge(older_operands) => {
+                    operands.splice(0..0, older_operands);
+                    continue;
+                }
+                SSTValue::Tombstone => None,
+                value => Some(idx.sst.resolve(key, value)?),
+            };
+
+            let value = Self::fold_operands(key, value, &operands, options)?;
+            return Ok((value.map(|value| IDXValue { key: key.to_string(), value }), location));
+        }
+
+        let value = Self::fold_operands(key, None, &operands, options)?;
+        Ok((value.map(|value| IDXValue { key: key.to_string(), value }), location))
+    }
+
+    /// Applies the operands one by one. Operands are checked when they are written, but an
+    /// operand the operator refuses later, like an overflowing `U64AddOperator` sum, is dropped,
+    /// so reads and compactions agree on the value and a compaction doesn't fail on the same
+    /// key again and again.
+    pub fn fold_operands(key: &str, mut value: Option<String>, operands: &[String], options: &Options) -> Result<Option<String>, Error> {
+        if operands.is_empty() {
+            return Ok(value);
+        }
+
+        let operator = options.merge_operator.as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::Unsupported, "No merge operator configured"))?;
+        for operand in operands {
+            match operator.full_merge(key, value.as_deref(), std::slice::from_ref(operand)) {
+                Ok(merged) => value = Some(merged),
+                Err(e) => tracing::warn!("Merge operand of {} was dropped > {}", key, e),
+            }
+        }
+        Ok(Some(value.unwrap_or_default()))
+    }
+
+    /// Number of tables written by a flush and not compacted yet.
+    pub(crate) fn level0_files(dir: &Path) -> usize {
+        Self::idx_files(dir).iter().filter(|path| Self::get_timestamp_from_filename(path).1 == 0).count()
+    }
+
+    /// Same as `search_key_in_all_files`, but returns the newest record as it is stored.
+    pub fn search_entry_in_all_files(dir: &Path, key: &str, options: &Arc<Options>) -> Option<SSTValue> {
+        for file in Self::idx_files(dir) {
+            let idx = Self::from(file, Arc::clone(options)).unwrap();
+            if let Ok(value) = idx.get_entry(key) {
+                return Some(value);
+            }
         }
 
-        value
+        None
     }
 
-    pub fn new(mut file_name: Option<String>) -> IDX {
+    pub fn new(dir: &Path, mut file_name: Option<String>, options: Arc<Options>) -> IDX {
         if file_name.is_none() {
-            file_name = Some(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs().to_string());
-        } 
+            file_name = Some(Self::next_timestamp(dir).to_string());
+        }
         
         let sst_path = format!("{}.sst", file_name.clone().unwrap());
         let idx_path = format!("{}.idx", file_name.unwrap());
         
-        let sst = sst::SST::new(Path::new(&sst_path).to_path_buf());
-        IDX{path: Path::new(&idx_path).to_path_buf(), sst}
+        let sst = sst::SST::new(dir.join(sst_path), options);
+        IDX{path: dir.join(idx_path), sst}
     }
     
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn sst(&self) -> &sst::SST {
+        &self.sst
+    }
+
     pub fn clear(&mut self) -> Result<(), Error> {
         fs::remove_file(&self.path)?;
+        sst::forget_map(&self.sst.path);
         fs::remove_file(&self.sst.path)
     }
 
-    pub fn from(idx_file: PathBuf) -> Result<IDX, Error> {
+    pub fn from(idx_file: PathBuf, options: Arc<Options>) -> Result<IDX, Error> {
         let file_name = idx_file.file_stem();
         if file_name.is_none() {
             return Err(Error::other("No Filename"));
         }
 
-        let sst_file = Path::new(&format!("{}.sst", file_name.unwrap().to_str().unwrap())).to_path_buf();
-        let sst = sst::SST::new(sst_file);
+        let sst_file = idx_file.with_extension("sst");
+        let sst = sst::SST::new(sst_file, options);
         Ok(IDX{path: idx_file, sst})
     }
     
-    pub fn fill_from_avl(&self, tree: &AVLTree) -> Result<(), Error> {
-        self.insert_avl_node(tree.root.as_ref().unwrap())?;
+    pub fn fill_from_avl(&self, tree: &AVLTree, value_log: &ValueLog) -> Result<(), Error> {
+        self.insert_avl_node(tree.root.as_ref().unwrap(), Some(value_log))?;
+        Ok(())
+    }
+
+    /// Same as `fill_from_avl` with every value kept inline, for tables outside the family.
+    pub(crate) fn fill_inline_from_avl(&self, tree: &AVLTree) -> Result<(), Error> {
+        self.insert_avl_node(tree.root.as_ref().unwrap(), None)?;
         Ok(())
     }
 
-    fn insert_avl_node(&self, node: &AVLNode) -> Result<(), Error> {
+    fn insert_avl_node(&self, node: &AVLNode, value_log: Option<&ValueLog>) -> Result<(), Error> {
         if let Some(left) = &node.left {
-            self.insert_avl_node(left)?;
+            self.insert_avl_node(left, value_log)?;
         }
 
-        self.set_key(node.key.as_str(), node.value.as_str())?;
-        
+        let separated = match (node.operands.is_empty() && !node.deleted, value_log) {
+            (true, Some(value_log)) => value_log.separate(node.key.as_str(), node.value.as_str())?,
+            _ => None,
+        };
+        let entry = match node.operands.is_empty() {
+            true if node.deleted => SSTValue::Tombstone,
+            true => match separated {
+                Some(pointer) => SSTValue::Pointer(pointer),
+                None => SSTValue::Inline(node.value.clone()),
+            },
+            false => SSTValue::Merge(node.operands.clone()),
+        };
+        self.throttle(node.key.as_str(), &entry, IoPriority::High);
+        self.set_entry(node.key.as_str(), &entry)?;
+
         if let Some(right) = &node.right {
-            self.insert_avl_node(right)?;
+            self.insert_avl_node(right, value_log)?;
         }
 
         Ok(())
@@ -136,12 +274,40 @@ impl IDX {
         Ok(String::from_utf8_lossy(&key_buf).to_string())
     }
 
-    pub fn iter(&self) -> Result<IDXIter, Error> {
+    fn throttle(&self, key: &str, value: &SSTValue, priority: IoPriority) {
+        /* Charges the index and table record about to be written to the rate limiter */
+        let Some(rate_limiter) = &self.sst.options().rate_limiter else { return };
+
+        let value_len = match value {
+            SSTValue::Inline(value) => value.len(),
+            SSTValue::Pointer(_) => ValuePointer::ENCODED_LEN,
+            SSTValue::Merge(operands) => operands.iter().map(|operand| 4 + operand.len()).sum(),
+            SSTValue::Tombstone => 0,
+        };
+        rate_limiter.request(2 * (IDX::KEY_LEN + key.len()) + 8 + 4 + value_len, priority);
+    }
+
+    pub fn iter(&self) -> Result<IDXIter<'_>, Error> {
         let mut file = self.get_file(false)?;
         let position = file.seek(SeekFrom::Start(0))?;
         Ok(IDXIter { idx: self, position })
     }
 
+    /// Iterates over the records as they are stored, without following value log pointers.
+    pub fn entries(&self) -> Result<IDXEntryIter<'_>, Error> {
+        self.entries_between(&(None, None))
+    }
+
+    /// Iterates over the index entries with their offsets, for inspecting a table.
+    pub fn records(&self) -> Result<IDXRecordIter<'_>, Error> {
+        Ok(IDXRecordIter { iter: self.iter()? })
+    }
+
+    /// Same as `entries`, limited to keys from the start of the range up to, but excluding, its end.
+    pub fn entries_between(&self, range: &KeyRange) -> Result<IDXEntryIter<'_>, Error> {
+        Ok(IDXEntryIter { iter: self.iter()?, range: range.clone() })
+    }
+
     fn find_mid(&self, file: &mut File, mut mid: u64) -> Result<u64, Error> {
         /* Fine start of the struct for bin search */
         while mid > 0 {
@@ -191,7 +357,7 @@ impl IDX {
                 Err(_) => return Ok(None)
             };
 
-            match key.cmp(&found_string) {
+            match self.sst.options().comparator.compare(key, &found_string) {
                 Ordering::Less => {
                     right = mid;
                 },
@@ -208,10 +374,16 @@ impl IDX {
         Ok(None)
     }
 
-    pub fn get_value(&self, key: &str) -> Result<IDXValue, Error> {
+    /// Tables only hold alphanumeric keys shorter than 11 bytes, for every comparator.
+    pub fn check_key(key: &str) -> Result<(), Error> {
         if key.len() >= 11 || !key.chars().all(|x| x.is_alphanumeric()) {
-            return Err(Error::other("Key must be alphanumeric and less than 11 chars"))
+            return Err(Error::new(ErrorKind::InvalidInput, "Key must be alphanumeric and less than 11 chars"));
         }
+        Ok(())
+    }
+
+    pub fn get_value(&self, key: &str) -> Result<IDXValue, Error> {
+        Self::check_key(key)?;
         
         let offset = self.find_offset(key)?;
 
@@ -221,12 +393,23 @@ impl IDX {
         }
     }
 
-    pub fn set_key(&self, key: &str, value: &str) -> Result<IDXKey, Error> {
-        if key.len() >= 11 || !key.chars().all(|x| x.is_alphanumeric()) {
-            return Err(Error::other("Key must be alphanumeric and less than 11 chars"))
+    pub fn get_entry(&self, key: &str) -> Result<SSTValue, Error> {
+        Self::check_key(key)?;
+
+        match self.find_offset(key)? {
+            Some(offset) => self.sst.get_entry(key, offset),
+            None => Err(Error::new(ErrorKind::NotFound, "Key not found")),
         }
+    }
 
-        let offset = self.sst.set(key, value)?;
+    pub fn set_key(&self, key: &str, value: &str) -> Result<IDXKey, Error> {
+        self.set_entry(key, &SSTValue::Inline(value.to_string()))
+    }
+
+    pub fn set_entry(&self, key: &str, value: &SSTValue) -> Result<IDXKey, Error> {
+        Self::check_key(key)?;
+
+        let offset = self.sst.set_entry(key, value)?;
 
         let mut file = self.get_file(true)?;
         file.seek(SeekFrom::End(0))?;
@@ -239,79 +422,390 @@ impl IDX {
 
     }
     
-    pub fn compaction() {
+    fn merge_values(inputs: &[IDX], key: &str, older_value: SSTValue, newer_value: SSTValue, options: &Options) -> Result<SSTValue, Error> {
+        Ok(match (older_value, newer_value) {
+            (SSTValue::Merge(mut operands), SSTValue::Merge(newer_operands)) => {
+                operands.extend(newer_operands);
+                SSTValue::Merge(operands)
+            }
+            (SSTValue::Tombstone, SSTValue::Merge(operands)) => {
+                SSTValue::Inline(Self::fold_operands(key, None, &operands, options)?.unwrap())
+            }
+            (older_value, SSTValue::Merge(operands)) => {
+                // Every input lives in the same directory, any of them resolves a pointer
+                let older_value = inputs[0].sst.resolve(key, older_value)?;
+                SSTValue::Inline(Self::fold_operands(key, Some(older_value), &operands, options)?.unwrap())
+            }
+            (_, newer_value) => newer_value,
+        })
+    }
+
+    /// `starts` holds the position of the first key of the range in each input. Tombstones
+    /// are dropped when the inputs hold the oldest table, there is nothing left for them to hide.
+    fn merge_files(
+        inputs: &[IDX],
+        level: u32,
+        bottommost: bool,
+        options: &Options,
+        range: &KeyRange,
+        starts: &[u64],
+    ) -> Result<Vec<(String, SSTValue)>, Error> {
+        /* Inputs are oldest first. Records are copied as stored, so separated values are never rewritten */
+        let mut entries = Vec::new();
+        let mut iters = inputs.iter().zip(starts)
+            .map(|(idx, start)| IDXEntryIter { iter: IDXIter { idx, position: *start }, range: range.clone() })
+            .collect::<Vec<_>>();
+        let mut heads = iters.iter_mut().map(|iter| iter.next().transpose()).collect::<Result<Vec<_>, Error>>()?;
+
         loop {
-            sleep(Duration::from_secs(1));
-            println!("Checking IDX files to compaction");
-            
-            let mut idx_files = std::fs::read_dir(".")
-                .unwrap()
-                .filter_map(|res| res.ok())
-                .map(|dir_entry| dir_entry.path())
-                .filter_map(|path| {
-                    if path.extension().is_some_and(|ext| ext == "idx") {
-                        Some(path)
-                    } else {
-                        None
-                    }
-                })
-                .filter_map(|path| {
-                    if path.metadata().is_ok_and(|m| m.len() < 5 * 1024 * 1024) {  // 5 MB
-                        Some(path)
-                    } else {
-                        None
+            let mut smallest: Option<&String> = None;
+            for (key, _) in heads.iter().flatten() {
+                if smallest.is_none_or(|smallest| options.comparator.compare(key, smallest) == Ordering::Less) {
+                    smallest = Some(key);
+                }
+            }
+            let Some(key) = smallest.cloned() else { break };
+
+            let mut value = None;
+            for (iter, head) in iters.iter_mut().zip(heads.iter_mut()) {
+                if head.as_ref().is_some_and(|(next_key, _)| options.comparator.compare(next_key, &key) == Ordering::Equal) {
+                    let next = iter.next().transpose()?;
+                    let (_, newer_value) = std::mem::replace(head, next).unwrap();
+                    value = Some(match value {
+                        Some(older_value) => Self::merge_values(inputs, &key, older_value, newer_value, options)?,
+                        None => newer_value,
+                    });
+                }
+            }
+            let value = value.unwrap();
+            if bottommost && value == SSTValue::Tombstone {
+                continue;
+            }
+
+            let Some(filter) = &options.compaction_filter else {
+                entries.push((key, value));
+                continue;
+            };
+
+            if let SSTValue::Merge(_) | SSTValue::Tombstone = value {
+                entries.push((key, value));
+                continue;
+            }
+
+            // A pointer into a collected segment belongs to a shadowed value, keep it as is
+            let Ok(resolved) = inputs[0].sst.resolve(&key, value.clone()) else {
+                entries.push((key, value));
+                continue;
+            };
+
+            match filter.filter(level, &key, &resolved) {
+                Decision::Keep => entries.push((key, value)),
+                Decision::Remove => {},
+                Decision::ChangeValue(new_value) => entries.push((key, SSTValue::Inline(new_value))),
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Every key of the tables in `dir` once, in key order, with the value a read would return.
+    pub fn merged(dir: &Path, options: &Arc<Options>) -> Result<MergedEntries, Error> {
+        let mut tables = Self::idx_files(dir).into_iter()
+            .map(|path| Self::from(path, Arc::clone(options)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        tables.reverse();
+
+        let mut merged = MergedEntries {
+            positions: vec![0; tables.len()],
+            heads: vec![None; tables.len()],
+            tables,
+            options: Arc::clone(options),
+            failed: false,
+        };
+        for i in 0..merged.tables.len() {
+            merged.advance(i)?;
+        }
+        Ok(merged)
+    }
+
+    /// The tables in `dir` as compaction strategies see them, oldest first.
+    pub(crate) fn tables(dir: &Path, busy: &HashSet<PathBuf>) -> Vec<TableInfo> {
+        let mut idx_files = Self::idx_files(dir);
+        idx_files.reverse();
+
+        idx_files.into_iter().map(|path| {
+            let (timestamp, generation) = Self::get_timestamp_from_filename(&path);
+            TableInfo {
+                size: fs::metadata(path.with_extension("sst")).map_or(0, |metadata| metadata.len()),
+                busy: busy.contains(&path),
+                path,
+                timestamp,
+                generation,
+            }
+        }).collect()
+    }
+
+    /// Asks the compaction strategy of the column family what to merge, as lists of table paths.
+    pub(crate) fn pick_compactions(dir: &Path, options: &Options, busy: &HashSet<PathBuf>) -> Vec<Vec<PathBuf>> {
+        let tables = Self::tables(dir, busy);
+        options.compaction_strategy.pick(&tables, options)
+            .into_iter()
+            .map(|group| group.into_iter().map(|i| tables[i].path.clone()).collect())
+            .collect()
+    }
+
+    /// Schedules compactions of every column family on a pool of `Options::max_background_compactions` workers.
+    pub fn compaction(db: Arc<Db>) {
+        let (sender, receiver) = mpsc::channel::<(Arc<ColumnFamily>, Vec<PathBuf>)>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let mut workers = Vec::new();
+        for _ in 0..db.options().max_background_compactions.max(1) {
+            let receiver = Arc::clone(&receiver);
+            workers.push(thread::spawn(move || loop {
+                let job = receiver.lock().unwrap().recv();
+                let Ok((column_family, inputs)) = job else { return };
+
+                if let Err(e) = Self::compact_tables(column_family.path(), &inputs, column_family.options()) {
+                    tracing::error!("Failed to compact files: {}", e);
+                }
+                column_family.refresh_level0_files();
+                column_family.release_tables(&inputs);
+            }));
+        }
+
+        while !db.wait_for_shutdown(db.options().compaction_interval) {
+            for column_family in db.column_families() {
+                tracing::debug!("Checking IDX files of {} to compaction", column_family.name());
+
+                let mut busy = column_family.compacting();
+                for inputs in Self::pick_compactions(column_family.path(), column_family.options(), &busy) {
+                    busy.extend(inputs.iter().cloned());
+                    sender.send((Arc::clone(&column_family), inputs)).unwrap();
+                }
+            }
+        }
+
+        /* Workers finish the queued jobs, then see the channel closed */
+        drop(sender);
+        for worker in workers {
+            if worker.join().is_err() {
+                tracing::error!("A compaction worker panicked");
+            }
+        }
+        tracing::info!("Compaction threads stopped");
+    }
+
+    /// Compacts the tables in `dir` on the calling thread until there is nothing left to pick.
+    pub fn compact(dir: &Path, options: &Arc<Options>) {
+        loop {
+            let jobs = Self::pick_compactions(dir, options, &HashSet::new());
+            if jobs.is_empty() {
+                return;
+            }
+
+            for inputs in jobs {
+                if let Err(e) = Self::compact_tables(dir, &inputs, options) {
+                    tracing::error!("Failed to compact files: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Splits the keys of the tables into up to `parts` ranges of about the same number of keys,
+    /// each with the position its first key has in every table, so no part reads the keys before it.
+    fn subcompaction_ranges(inputs: &[IDX], parts: usize, options: &Options) -> Result<Vec<(KeyRange, Vec<u64>)>, Error> {
+        if parts <= 1 {
+            return Ok(vec![((None, None), vec![0; inputs.len()])]);
+        }
+
+        let mut positions = Vec::new();
+        for idx in inputs {
+            let mut iter = idx.iter()?;
+            let mut table = Vec::new();
+            loop {
+                let position = iter.position;
+                let Some(idx_key) = iter.next_key() else { break };
+                table.push((idx_key.key, position));
+            }
+            positions.push((table, iter.position));
+        }
+        let mut keys = positions.iter().flat_map(|(table, _)| table).map(|(key, _)| key.as_str()).collect::<Vec<_>>();
+        keys.sort_by(|a, b| options.comparator.compare(a, b));
+        keys.dedup();
+
+        let mut bounds = (1..parts).map(|part| keys.get(part * keys.len() / parts).map(|key| key.to_string())).collect::<Vec<_>>();
+        bounds.dedup();
+
+        /* Tables are sorted, the first key of a part is found by bisecting each of them */
+        let starts = |from: &Option<String>| positions.iter().map(|(table, end)| match from {
+            None => 0,
+            Some(from) => {
+                let index = table.partition_point(|(key, _)| options.comparator.compare(key, from) == Ordering::Less);
+                table.get(index).map_or(*end, |(_, position)| *position)
+            }
+        }).collect::<Vec<_>>();
+
+        let mut ranges = Vec::new();
+        let mut from = None;
+        for bound in bounds.into_iter().flatten() {
+            ranges.push(((from.clone(), Some(bound.clone())), starts(&from)));
+            from = Some(bound);
+        }
+        let last_starts = starts(&from);
+        ranges.push(((from, None), last_starts));
+        Ok(ranges)
+    }
+
+    /// Merges neighbouring tables, oldest first, into `<timestamp of the oldest>_<highest generation + 1>`.
+    ///
+    /// Large inputs are split into `Options::max_subcompactions` key ranges merged in parallel.
+    /// Their outputs are joined in a scratch directory, then the `.sst` and the `.idx` are renamed
+    /// into place and the inputs removed. Publishing is not atomic: in between, reads find the
+    /// output, which sorts above the oldest input and holds the newest values of every input.
+    /// A crash leaves both, and the next compaction of them drops the duplicates.
+    ///
+    /// A single input is rewritten on its own, which drops what the compaction filter removes.
+    /// Returns the published `.idx` path, `None` when nothing was left.
+    pub fn compact_tables(dir: &Path, inputs: &[PathBuf], options: &Arc<Options>) -> Result<Option<PathBuf>, Error> {
+        if inputs.is_empty() {
+            return Ok(None);
+        }
+        tracing::info!("Start compaction of {} files", inputs.len());
+
+        let mut input_idxs = inputs.iter()
+            .map(|path| IDX::from(path.clone(), Arc::clone(options)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut size = 0.0;
+        for idx in &input_idxs {
+            size += idx.sst.get_size()?;
+        }
+        tracing::info!("Size of files to compact is {size} MB");
+
+        let (timestamp, _) = Self::get_timestamp_from_filename(&inputs[0]);
+        let generation = inputs.iter().map(|path| Self::get_timestamp_from_filename(path).1).max().unwrap() + 1;
+        let new_idx_file_name = format!("{timestamp}_{generation}");
+        let bottommost = Self::idx_files(dir).last().is_some_and(|oldest| inputs.contains(oldest));
+
+        let scratch = dir.join(format!(".compaction_{new_idx_file_name}"));
+        let _ = fs::remove_dir_all(&scratch);
+        fs::create_dir_all(&scratch)?;
+
+        let ranges = Self::subcompaction_ranges(&input_idxs, options.max_subcompactions, options)?;
+        let parts = thread::scope(|scope| {
+            let handles = ranges.iter().enumerate().map(|(part, (range, starts))| {
+                let (input_idxs, scratch) = (&input_idxs, &scratch);
+                scope.spawn(move || -> Result<IDX, Error> {
+                    let part_idx = IDX::new(scratch, Some(format!("part_{part}")), Arc::clone(options));
+                    for (key, value) in Self::merge_files(input_idxs, generation, bottommost, options, range, starts)? {
+                        part_idx.throttle(&key, &value, IoPriority::Low);
+                        part_idx.set_entry(&key, &value)?;
                     }
+                    Ok(part_idx)
                 })
-                .collect::<Vec<_>>();
-
-            idx_files.sort_by(|a, b| {
-                let a_timestamp = Self::get_timestamp_from_filename(a);
-                let b_timestamp = Self::get_timestamp_from_filename(b);
-                b_timestamp.cmp(&a_timestamp)
-            });
-            
-            while idx_files.len() > 2 {
-                println!("Start compaction");
-                dbg!(&idx_files);
-                
-                let a_idx_path = idx_files.pop().unwrap();
-                let b_idx_path = idx_files.pop().unwrap();
-                
-                let mut tree = AVLTree::new();
-                let mut a_idx = IDX::from(a_idx_path.clone()).unwrap();
-                let mut b_idx = IDX::from(b_idx_path).unwrap();
-                
-                println!("Size of files to compact is {} MB", a_idx.sst.get_size().unwrap() + b_idx.sst.get_size().unwrap());
-            
-                tree.feel_from_idx(&a_idx);
-                tree.feel_from_idx(&b_idx);
-
-
-                let file_stem = a_idx_path.file_stem().unwrap().to_str().unwrap().to_string();
-                let new_idx_file_name = file_stem.split("_");
-                let new_idx_file_name_str = new_idx_file_name.clone().collect::<Vec<&str>>();
-
-                let new_idx_file_name = if new_idx_file_name_str.len() == 1 {
-                    Some(format!("{}_{}", new_idx_file_name_str[0], 1))
-                } else {
-                    let base_name = new_idx_file_name_str[0];
-                    let index = new_idx_file_name_str[1].parse::<u32>().unwrap_or(0) + 1;
-                    Some(format!("{}_{}", base_name, index))
-                };
+            }).collect::<Vec<_>>();
 
-                let new_idx = IDX::new(new_idx_file_name);
-                new_idx.fill_from_avl(&tree).unwrap();
+            handles.into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|_| Err(Error::other("Subcompaction panicked"))))
+                .collect::<Result<Vec<_>, Error>>()
+        });
 
-                a_idx.clear().unwrap();
-                println!("First idx file was removed > {}", {a_idx.path.to_string_lossy()});
-                b_idx.clear().unwrap();
-                println!("Second idx file was removed > {}", {b_idx.path.to_string_lossy()});
-                
-                println!("Compaction complete, new file > {}, with size > {}", new_idx.path.to_string_lossy(),  new_idx.sst.get_size().unwrap());
+        let publish = parts.and_then(|parts| {
+            let new_idx = IDX::new(&scratch, Some(new_idx_file_name.clone()), Arc::clone(options));
+            for part in &parts {
+                new_idx.append(part)?;
+            }
 
-                dbg!(&idx_files);
+            // Everything was filtered out, there is nothing to publish
+            if !new_idx.path.exists() {
+                return Ok(None);
             }
+
+            let published = IDX::new(dir, Some(new_idx_file_name.clone()), Arc::clone(options));
+            fs::rename(&new_idx.sst.path, &published.sst.path)?;
+            fs::rename(&new_idx.path, &published.path)?;
+            Ok(Some(published))
+        });
+        let _ = fs::remove_dir_all(&scratch);
+        let new_idx = publish?;
+
+        for idx in input_idxs.iter_mut() {
+            idx.clear()?;
+            tracing::info!("Idx file was removed > {}", idx.path.to_string_lossy());
         }
+
+        match &new_idx {
+            Some(new_idx) => tracing::info!("Compaction complete, new file > {}, with size > {}", new_idx.path.to_string_lossy(), new_idx.sst.get_size().unwrap_or(0.0)),
+            None => tracing::info!("Compaction complete, nothing was left"),
+        }
+        Ok(new_idx.map(|new_idx| new_idx.path))
+    }
+
+    /// The first and the last key of the table, `None` when it is empty.
+    pub fn key_range(&self) -> Result<Option<(String, String)>, Error> {
+        let mut iter = self.iter()?;
+        let Some(first) = iter.next_key() else { return Ok(None) };
+
+        let mut last = first.key.clone();
+        while let Some(idx_key) = iter.next_key() {
+            last = idx_key.key;
+        }
+        Ok(Some((first.key, last)))
+    }
+
+    /// Reads every record and checks that keys are in comparator order and pointers lead to
+    /// their values. Returns the number of records.
+    pub fn verify(&self) -> Result<usize, Error> {
+        let comparator = &self.sst.options().comparator;
+        let mut iter = self.iter()?;
+        let mut last_key: Option<String> = None;
+        let mut records = 0;
+
+        while let Some(idx_key) = iter.next_key() {
+            if let Some(last_key) = &last_key {
+                if comparator.compare(last_key, &idx_key.key) != Ordering::Less {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("Key {:?} comes after {:?}", idx_key.key, last_key)));
+                }
+            }
+
+            let context = |e: Error| Error::new(e.kind(), format!("Key {:?} at offset {}: {}", idx_key.key, idx_key.offset, e));
+            let value = self.sst.get_entry(&idx_key.key, idx_key.offset).map_err(context)?;
+            if let SSTValue::Pointer(_) = value {
+                self.sst.resolve(&idx_key.key, value).map_err(context)?;
+            }
+
+            last_key = Some(idx_key.key);
+            records += 1;
+        }
+
+        // A record cut short ends the iteration like the end of the file does
+        let len = fs::metadata(&self.path)?.len();
+        if iter.position != len {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} bytes after the last record", len - iter.position)));
+        }
+        Ok(records)
+    }
+
+    /// Appends the records of `part`, whose keys all sort after the keys of this table.
+    fn append(&self, part: &IDX) -> Result<(), Error> {
+        if !part.path.exists() {
+            return Ok(());
+        }
+
+        let base = fs::metadata(&self.sst.path).map_or(0, |metadata| metadata.len());
+        let mut sst_file = OpenOptions::new().create(true).append(true).open(&self.sst.path)?;
+        io::copy(&mut File::open(&part.sst.path)?, &mut sst_file)?;
+
+        let mut idx_file = BufWriter::new(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        let mut iter = part.iter()?;
+        while let Some(idx_key) = iter.next_key() {
+            idx_file.write_all(&idx_key.key_len.to_le_bytes())?;
+            idx_file.write_all(idx_key.key.as_bytes())?;
+            idx_file.write_all(&(idx_key.offset + base).to_le_bytes())?;
+        }
+        idx_file.flush()
     }
 }
 
@@ -321,11 +815,8 @@ pub struct IDXIter<'a> {
 }
 
 
-impl<'a> Iterator for IDXIter<'a> {
-    
-    type Item = IDXValue;
-    
-    fn next(&mut self) -> Option<Self::Item> {
+impl<'a> IDXIter<'a> {
+    fn next_key(&mut self) -> Option<IDXKey> {
         let mut key_len_buf = [0u8; 1];
         let mut file = self.idx.get_file(false).unwrap();
         if file.seek(SeekFrom::Start(self.position)).is_err() {
@@ -353,9 +844,137 @@ impl<'a> Iterator for IDXIter<'a> {
 
         self.position += 1 + key_len as u64 + 8;
 
-        Some(IDXValue {
-            key: String::from_utf8_lossy(&key_buf).to_string(),
-            value: self.idx.sst.get(key.as_str(), offset).unwrap()
-        })
+        Some(IDXKey { key_len: key_len as u8, key, offset })
     }
-}
\ No newline at end of file
+}
+
+impl<'a> Iterator for IDXIter<'a> {
+    
+    type Item = Result<IDXValue, Error>;
+    
+    /// Fails on merge records, which only have a value once folded into an older one.
+    fn next(&mut self) -> Option<Self::Item> {
+        let idx_key = self.next_key()?;
+
+        Some(self.idx.sst.get(idx_key.key.as_str(), idx_key.offset).map(|value| IDXValue { key: idx_key.key, value }))
+    }
+}
+
+pub struct IDXEntryIter<'a> {
+    iter: IDXIter<'a>,
+    range: KeyRange,
+}
+
+impl<'a> Iterator for IDXEntryIter<'a> {
+
+    type Item = Result<(String, SSTValue), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let comparator = &self.iter.idx.sst.options().comparator;
+        loop {
+            let idx_key = self.iter.next_key()?;
+            /* Values before the range are skipped without being read */
+            if self.range.0.as_ref().is_some_and(|from| comparator.compare(&idx_key.key, from) == Ordering::Less) {
+                continue;
+            }
+            if self.range.1.as_ref().is_some_and(|to| comparator.compare(&idx_key.key, to) != Ordering::Less) {
+                return None;
+            }
+
+            let value = self.iter.idx.sst.get_entry(idx_key.key.as_str(), idx_key.offset)
+                .map_err(|e| Error::new(e.kind(), format!("Key {:?} at offset {} of {}: {}", idx_key.key, idx_key.offset, self.iter.idx.sst.path.display(), e)));
+            return Some(value.map(|value| (idx_key.key, value)));
+        }
+    }
+}
+pub struct IDXRecordIter<'a> {
+    iter: IDXIter<'a>,
+}
+
+impl IDXRecordIter<'_> {
+    /// Bytes of the `.idx` file read so far, short of its length when the last entry is cut off.
+    pub fn position(&self) -> u64 {
+        self.iter.position
+    }
+}
+
+impl<'a> Iterator for IDXRecordIter<'a> {
+
+    type Item = Result<IDXRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let idx_offset = self.iter.position;
+        let idx_key = self.iter.next_key()?;
+        let record = self.iter.idx.sst.get_entry(&idx_key.key, idx_key.offset)
+            .map(|value| IDXRecord { idx_offset, key: idx_key.key.clone(), sst_offset: idx_key.offset, value })
+            .map_err(|e| Error::new(e.kind(), format!("Key {:?} at offset {}: {}", idx_key.key, idx_key.offset, e)));
+        Some(record)
+    }
+}
+
+/// See `IDX::merged`. The tables must stay in place meanwhile, a `Snapshot` keeps them for a live column family.
+pub struct MergedEntries {
+    /// Oldest first, like the inputs of a compaction.
+    tables: Vec<IDX>,
+    positions: Vec<u64>,
+    heads: Vec<Option<(String, SSTValue)>>,
+    options: Arc<Options>,
+    failed: bool,
+}
+
+impl MergedEntries {
+    fn advance(&mut self, i: usize) -> Result<(), Error> {
+        let mut iter = IDXIter { idx: &self.tables[i], position: self.positions[i] };
+        self.heads[i] = match iter.next_key() {
+            Some(idx_key) => Some((idx_key.key.clone(), self.tables[i].sst.get_entry(&idx_key.key, idx_key.offset)?)),
+            None => None,
+        };
+        self.positions[i] = iter.position;
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(String, String)>, Error> {
+        let comparator = Arc::clone(&self.options.comparator);
+        loop {
+            let Some(key) = self.heads.iter().flatten()
+                .map(|(key, _)| key)
+                .min_by(|a, b| comparator.compare(a, b))
+                .cloned() else { return Ok(None) };
+
+            let mut value = None;
+            for i in 0..self.tables.len() {
+                if self.heads[i].as_ref().is_none_or(|(next_key, _)| comparator.compare(next_key, &key) != Ordering::Equal) {
+                    continue;
+                }
+
+                let (_, newer_value) = self.heads[i].take().unwrap();
+                self.advance(i)?;
+                value = Some(match value {
+                    Some(older_value) => IDX::merge_values(&self.tables, &key, older_value, newer_value, &self.options)?,
+                    None => newer_value,
+                });
+            }
+
+            let value = match value.unwrap() {
+                SSTValue::Tombstone => continue,
+                SSTValue::Merge(operands) => IDX::fold_operands(&key, None, &operands, &self.options)?.unwrap(),
+                value => self.tables[0].sst.resolve(&key, value)?,
+            };
+            return Ok(Some((key, value)));
+        }
+    }
+}
+
+impl Iterator for MergedEntries {
+    type Item = Result<(String, String), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let entry = self.next_entry().transpose();
+        self.failed = matches!(entry, Some(Err(_)));
+        entry
+    }
+}
@@ -1,4 +1,23 @@
 pub mod avl;
+pub mod backup;
+pub mod column_family;
+pub mod compaction_filter;
+pub mod compaction_strategy;
+pub mod comparator;
+pub mod db;
+pub mod export;
 pub mod idx;
+pub mod manifest;
+pub mod merge;
+pub mod metrics;
+pub mod migrate;
+pub mod options;
+pub mod rate_limiter;
+mod repl;
+pub mod snapshot;
 pub mod sst;
+pub mod sst_writer;
+pub mod vlog;
+pub mod wal;
+pub mod write_batch;
 pub mod cli;
\ No newline at end of file
@@ -1,57 +1,162 @@
 use std::sync::Arc;
 use std::thread;
 use axum::{
-    routing::{post, delete},
-    Router,
+    routing::{get, post, put, delete},
+    Extension, Router,
 };
 use tower_http::trace::TraceLayer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use crate::idx::IDX;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
+use sstable::avl;
+use sstable::db::Db;
+use sstable::idx::IDX;
+use sstable::vlog;
+use config::{Config, LogFormat};
 
-mod avl;
-mod idx;
+mod config;
 mod handlers;
-mod sst;
+#[cfg(feature = "resp")]
+mod resp;
 
 #[tokio::main()]
 async fn main() {
-    // Track AVL size thread
-    let shared_state = Arc::new(avl::AVLTreeSingleton::new());
-
-    thread::spawn({
-        let shared_state = Arc::clone(&shared_state);
-        move || {
-            avl::check_size(shared_state);
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
         }
-    });
-
-    thread::spawn({
-        move || {
-            IDX::compaction();
-        }
-    });
-
+    };
 
     // Initialize tracing
+    let fmt_layer = match config.log_format {
+        LogFormat::Full => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Compact => tracing_subscriber::fmt::layer().compact().boxed(),
+        LogFormat::Pretty => tracing_subscriber::fmt::layer().pretty().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().boxed(),
+    };
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into()),
+            std::env::var("RUST_LOG").unwrap_or_else(|_| config.log_level.clone()),
         ))
-        .with(tracing_subscriber::fmt::layer())
+        .with(fmt_layer)
         .init();
+    tracing::info!("effective configuration:\n{}", config.to_toml());
+
+    // Track AVL size thread
+    let shared_state = Arc::new(Db::open(&config.data_dir, config.options()).unwrap());
+
+    let background = vec![
+        thread::spawn({
+            let shared_state = Arc::clone(&shared_state);
+            move || {
+                avl::check_size(shared_state);
+            }
+        }),
+        thread::spawn({
+            let shared_state = Arc::clone(&shared_state);
+            move || {
+                vlog::collect_garbage(shared_state);
+            }
+        }),
+        thread::spawn({
+            let shared_state = Arc::clone(&shared_state);
+            move || {
+                IDX::compaction(shared_state);
+            }
+        }),
+    ];
 
-    // Create shared state
 
     // Build our application with a route
     let app = Router::new()
         .route("/set", post(handlers::set))
         .route("/get", post(handlers::get))
+        .route("/merge", post(handlers::merge))
         .route("/delete", delete(handlers::delete))
-        .with_state(shared_state)
+        .route("/batch", post(handlers::batch))
+        .route("/kv/:key", get(handlers::get_kv).put(handlers::put_kv).delete(handlers::delete_kv))
+        .route("/metrics", get(handlers::metrics))
+        .route("/export", get(handlers::export))
+        .route("/import", post(handlers::import))
+        .route("/admin/rate_limit", get(handlers::get_rate_limit).put(handlers::set_rate_limit))
+        .route("/admin/flush", post(handlers::flush))
+        .route("/admin/checkpoint", post(handlers::checkpoint))
+        .route("/admin/backup", post(handlers::backup))
+        .route("/admin/backup/restore", post(handlers::restore_backup))
+        .route("/admin/compact_range", post(handlers::compact_range))
+        .route("/admin/compact_range/:id", get(handlers::compact_range_progress))
+        .route("/cf/:family", put(handlers::create_column_family).delete(handlers::drop_column_family))
+        .route("/cf/:family/set", post(handlers::set))
+        .route("/cf/:family/get", post(handlers::get))
+        .route("/cf/:family/merge", post(handlers::merge))
+        .route("/cf/:family/delete", delete(handlers::delete))
+        .route("/cf/:family/kv/:key", get(handlers::get_kv).put(handlers::put_kv).delete(handlers::delete_kv))
+        .with_state(Arc::clone(&shared_state))
+        .layer(Extension(handlers::AdminDir(config.admin_dir.clone())))
         .layer(TraceLayer::new_for_http());
 
-    // Run it with hyper on localhost:8000
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:8000").await.unwrap();
+    #[cfg(feature = "resp")]
+    let (stop_resp, resp) = {
+        let (stop_resp, stopped) = tokio::sync::watch::channel(false);
+        let resp = match &config.resp_bind {
+            Some(bind) => {
+                let listener = tokio::net::TcpListener::bind(bind).await.unwrap();
+                tracing::info!("RESP listening on {}", listener.local_addr().unwrap());
+                Some(tokio::spawn(resp::serve(listener, Arc::clone(&shared_state), stopped)))
+            }
+            None => None,
+        };
+        (stop_resp, resp)
+    };
+    #[cfg(not(feature = "resp"))]
+    if config.resp_bind.is_some() {
+        tracing::warn!("resp_bind is ignored, the server was built without the resp feature");
+    }
+
+    let listener = tokio::net::TcpListener::bind(&config.bind).await.unwrap();
     tracing::info!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    // Stops accepting connections on a signal and returns once the open requests are answered
+    axum::serve(listener, app).with_graceful_shutdown(shutdown_signal()).await.unwrap();
+
+    #[cfg(feature = "resp")]
+    if let Some(resp) = resp {
+        let _ = stop_resp.send(true);
+        let _ = resp.await;
+    }
+
+    tracing::info!("stopping background threads");
+    shared_state.shutdown();
+    for handle in background {
+        if handle.join().is_err() {
+            tracing::error!("a background thread panicked");
+        }
+    }
+
+    // Nothing else writes anymore, whatever is left in the memtables goes to disk
+    for column_family in shared_state.column_families() {
+        match column_family.flush(true) {
+            Ok(Some(table)) => tracing::info!("flushed {} to table {}", column_family.name(), table.id),
+            Ok(None) => {}
+            Err(e) => tracing::error!("failed to flush {}: {}", column_family.name(), e),
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.unwrap();
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap().recv().await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("shutdown signal received, draining requests");
 }
\ No newline at end of file
@@ -0,0 +1,50 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+/// Settings a database has to be reopened with, kept as `key=value` lines in `MANIFEST`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub comparator: String,
+    /// Names of the column families besides the default one, one `column_family=` line each.
+    pub column_families: Vec<String>,
+}
+
+impl Manifest {
+    pub const FILE_NAME: &'static str = "MANIFEST";
+
+    pub fn load(dir: &Path) -> Result<Option<Manifest>, Error> {
+        let content = match fs::read_to_string(dir.join(Self::FILE_NAME)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut comparator = None;
+        let mut column_families = Vec::new();
+        for line in content.lines() {
+            match line.split_once('=') {
+                Some(("comparator", value)) => comparator = Some(value.to_string()),
+                Some(("column_family", value)) => column_families.push(value.to_string()),
+                _ => {}
+            }
+        }
+
+        let comparator = comparator.ok_or_else(|| Error::new(ErrorKind::InvalidData, "MANIFEST has no comparator"))?;
+        Ok(Some(Manifest { comparator, column_families }))
+    }
+
+    pub fn store(&self, dir: &Path) -> Result<(), Error> {
+        /* Written aside and renamed, so a crash never leaves half a manifest */
+        let path = dir.join(Self::FILE_NAME);
+        let tmp_path = path.with_extension("tmp");
+
+        let mut content = format!("comparator={}\n", self.comparator);
+        for column_family in &self.column_families {
+            content.push_str(&format!("column_family={column_family}\n"));
+        }
+
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, path)
+    }
+}
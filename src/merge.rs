@@ -0,0 +1,90 @@
+use std::io::{Error, ErrorKind};
+
+/// Folds merge operands into a value, so read-modify-write needs no read.
+pub trait MergeOperator: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Applies `operands`, oldest first, on top of the existing value if there is one.
+    fn full_merge(&self, key: &str, existing: Option<&str>, operands: &[String]) -> Result<String, Error>;
+
+    /// Refuses an operand before it is written, so it can't fail every later fold of the key.
+    fn check_operand(&self, _key: &str, _operand: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fn parse_u64(key: &str, value: &str) -> Result<u64, Error> {
+    value.parse::<u64>().map_err(|_| {
+        Error::new(ErrorKind::InvalidInput, format!("Value of {key} is not an unsigned integer: {value:?}"))
+    })
+}
+
+/// Treats values as decimal `u64` counters and adds the operands to them.
+pub struct U64AddOperator;
+
+impl MergeOperator for U64AddOperator {
+    fn name(&self) -> &str {
+        "u64add"
+    }
+
+    fn full_merge(&self, key: &str, existing: Option<&str>, operands: &[String]) -> Result<String, Error> {
+        let mut sum = existing.map(|value| parse_u64(key, value)).transpose()?.unwrap_or(0);
+        for operand in operands {
+            sum = sum.checked_add(parse_u64(key, operand)?)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Adding {operand} to {key} overflows")))?;
+        }
+        Ok(sum.to_string())
+    }
+
+    fn check_operand(&self, key: &str, operand: &str) -> Result<(), Error> {
+        parse_u64(key, operand).map(drop)
+    }
+}
+
+/// Appends the operands to the value, separated by `delimiter`.
+pub struct StringAppendOperator {
+    pub delimiter: String,
+}
+
+impl Default for StringAppendOperator {
+    fn default() -> Self {
+        StringAppendOperator { delimiter: ",".to_string() }
+    }
+}
+
+impl MergeOperator for StringAppendOperator {
+    fn name(&self) -> &str {
+        "stringappend"
+    }
+
+    fn full_merge(&self, _key: &str, existing: Option<&str>, operands: &[String]) -> Result<String, Error> {
+        let mut parts = existing.into_iter().chain(operands.iter().map(String::as_str));
+        let mut result = parts.next().unwrap_or_default().to_string();
+        for part in parts {
+            result.push_str(&self.delimiter);
+            result.push_str(part);
+        }
+        Ok(result)
+    }
+}
+
+/// Keeps the largest of the value and the operands, compared as decimal `u64`.
+pub struct MaxOperator;
+
+impl MergeOperator for MaxOperator {
+    fn name(&self) -> &str {
+        "max"
+    }
+
+    fn full_merge(&self, key: &str, existing: Option<&str>, operands: &[String]) -> Result<String, Error> {
+        let mut max = existing.map(|value| parse_u64(key, value)).transpose()?;
+        for operand in operands {
+            max = max.max(Some(parse_u64(key, operand)?));
+        }
+        Ok(max.unwrap_or(0).to_string())
+    }
+
+    fn check_operand(&self, key: &str, operand: &str) -> Result<(), Error> {
+        parse_u64(key, operand).map(drop)
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use crate::db::Db;
+
+/// Why writes to a column family are being held back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StallReason {
+    /// Memtables are switched faster than they are flushed.
+    ImmutableMemtables,
+    /// Flushed tables are written faster than they are compacted.
+    Level0Files,
+}
+
+impl StallReason {
+    const ALL: [StallReason; 2] = [StallReason::ImmutableMemtables, StallReason::Level0Files];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StallReason::ImmutableMemtables => "immutable_memtables",
+            StallReason::Level0Files => "level0_files",
+        }
+    }
+}
+
+/// Counters shared by every column family of a database.
+#[derive(Default)]
+pub struct Metrics {
+    write_slowdowns: [AtomicU64; 2],
+    write_stops: [AtomicU64; 2],
+    write_stall_timeouts: AtomicU64,
+    write_stall_micros: AtomicU64,
+    flushes: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_slowdown(&self, reason: StallReason) {
+        self.write_slowdowns[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_stop(&self, reason: StallReason) {
+        self.write_stops[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_stall_timeout(&self) {
+        self.write_stall_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_stall_time(&self, stalled: Duration) {
+        self.write_stall_micros.fetch_add(stalled.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_flush(&self) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn write_slowdowns(&self, reason: StallReason) -> u64 {
+        self.write_slowdowns[reason as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn write_stops(&self, reason: StallReason) -> u64 {
+        self.write_stops[reason as usize].load(Ordering::Relaxed)
+    }
+}
+
+/// Renders the counters and the state of every column family in the Prometheus text format.
+pub fn render(db: &Db) -> String {
+    let metrics = db.metrics();
+    let mut out = String::new();
+
+    writeln!(out, "# TYPE sstable_write_slowdowns_total counter").unwrap();
+    for reason in StallReason::ALL {
+        writeln!(out, "sstable_write_slowdowns_total{{reason=\"{}\"}} {}", reason.as_str(), metrics.write_slowdowns(reason)).unwrap();
+    }
+    writeln!(out, "# TYPE sstable_write_stops_total counter").unwrap();
+    for reason in StallReason::ALL {
+        writeln!(out, "sstable_write_stops_total{{reason=\"{}\"}} {}", reason.as_str(), metrics.write_stops(reason)).unwrap();
+    }
+    writeln!(out, "# TYPE sstable_write_stall_timeouts_total counter").unwrap();
+    writeln!(out, "sstable_write_stall_timeouts_total {}", metrics.write_stall_timeouts.load(Ordering::Relaxed)).unwrap();
+    writeln!(out, "# TYPE sstable_write_stall_micros_total counter").unwrap();
+    writeln!(out, "sstable_write_stall_micros_total {}", metrics.write_stall_micros.load(Ordering::Relaxed)).unwrap();
+    writeln!(out, "# TYPE sstable_flushes_total counter").unwrap();
+    writeln!(out, "sstable_flushes_total {}", metrics.flushes.load(Ordering::Relaxed)).unwrap();
+
+    let column_families = db.column_families();
+    writeln!(out, "# TYPE sstable_memtable_bytes gauge").unwrap();
+    for column_family in &column_families {
+        let size = column_family.memtable().get_instance().read().map_or(0, |tree| tree.size());
+        writeln!(out, "sstable_memtable_bytes{{column_family=\"{}\"}} {}", column_family.name(), size).unwrap();
+    }
+    writeln!(out, "# TYPE sstable_immutable_memtables gauge").unwrap();
+    for column_family in &column_families {
+        writeln!(out, "sstable_immutable_memtables{{column_family=\"{}\"}} {}", column_family.name(), column_family.immutable_memtables()).unwrap();
+    }
+    writeln!(out, "# TYPE sstable_level0_files gauge").unwrap();
+    for column_family in &column_families {
+        writeln!(out, "sstable_level0_files{{column_family=\"{}\"}} {}", column_family.name(), column_family.level0_files()).unwrap();
+    }
+    writeln!(out, "# TYPE sstable_write_stall gauge").unwrap();
+    for column_family in &column_families {
+        let stall = match column_family.write_stall() {
+            Some((reason, true)) => format!("stop_{}", reason.as_str()),
+            Some((reason, false)) => format!("slowdown_{}", reason.as_str()),
+            None => "none".to_string(),
+        };
+        writeln!(out, "sstable_write_stall{{column_family=\"{}\",state=\"{}\"}} 1", column_family.name(), stall).unwrap();
+    }
+
+    out
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::db::{Db, DEFAULT_COLUMN_FAMILY};
+use crate::idx::IDX;
+use crate::manifest::Manifest;
+use crate::options::Options;
+use crate::sst::SSTValue;
+use crate::sst_writer::SstWriter;
+use crate::wal::Wal;
+
+/// What `migrate` did with one column family.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MigratedFamily {
+    pub name: String,
+    /// Tables of the source, the destination has one table or none.
+    pub tables: usize,
+    pub entries: usize,
+}
+
+/// Rewrites the database in `source` into a new one in `dest`, which must not exist yet.
+///
+/// Every column family is read like `IDX::merged` reads it: duplicates resolve to the newest
+/// table by timestamp and compaction generation, merge operands are folded and value log
+/// pointers followed. The live entries go into one table per family, with every value inline.
+/// Afterwards both sides are read again and compared entry by entry. `dest` only shows up
+/// once that passed, and `source` is never written to, so nothing may write to it meanwhile.
+/// Writes still waiting in the write-ahead log of `source` are refused, not replayed.
+///
+/// Databases from before the manifest, with the tables right in `source`, migrate too. There
+/// is a single table layout today, so this is also how to rebuild a database compactly.
+pub fn migrate(source: &Path, dest: &Path, options: Options) -> Result<Vec<MigratedFamily>, Error> {
+    if dest.exists() {
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", dest.display())));
+    }
+    let names = match Manifest::load(source)? {
+        Some(manifest) if manifest.comparator != options.comparator.name() => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Database was created with the {} comparator, not {}", manifest.comparator, options.comparator.name()),
+            ));
+        }
+        Some(manifest) => manifest.column_families,
+        None if IDX::idx_files(source).is_empty() => {
+            return Err(Error::new(ErrorKind::NotFound, format!("{} holds no database", source.display())));
+        }
+        None => Vec::new(),
+    };
+    let logged = Wal::segments(source)?.into_iter()
+        .any(|segment| fs::metadata(source.join(Wal::segment_path(segment))).is_ok_and(|metadata| metadata.len() > 0));
+    if logged {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} has writes only in its write-ahead log, open it once to replay them", source.display()),
+        ));
+    }
+
+    let tmp = PathBuf::from(format!("{}.tmp", dest.display()));
+    let _ = fs::remove_dir_all(&tmp);
+    let result = (|| {
+        let db = Db::open(&tmp, options.clone())?;
+        let mut migrated = Vec::new();
+        for name in std::iter::once(DEFAULT_COLUMN_FAMILY.to_string()).chain(names) {
+            let dir = match name.as_str() {
+                DEFAULT_COLUMN_FAMILY => source.to_path_buf(),
+                name => source.join(name),
+            };
+            if name != DEFAULT_COLUMN_FAMILY {
+                db.create_column_family(&name, options.clone())?;
+            }
+            migrated.push(migrate_family(&db, &name, &dir)?);
+        }
+        drop(db);
+        fs::rename(&tmp, dest)?;
+        Ok(migrated)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_dir_all(&tmp);
+    }
+    result
+}
+
+fn migrate_family(db: &Db, name: &str, dir: &Path) -> Result<MigratedFamily, Error> {
+    let column_family = db.column_family(name).unwrap();
+    let options = column_family.options();
+
+    /* Built next to the family so ingesting it is a hard link */
+    let staging = db.path().join(format!(".migrate_{name}"));
+    let mut writer = SstWriter::create(&staging, Arc::clone(options))?;
+    for entry in IDX::merged(dir, options)? {
+        let (key, value) = entry?;
+        writer.put(&key, &value)?;
+    }
+
+    let entries = writer.entries();
+    if entries > 0 {
+        let table = writer.finish()?;
+        let ingested = column_family.ingest_external_files(std::slice::from_ref(&table));
+        let _ = fs::remove_file(&table);
+        let _ = fs::remove_file(table.with_extension("sst"));
+        ingested?;
+    }
+
+    verify(dir, column_family.path(), options)?;
+    Ok(MigratedFamily { name: name.to_string(), tables: IDX::idx_files(dir).len(), entries })
+}
+
+/// Reads both directories again and fails on the first entry that differs.
+///
+/// Both sides are read by `IDX::merged`, so the keys of every table are also counted on their
+/// own, which catches a merge that skips keys on both sides alike.
+fn verify(source: &Path, dest: &Path, options: &Arc<Options>) -> Result<(), Error> {
+    let mut expected = IDX::merged(source, options)?;
+    let mut migrated = IDX::merged(dest, options)?;
+    let mut entries = 0;
+    loop {
+        match (expected.next().transpose()?, migrated.next().transpose()?) {
+            (None, None) => break,
+            (Some(expected), Some(migrated)) if expected == migrated => entries += 1,
+            (expected, migrated) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Migrated entry {} of {} differs: expected {:?}, found {:?}", entries + 1, dest.display(), expected, migrated),
+                ));
+            }
+        }
+    }
+
+    for dir in [source, dest] {
+        let keys = count_keys(dir, options)?;
+        if keys != entries {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} holds {} keys, {} were migrated", dir.display(), keys, entries),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Distinct keys of the tables in `dir`, read table by table. A key whose newest record is a
+/// tombstone is not counted.
+fn count_keys(dir: &Path, options: &Arc<Options>) -> Result<usize, Error> {
+    let mut keys = HashMap::new();
+    for path in IDX::idx_files(dir) {
+        for record in IDX::from(path, Arc::clone(options))?.records()? {
+            let record = record?;
+            /* Tables are listed newest first */
+            keys.entry(record.key).or_insert(!matches!(record.value, SSTValue::Tombstone));
+        }
+    }
+    Ok(keys.values().filter(|live| **live).count())
+}
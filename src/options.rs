@@ -0,0 +1,102 @@
+use std::sync::Arc;
+use std::time::Duration;
+use crate::compaction_filter::CompactionFilter;
+use crate::compaction_strategy::{CompactionStrategy, LeveledCompaction};
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::merge::MergeOperator;
+use crate::rate_limiter::RateLimiter;
+
+/// How table files are read from disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReaderBackend {
+    /// `seek` + `read_exact` on a freshly opened file.
+    #[default]
+    Buffered,
+    /// Serve reads straight from a read-only memory map of the file.
+    Mmap,
+}
+
+/// Settings of a database or a column family. The background threads are shared by all
+/// families, so `max_background_compactions`, `compaction_interval` and `value_log_gc_interval`
+/// are read from the options the database is opened with only.
+#[derive(Clone)]
+pub struct Options {
+    /// Key order, fixed for the lifetime of a database.
+    pub comparator: Arc<dyn Comparator>,
+    pub reader: ReaderBackend,
+    /// Tables kept mapped for `ReaderBackend::Mmap` reads. The cache is shared by every
+    /// family and emptied once it holds this many.
+    pub map_cache_size: usize,
+    /// Values longer than this many bytes are moved to the value log on flush,
+    /// `None` keeps every value inline.
+    pub value_log_threshold: Option<usize>,
+    /// Size in bytes after which a new value log segment is started.
+    pub value_log_segment_size: u64,
+    /// Share of dead bytes a sealed segment needs before its live values are rewritten.
+    pub value_log_gc_ratio: f64,
+    /// Needed to store and read merge operands, see `Db::merge`.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Runs on the values compactions write. A removed key reads as its value in the newest
+    /// table left out of the compaction, if there is one, see `Decision::Remove`.
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// The memtable is flushed once its keys and values take this many bytes.
+    pub memtable_size_limit: usize,
+    /// The memtable is flushed once it holds this many keys, `None` means no limit.
+    pub memtable_count_limit: Option<usize>,
+    /// Writes are slowed down once this many full memtables wait to be flushed.
+    pub immutable_memtables_slowdown_trigger: usize,
+    /// Writes are stopped once this many full memtables wait to be flushed.
+    pub max_immutable_memtables: usize,
+    /// Writes are slowed down once this many flushed tables wait to be compacted.
+    pub level0_slowdown_writes_trigger: usize,
+    /// Writes are stopped once this many flushed tables wait to be compacted.
+    pub level0_stop_writes_trigger: usize,
+    /// How long every write sleeps while writes are slowed down.
+    pub write_slowdown_delay: Duration,
+    /// How long a write waits while writes are stopped before it fails.
+    pub write_stall_timeout: Duration,
+    /// Picks the tables to merge, leveled by default.
+    pub compaction_strategy: Arc<dyn CompactionStrategy>,
+    /// Tables of this many bytes or more are left out of leveled compaction.
+    pub compaction_size_limit: u64,
+    /// Compactions run at the same time.
+    pub max_background_compactions: usize,
+    /// Key ranges one compaction is split into and merged in parallel.
+    pub max_subcompactions: usize,
+    /// How often the compaction thread looks for tables to merge.
+    pub compaction_interval: Duration,
+    /// How often value log segments are checked for garbage.
+    pub value_log_gc_interval: Duration,
+    /// Throttles flush and compaction writes, share one limiter between families to cap them together.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            comparator: Arc::new(BytewiseComparator),
+            reader: ReaderBackend::default(),
+            map_cache_size: 1024,
+            value_log_threshold: None,
+            value_log_segment_size: 64 * 1024 * 1024,  // 64 MB
+            value_log_gc_ratio: 0.5,
+            merge_operator: None,
+            compaction_filter: None,
+            memtable_size_limit: 10 * 1024 * 1024,  // 10 MB
+            memtable_count_limit: None,
+            immutable_memtables_slowdown_trigger: 2,
+            max_immutable_memtables: 4,
+            level0_slowdown_writes_trigger: 20,
+            level0_stop_writes_trigger: 36,
+            write_slowdown_delay: Duration::from_millis(1),
+            write_stall_timeout: Duration::from_secs(10),
+            compaction_strategy: Arc::new(LeveledCompaction),
+            compaction_size_limit: 5 * 1024 * 1024,  // 5 MB
+            max_background_compactions: 1,
+            max_subcompactions: 1,
+            compaction_interval: Duration::from_secs(1),
+            value_log_gc_interval: Duration::from_secs(60),
+            rate_limiter: None,
+        }
+    }
+}
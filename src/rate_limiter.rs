@@ -0,0 +1,123 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Who is asking for I/O, higher priorities are served first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Compaction, holds back while flushes wait or foreground reads are running.
+    Low,
+    /// Flushes, they free memtables that writes may be stalled on.
+    High,
+}
+
+struct Bucket {
+    bytes_per_second: u64,
+    /// Can go below zero after a request larger than the bucket.
+    available: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        // At most one second worth of tokens is kept, so an idle limiter can't be bursted through
+        let capacity = self.bytes_per_second as f64;
+        self.available = (self.available + elapsed * capacity).min(capacity);
+        self.refilled_at = now;
+    }
+}
+
+/// Token bucket shared by the background writers of every column family.
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    condvar: Condvar,
+    high_waiting: AtomicUsize,
+    foreground: AtomicUsize,
+}
+
+/// Marks a foreground read as running until it is dropped, see `RateLimiter::foreground`.
+pub struct ForegroundGuard<'a> {
+    limiter: &'a RateLimiter,
+}
+
+impl Drop for ForegroundGuard<'_> {
+    fn drop(&mut self) {
+        self.limiter.foreground.fetch_sub(1, Ordering::Relaxed);
+        self.limiter.condvar.notify_all();
+    }
+}
+
+impl RateLimiter {
+    /// Longest a low priority request gives way to foreground reads before it goes on anyway.
+    const MAX_FOREGROUND_DEFER: Duration = Duration::from_millis(50);
+    const POLL: Duration = Duration::from_millis(1);
+
+    /// `0` bytes per second means no limit.
+    pub fn new(bytes_per_second: u64) -> RateLimiter {
+        RateLimiter {
+            bucket: Mutex::new(Bucket { bytes_per_second, available: bytes_per_second as f64, refilled_at: Instant::now() }),
+            condvar: Condvar::new(),
+            high_waiting: AtomicUsize::new(0),
+            foreground: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bucket.lock().unwrap().bytes_per_second
+    }
+
+    /// Takes effect for requests that are already waiting too.
+    pub fn set_bytes_per_second(&self, bytes_per_second: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.bytes_per_second = bytes_per_second;
+        bucket.available = bucket.available.min(bytes_per_second as f64);
+        self.condvar.notify_all();
+    }
+
+    pub fn foreground(&self) -> ForegroundGuard<'_> {
+        self.foreground.fetch_add(1, Ordering::Relaxed);
+        ForegroundGuard { limiter: self }
+    }
+
+    /// Blocks until `bytes` may be written.
+    pub fn request(&self, bytes: usize, priority: IoPriority) {
+        if priority == IoPriority::High {
+            self.high_waiting.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let started = Instant::now();
+        let mut bucket = self.bucket.lock().unwrap();
+        loop {
+            if bucket.bytes_per_second == 0 {
+                break;
+            }
+
+            let give_way = priority == IoPriority::Low && (
+                self.high_waiting.load(Ordering::Relaxed) > 0
+                    || (self.foreground.load(Ordering::Relaxed) > 0 && started.elapsed() < Self::MAX_FOREGROUND_DEFER)
+            );
+
+            bucket.refill();
+            // Requests larger than the bucket go once it is full and leave it in debt
+            let needed = (bytes as f64).min(bucket.bytes_per_second as f64);
+            if !give_way && bucket.available >= needed {
+                bucket.available -= bytes as f64;
+                break;
+            }
+
+            let wait = match give_way {
+                true => Self::POLL,
+                false => Duration::from_secs_f64((needed - bucket.available) / bucket.bytes_per_second as f64).max(Self::POLL),
+            };
+            bucket = self.condvar.wait_timeout(bucket, wait).unwrap().0;
+        }
+
+        if priority == IoPriority::High {
+            self.high_waiting.fetch_sub(1, Ordering::Relaxed);
+            self.condvar.notify_all();
+        }
+    }
+}
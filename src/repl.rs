@@ -0,0 +1,256 @@
+use std::io::{self, Error, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use crate::cli;
+use crate::column_family::ColumnFamily;
+use crate::db::Db;
+use crate::snapshot::Snapshot;
+
+/// Rows `scan` prints unless it is given a limit.
+const DEFAULT_SCAN_LIMIT: usize = 100;
+
+const COMMANDS: [(&str, &str); 8] = [
+    ("get", "get <key>"),
+    ("put", "put <key> <value>, the value is the rest of the line"),
+    ("del", "del <key>"),
+    ("scan", "scan [start] [end] [limit], keys from start up to, but excluding, end"),
+    ("snapshot", "snapshot [release], reads see the database as it is now until released"),
+    ("stats", "stats"),
+    ("help", "help"),
+    ("exit", "exit, also ctrl-d"),
+];
+
+struct ReplHelper;
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        /* Only the command itself is completed, keys would mean reading every table */
+        let word = &line[..pos];
+        if word.contains(' ') {
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = COMMANDS.iter()
+            .filter(|(command, _)| command.starts_with(word))
+            .map(|(command, _)| Pair { display: command.to_string(), replacement: format!("{command} ") })
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+/// Reads commands until `exit`, working on the database directly like the rest of the CLI.
+/// Writes stay in the memtable until it is full or the session ends, then they are flushed.
+pub(crate) fn repl(db: &Db, column_family: Arc<ColumnFamily>) -> Result<(), Error> {
+    let mut editor = Editor::<ReplHelper, DefaultHistory>::new().map_err(Error::other)?;
+    editor.set_helper(Some(ReplHelper));
+    let history = history_path();
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+
+    let mut session = Session::default();
+    let prompt = format!("{}> ", column_family.name());
+    loop {
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(Error::other(e)),
+        };
+        let Some((command, args)) = parse(&line) else { continue };
+        let _ = editor.add_history_entry(line.trim());
+
+        if command == "exit" || command == "quit" {
+            break;
+        }
+        if let Err(e) = run(db, &column_family, &mut session, command, args) {
+            eprintln!("Error: {}", e);
+        }
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    /* There is no flush thread here, the next open would have to replay the log instead */
+    drop(session);
+    cli::flush_and_compact(&column_family)?;
+    Ok(())
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".sstable_cli_history"))
+}
+
+/// Snapshots of a session, both removed when it ends.
+#[derive(Default)]
+struct Session {
+    /// Taken by `snapshot`, every read uses it until it is released.
+    snapshot: Option<Snapshot>,
+    /// Taken by a scan without a held snapshot and kept for the next ones until a write.
+    scan: Option<Snapshot>,
+}
+
+/// Splits a line into the command and the rest, `None` for a blank line.
+fn parse(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+    Some(line.split_once(' ').map_or((line, ""), |(command, args)| (command, args.trim())))
+}
+
+fn run(db: &Db, column_family: &ColumnFamily, session: &mut Session, command: &str, args: &str) -> Result<(), Error> {
+    let mut out = io::stdout().lock();
+    let words = args.split_whitespace().collect::<Vec<_>>();
+    if matches!(command, "put" | "del") {
+        session.scan = None;
+    }
+
+    match (command, words.as_slice()) {
+        ("get", [key]) => {
+            let value = match &session.snapshot {
+                Some(snapshot) => snapshot.get(key)?,
+                None => column_family.get(key)?,
+            };
+            match value {
+                Some(value) => writeln!(out, "{}", display_value(&value))?,
+                None => writeln!(out, "(not found)")?,
+            }
+        }
+        ("put", [key, ..]) => {
+            let value = args[key.len()..].trim_start();
+            column_family.set(key, value)?;
+            if column_family.immutable_memtables() > 0 {
+                cli::flush_and_compact(column_family)?;
+            }
+            writeln!(out, "OK")?;
+        }
+        ("del", [key]) => {
+            column_family.delete(key)?;
+            if column_family.immutable_memtables() > 0 {
+                cli::flush_and_compact(column_family)?;
+            }
+            writeln!(out, "OK")?;
+        }
+        ("scan", words) if words.len() <= 3 => {
+            let limit = match words.get(2) {
+                Some(limit) => limit.parse().map_err(|_| Error::other(format!("Invalid limit {limit}")))?,
+                None => DEFAULT_SCAN_LIMIT,
+            };
+            /* Scans read tables only, a snapshot brings the memtable along. Nothing runs in
+               the background here, so it stays current until the next write */
+            let entries = match &session.snapshot {
+                Some(snapshot) => snapshot.entries()?,
+                None => match &session.scan {
+                    Some(scan) => scan.entries()?,
+                    None => session.scan.insert(Snapshot::new(column_family)?).entries()?,
+                },
+            };
+
+            let comparator = &column_family.options().comparator;
+            let start = words.first().filter(|start| **start != "-");
+            let end = words.get(1).filter(|end| **end != "-");
+            let mut rows = 0;
+            for entry in entries {
+                let (key, value) = entry?;
+                if start.is_some_and(|start| comparator.compare(&key, start).is_lt()) {
+                    continue;
+                }
+                if end.is_some_and(|end| comparator.compare(&key, end).is_ge()) || rows == limit {
+                    break;
+                }
+                writeln!(out, "{}\t{}", key, display_value(&value))?;
+                rows += 1;
+            }
+            writeln!(out, "({} rows)", rows)?;
+        }
+        ("snapshot", []) => {
+            session.snapshot = Some(Snapshot::new(column_family)?);
+            writeln!(out, "Snapshot taken, reads use it until `snapshot release`")?;
+        }
+        ("snapshot", ["release"]) => {
+            let released = session.snapshot.take().is_some();
+            writeln!(out, "{}", if released { "Snapshot released" } else { "No snapshot held" })?;
+        }
+        ("stats", []) => cli::print_stats(db, false, &mut out)?,
+        ("help", _) => {
+            for (_, usage) in COMMANDS {
+                writeln!(out, "  {}", usage)?;
+            }
+            writeln!(out, "  A `-` skips the start or end of a scan.")?;
+        }
+        _ => match COMMANDS.iter().find(|(name, _)| *name == command) {
+            Some((_, usage)) => writeln!(out, "Usage: {}", usage)?,
+            None => writeln!(out, "Unknown command {}, try help", command)?,
+        },
+    }
+    Ok(())
+}
+
+/// Pretty prints JSON, shows values with control characters as hex and anything else as is.
+fn display_value(value: &str) -> String {
+    if value.starts_with(['{', '[']) {
+        if let Ok(json) = serde_json::from_str::<serde_json::Value>(value) {
+            return serde_json::to_string_pretty(&json).unwrap();
+        }
+    }
+    if value.chars().any(|c| c.is_control() && c != '\t') {
+        let hex = value.bytes().map(|byte| format!("{byte:02x}")).collect::<Vec<_>>().join(" ");
+        return format!("hex: {hex}");
+    }
+    value.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use rustyline::history::DefaultHistory;
+    use super::*;
+
+    #[test]
+    fn display_value_formats_json_and_control_characters() {
+        assert_eq!(display_value(r#"{"a":[1,2]}"#), "{\n  \"a\": [\n    1,\n    2\n  ]\n}");
+        // Not JSON after all, shown as is
+        assert_eq!(display_value("{not json"), "{not json");
+        assert_eq!(display_value("a\u{1}b"), "hex: 61 01 62");
+        assert_eq!(display_value("tab\tseparated"), "tab\tseparated");
+    }
+
+    #[test]
+    fn lines_split_into_command_and_arguments() {
+        assert_eq!(parse("  "), None);
+        assert_eq!(parse("stats"), Some(("stats", "")));
+        assert_eq!(parse("  put key  a value with  spaces "), Some(("put", "key  a value with  spaces")));
+    }
+
+    #[test]
+    fn only_commands_are_completed() {
+        let history = DefaultHistory::new();
+        let context = Context::new(&history);
+        let complete = |line: &str| {
+            let (start, candidates) = ReplHelper.complete(line, line.len(), &context).unwrap();
+            (start, candidates.into_iter().map(|pair| pair.replacement).collect::<Vec<_>>())
+        };
+
+        assert_eq!(complete("s"), (0, vec!["scan ".to_string(), "snapshot ".to_string(), "stats ".to_string()]));
+        assert_eq!(complete("he"), (0, vec!["help ".to_string()]));
+        assert_eq!(complete("get k"), (5, Vec::new()));
+    }
+}
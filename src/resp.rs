@@ -0,0 +1,738 @@
+use std::io::{self, ErrorKind};
+use std::iter::Peekable;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use sstable::db::{Db, DEFAULT_COLUMN_FAMILY};
+use sstable::idx::{MergedEntries, IDX};
+use sstable::snapshot::Snapshot;
+use sstable::write_batch::WriteBatch;
+
+/// Largest bulk string a client may send, the same as Redis.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+/// Longest inline command or header line, the same as Redis.
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// Keys a `SCAN` call looks at unless it is given a `COUNT`.
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// Holds the deadlines of keys set with `EX` or `PX`, in milliseconds since the epoch, under
+/// the same key. Only RESP reads honour them, the HTTP front-end sees an expired key until a
+/// RESP command removes it.
+const EXPIRY_FAMILY: &str = "resp_expiry";
+
+#[derive(Default)]
+struct Stats {
+    connected_clients: AtomicUsize,
+    connections_received: AtomicU64,
+    commands_processed: AtomicU64,
+}
+
+/// A reply, written in RESP2 or RESP3 depending on what the connection asked for with `HELLO`.
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+    /// A flat array of pairs in RESP2.
+    Map(Vec<(&'static str, Reply)>),
+}
+
+impl Reply {
+    fn encode(&self, protocol: u8, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(message) => out.extend_from_slice(format!("+{message}\r\n").as_bytes()),
+            Reply::Error(message) => out.extend_from_slice(format!("-{}\r\n", message.replace(['\r', '\n'], " ")).as_bytes()),
+            Reply::Integer(value) => out.extend_from_slice(format!(":{value}\r\n").as_bytes()),
+            Reply::Bulk(value) => {
+                out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                out.extend_from_slice(value);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Null if protocol >= 3 => out.extend_from_slice(b"_\r\n"),
+            Reply::Null => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(protocol, out);
+                }
+            }
+            Reply::Map(pairs) => {
+                match protocol >= 3 {
+                    true => out.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes()),
+                    false => out.extend_from_slice(format!("*{}\r\n", pairs.len() * 2).as_bytes()),
+                }
+                for (name, value) in pairs {
+                    Reply::Bulk(name.as_bytes().to_vec()).encode(protocol, out);
+                    value.encode(protocol, out);
+                }
+            }
+        }
+    }
+}
+
+/// A `SCAN` a connection is paging through, the next page goes on from `cursor`.
+struct ScanCursor {
+    entries: Peekable<MergedEntries>,
+    /// Keeps the tables `entries` reads from, dropped after them.
+    _snapshot: Snapshot,
+    cursor: usize,
+}
+
+impl From<io::Error> for Reply {
+    fn from(e: io::Error) -> Reply {
+        match e.kind() {
+            // Writes are stopped until flushes and compactions catch up
+            ErrorKind::TimedOut => Reply::Error(format!("TRYAGAIN {e}")),
+            _ => Reply::Error(format!("ERR {e}")),
+        }
+    }
+}
+
+/// Accepts RESP connections on the default column family until `shutdown` changes, then
+/// stops every connection before returning.
+pub async fn serve(listener: TcpListener, db: Arc<Db>, mut shutdown: watch::Receiver<bool>) {
+    let stats = Arc::new(Stats::default());
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let (db, stats) = (Arc::clone(&db), Arc::clone(&stats));
+                    connections.spawn(async move {
+                        stats.connections_received.fetch_add(1, Ordering::Relaxed);
+                        stats.connected_clients.fetch_add(1, Ordering::Relaxed);
+                        if let Err(e) = connection(stream, db, &stats).await {
+                            tracing::debug!("RESP connection closed: {}", e);
+                        }
+                        stats.connected_clients.fetch_sub(1, Ordering::Relaxed);
+                    });
+                }
+                Err(e) => tracing::warn!("failed to accept a RESP connection: {}", e),
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = shutdown.changed() => break,
+        }
+    }
+    connections.shutdown().await;
+}
+
+async fn connection(stream: TcpStream, db: Arc<Db>, stats: &Stats) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut protocol = 2;
+    let mut scanning = None;
+    let mut out = Vec::new();
+
+    loop {
+        let args = match read_command(&mut reader).await {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                writer.write_all(format!("-ERR Protocol error: {e}\r\n").as_bytes()).await?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let Some(name) = args.first() else { continue };
+        let name = String::from_utf8_lossy(name).to_ascii_uppercase();
+        stats.commands_processed.fetch_add(1, Ordering::Relaxed);
+
+        let reply = match name.as_str() {
+            "HELLO" => hello(&args[1..], &mut protocol),
+            "QUIT" => {
+                writer.write_all(b"+OK\r\n").await?;
+                return Ok(());
+            }
+            "INFO" => info(&db, stats),
+            _ => {
+                /* Reads can go to disk and writes can wait for a stall, neither on the runtime */
+                let db = Arc::clone(&db);
+                let mut scan = scanning.take();
+                let reply;
+                (reply, scanning) = tokio::task::spawn_blocking(move || {
+                    (execute(&db, &name, &args[1..], &mut scan).unwrap_or_else(|error| error), scan)
+                }).await.map_err(io::Error::other)?;
+                reply
+            }
+        };
+
+        out.clear();
+        reply.encode(protocol, &mut out);
+        writer.write_all(&out).await?;
+    }
+}
+
+/// A command as an array of bulk strings, or an inline command split on whitespace.
+/// `None` once the client is gone.
+async fn read_command(reader: &mut (impl AsyncBufReadExt + Unpin)) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader).await? else { return Ok(None) };
+    let Some(count) = line.strip_prefix(b"*") else {
+        return Ok(Some(line.split(|byte| byte.is_ascii_whitespace()).filter(|arg| !arg.is_empty()).map(<[u8]>::to_vec).collect()));
+    };
+
+    let count = parse_length(count, MAX_ARGUMENTS)?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(reader).await?.ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
+        let len = line.strip_prefix(b"$").ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "expected '$'"))?;
+        let len = parse_length(len, MAX_BULK_LEN)?;
+
+        /* Grows with the bytes that arrive, not with the length the client announced */
+        let mut arg = Vec::with_capacity(len.min(MAX_LINE_LEN) + 2);
+        (&mut *reader).take(len as u64 + 2).read_to_end(&mut arg).await?;
+        if arg.len() < len + 2 {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof));
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(io::Error::new(ErrorKind::InvalidData, "bulk string is not terminated"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+async fn read_line(reader: &mut (impl AsyncBufReadExt + Unpin)) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let limit = MAX_LINE_LEN + 2;
+    if (&mut *reader).take(limit as u64).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if line.len() == limit && !line.ends_with(b"\n") {
+        return Err(io::Error::new(ErrorKind::InvalidData, "line is too long"));
+    }
+    while line.last().is_some_and(|byte| *byte == b'\n' || *byte == b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_length(digits: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(digits).ok()
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .filter(|len| *len <= max)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "invalid length"))
+}
+
+fn hello(args: &[Vec<u8>], protocol: &mut u8) -> Reply {
+    /* AUTH and SETNAME are accepted and ignored, there are no users */
+    if let Some(version) = args.first() {
+        match version.as_slice() {
+            b"2" => *protocol = 2,
+            b"3" => *protocol = 3,
+            _ => return Reply::Error("NOPROTO unsupported protocol version".to_string()),
+        }
+    }
+    Reply::Map(vec![
+        ("server", Reply::Bulk(b"sstable".to_vec())),
+        ("version", Reply::Bulk(env!("CARGO_PKG_VERSION").as_bytes().to_vec())),
+        ("proto", Reply::Integer(*protocol as i64)),
+        ("mode", Reply::Bulk(b"standalone".to_vec())),
+        ("role", Reply::Bulk(b"master".to_vec())),
+        ("modules", Reply::Array(Vec::new())),
+    ])
+}
+
+fn info(db: &Db, stats: &Stats) -> Reply {
+    let info = format!(
+        "# Server\r\nserver:sstable\r\nversion:{}\r\n\r\n# Clients\r\nconnected_clients:{}\r\n\r\n# Stats\r\ntotal_connections_received:{}\r\ntotal_commands_processed:{}\r\n\r\n# Keyspace\r\ncolumn_families:{}\r\n",
+        env!("CARGO_PKG_VERSION"),
+        stats.connected_clients.load(Ordering::Relaxed),
+        stats.connections_received.load(Ordering::Relaxed),
+        stats.commands_processed.load(Ordering::Relaxed),
+        db.column_families().len(),
+    );
+    Reply::Bulk(info.into_bytes())
+}
+
+fn wrong_arguments(name: &str) -> Reply {
+    Reply::Error(format!("ERR wrong number of arguments for '{}' command", name.to_ascii_lowercase()))
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_string())
+}
+
+fn key(arg: &[u8]) -> Result<String, Reply> {
+    let key = std::str::from_utf8(arg).map_err(|_| Reply::Error("ERR Key must be alphanumeric and less than 11 chars".to_string()))?;
+    IDX::check_key(key)?;
+    Ok(key.to_string())
+}
+
+fn value(arg: &[u8]) -> Result<String, Reply> {
+    String::from_utf8(arg.to_vec()).map_err(|_| Reply::Error("ERR value is not UTF-8".to_string()))
+}
+
+fn integer(arg: &[u8]) -> Result<i64, Reply> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|arg| arg.parse().ok())
+        .ok_or_else(|| Reply::Error("ERR value is not an integer or out of range".to_string()))
+}
+
+fn bulk(value: Option<String>) -> Reply {
+    value.map_or(Reply::Null, |value| Reply::Bulk(value.into_bytes()))
+}
+
+fn execute(db: &Db, name: &str, args: &[Vec<u8>], scanning: &mut Option<ScanCursor>) -> Result<Reply, Reply> {
+    let column_family = db.default_column_family();
+    match (name, args) {
+        ("PING", []) => Ok(Reply::Simple("PONG")),
+        ("PING", [message]) | ("ECHO", [message]) => Ok(Reply::Bulk(message.clone())),
+        ("GET", [name]) => Ok(bulk(get(db, &key(name)?)?)),
+        ("SET", [name, value_arg, options @ ..]) => set(db, &key(name)?, value(value_arg)?, options),
+        ("MGET", names) if !names.is_empty() => {
+            let values = names.iter()
+                .map(|name| Ok(bulk(get(db, &key(name)?)?)))
+                .collect::<Result<Vec<_>, Reply>>()?;
+            Ok(Reply::Array(values))
+        }
+        ("MSET", pairs) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            let mut batch = WriteBatch::new();
+            let expiry = db.column_family(EXPIRY_FAMILY);
+            for pair in pairs.chunks(2) {
+                let name = key(&pair[0])?;
+                batch.put(DEFAULT_COLUMN_FAMILY, &name, &value(&pair[1])?);
+                if expiry.is_some() {
+                    batch.delete(EXPIRY_FAMILY, &name);
+                }
+            }
+            let _guard = column_family.lock_conditional_writes();
+            db.write(&batch)?;
+            Ok(Reply::Simple("OK"))
+        }
+        ("DEL", names) if !names.is_empty() => {
+            let names = names.iter().map(|name| key(name)).collect::<Result<Vec<_>, Reply>>()?;
+            let _guard = column_family.lock_conditional_writes();
+            let mut batch = WriteBatch::new();
+            let expiry = db.column_family(EXPIRY_FAMILY);
+            let mut deleted = 0;
+            for name in names {
+                if get_locked(db, &name)?.is_some() {
+                    batch.delete(DEFAULT_COLUMN_FAMILY, &name);
+                    if expiry.is_some() {
+                        batch.delete(EXPIRY_FAMILY, &name);
+                    }
+                    deleted += 1;
+                }
+            }
+            db.write(&batch)?;
+            Ok(Reply::Integer(deleted))
+        }
+        ("EXISTS", names) if !names.is_empty() => {
+            let mut exists = 0;
+            for name in names {
+                exists += get(db, &key(name)?)?.is_some() as i64;
+            }
+            Ok(Reply::Integer(exists))
+        }
+        ("INCR", [name]) => increment(db, &key(name)?, 1),
+        ("INCRBY", [name, by]) => increment(db, &key(name)?, integer(by)?),
+        ("SCAN", [cursor, options @ ..]) => scan(db, cursor, options, scanning),
+        /* Clients send these while connecting */
+        ("COMMAND", _) => Ok(Reply::Array(Vec::new())),
+        ("CLIENT", _) => Ok(Reply::Simple("OK")),
+        ("SELECT", [index]) if index.as_slice() == b"0" => Ok(Reply::Simple("OK")),
+        ("SELECT", [_]) => Err(Reply::Error("ERR DB index is out of range".to_string())),
+        ("PING" | "ECHO" | "GET" | "SET" | "MGET" | "MSET" | "DEL" | "EXISTS" | "INCR" | "INCRBY" | "SCAN" | "SELECT", _) => {
+            Err(wrong_arguments(name))
+        }
+        _ => Err(Reply::Error(format!("ERR unknown command '{}'", name.to_ascii_lowercase()))),
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+fn expired(db: &Db, key: &str) -> Result<bool, Reply> {
+    let Some(expiry) = db.column_family(EXPIRY_FAMILY) else { return Ok(false) };
+    let deadline = expiry.get(key)?.map(|deadline| deadline.parse::<u64>()).transpose()
+        .map_err(|_| Reply::Error(format!("ERR invalid deadline stored for {key}")))?;
+    Ok(deadline.is_some_and(|deadline| deadline <= now_millis()))
+}
+
+/// The value of `key`, unless its deadline passed. An expired key is deleted on the way.
+fn get(db: &Db, key: &str) -> Result<Option<String>, Reply> {
+    let column_family = db.default_column_family();
+    let value = column_family.get(key)?;
+    if value.is_none() || !expired(db, key)? {
+        return Ok(value);
+    }
+    let _guard = column_family.lock_conditional_writes();
+    get_locked(db, key)
+}
+
+/// Same as `get`, for callers that hold the conditional write lock of the default family.
+fn get_locked(db: &Db, key: &str) -> Result<Option<String>, Reply> {
+    let value = db.default_column_family().get(key)?;
+    if value.is_some() && expired(db, key)? {
+        let mut batch = WriteBatch::new();
+        batch.delete(DEFAULT_COLUMN_FAMILY, key).delete(EXPIRY_FAMILY, key);
+        db.write(&batch)?;
+        return Ok(None);
+    }
+    Ok(value)
+}
+
+/// `SET key value [NX | XX] [GET] [EX seconds | PX milliseconds | KEEPTTL]`. A deadline goes
+/// to the expiry family, a `SET` without `KEEPTTL` removes the one the key had.
+fn set(db: &Db, key: &str, value: String, options: &[Vec<u8>]) -> Result<Reply, Reply> {
+    let invalid_expire_time = || Reply::Error("ERR invalid expire time in 'set' command".to_string());
+    let (mut nx, mut xx, mut get, mut keep_ttl, mut deadline) = (false, false, false, false, None);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let option = option.to_ascii_uppercase();
+        match option.as_slice() {
+            b"NX" => nx = true,
+            b"XX" => xx = true,
+            b"GET" => get = true,
+            b"KEEPTTL" if deadline.is_none() => keep_ttl = true,
+            b"EX" | b"PX" if deadline.is_none() && !keep_ttl => {
+                let time = integer(options.next().ok_or_else(syntax_error)?)?;
+                let millis = match option.as_slice() {
+                    b"EX" => time.checked_mul(1000),
+                    _ => Some(time),
+                };
+                deadline = Some(millis.filter(|millis| *millis > 0)
+                    .and_then(|millis| now_millis().checked_add(millis as u64))
+                    .ok_or_else(invalid_expire_time)?);
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+    if nx && xx {
+        return Err(syntax_error());
+    }
+
+    let column_family = db.default_column_family();
+    let _guard = column_family.lock_conditional_writes();
+    let current = match nx || xx || get {
+        true => get_locked(db, key)?,
+        false => None,
+    };
+    if (nx && current.is_some()) || (xx && current.is_none()) {
+        return Ok(if get { bulk(current) } else { Reply::Null });
+    }
+
+    let mut batch = WriteBatch::new();
+    batch.put(DEFAULT_COLUMN_FAMILY, key, &value);
+    match deadline {
+        Some(deadline) => {
+            if db.column_family(EXPIRY_FAMILY).is_none() {
+                match db.create_column_family(EXPIRY_FAMILY, db.options().as_ref().clone()) {
+                    Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e.into()),
+                    _ => {}
+                }
+            }
+            batch.put(EXPIRY_FAMILY, key, &deadline.to_string());
+        }
+        None if !keep_ttl && db.column_family(EXPIRY_FAMILY).is_some() => {
+            batch.delete(EXPIRY_FAMILY, key);
+        }
+        None => {}
+    }
+    db.write(&batch)?;
+    Ok(if get { bulk(current) } else { Reply::Simple("OK") })
+}
+
+/// Adds through the merge operator. Its values are unsigned, so a decrement is written as the
+/// new value instead, and one that would go below zero is refused. Either keeps the deadline.
+fn increment(db: &Db, key: &str, by: i64) -> Result<Reply, Reply> {
+    let out_of_range = || Reply::Error("ERR value is not an integer or out of range".to_string());
+    let overflow = || Reply::Error("ERR increment or decrement would overflow".to_string());
+
+    let column_family = db.default_column_family();
+    let _guard = column_family.lock_conditional_writes();
+    /* An operand on a value that is not a number would break every later read of the key */
+    let current = get_locked(db, key)?.map(|value| value.parse::<u64>()).transpose().map_err(|_| out_of_range())?;
+    let current = current.unwrap_or(0);
+    if by < 0 {
+        let value = current.checked_sub(by.unsigned_abs()).ok_or_else(overflow)?;
+        column_family.set(key, &value.to_string())?;
+        return Ok(Reply::Integer(value as i64));
+    }
+    if current.checked_add(by as u64).is_none_or(|sum| sum > i64::MAX as u64) {
+        return Err(overflow());
+    }
+
+    column_family.merge(key, &by.to_string())?;
+    let value = column_family.get(key)?.ok_or_else(out_of_range)?;
+    Ok(Reply::Integer(value.parse().map_err(|_| out_of_range())?))
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`. Cursor 0 takes a snapshot the connection then
+/// pages through, so a scan returns every key of that moment once. The cursor is the number of
+/// keys before it in key order, any other cursor takes a new snapshot and skips that many keys,
+/// so keys written meanwhile can shift a key into the next page or skip one.
+fn scan(db: &Db, cursor: &[u8], options: &[Vec<u8>], scanning: &mut Option<ScanCursor>) -> Result<Reply, Reply> {
+    let cursor = std::str::from_utf8(cursor).ok()
+        .and_then(|cursor| cursor.parse::<usize>().ok())
+        .ok_or_else(|| Reply::Error("ERR invalid cursor".to_string()))?;
+
+    let (mut pattern, mut count) = (None, DEFAULT_SCAN_COUNT);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let argument = options.next().ok_or_else(syntax_error)?;
+        match option.to_ascii_uppercase().as_slice() {
+            b"MATCH" => pattern = Some(argument.as_slice()),
+            b"COUNT" => count = integer(argument)?.try_into().ok().filter(|count| *count > 0).ok_or_else(syntax_error)?,
+            _ => return Err(syntax_error()),
+        }
+    }
+
+    let mut scan = match scanning.take() {
+        Some(scan) if cursor != 0 && scan.cursor == cursor => scan,
+        _ => {
+            let snapshot = Snapshot::new(&db.default_column_family())?;
+            let mut entries = snapshot.entries()?.peekable();
+            for entry in entries.by_ref().take(cursor) {
+                entry?;
+            }
+            ScanCursor { entries, _snapshot: snapshot, cursor }
+        }
+    };
+
+    let mut keys = Vec::new();
+    for entry in scan.entries.by_ref().take(count) {
+        let (key, _) = entry?;
+        scan.cursor += 1;
+        if pattern.is_none_or(|pattern| glob_match(pattern, key.as_bytes())) && !expired(db, &key)? {
+            keys.push(Reply::Bulk(key.into_bytes()));
+        }
+    }
+
+    let next = match scan.entries.peek() {
+        Some(_) => scan.cursor,
+        None => 0,
+    };
+    if next != 0 {
+        *scanning = Some(scan);
+    }
+    Ok(Reply::Array(vec![Reply::Bulk(next.to_string().into_bytes()), Reply::Array(keys)]))
+}
+
+/// Redis glob patterns: `*`, `?`, `[abc]`, `[^a]`, `[a-z]` and `\` escapes.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((b'[', rest)) => {
+            let Some((&byte, text_rest)) = text.split_first() else { return false };
+            let Some(end) = rest.iter().skip(1).position(|c| *c == b']').map(|end| end + 1) else {
+                return false;
+            };
+            let (negate, class) = match rest[0] == b'^' {
+                true => (true, &rest[1..end]),
+                false => (false, &rest[..end]),
+            };
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    let (low, high) = (class[i].min(class[i + 2]), class[i].max(class[i + 2]));
+                    matched |= (low..=high).contains(&byte);
+                    i += 3;
+                } else {
+                    matched |= class[i] == byte;
+                    i += 1;
+                }
+            }
+            matched != negate && glob_match(&rest[end + 1..], text_rest)
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => text.first() == Some(&rest[0]) && glob_match(&rest[1..], &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(input: &[u8]) -> io::Result<Option<Vec<Vec<u8>>>> {
+        read_command(&mut BufReader::new(input)).await
+    }
+
+    fn encode(reply: Reply, protocol: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        reply.encode(protocol, &mut out);
+        out
+    }
+
+    #[test]
+    fn globs_match_like_redis() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"user*", b"user42"));
+        assert!(!glob_match(b"user*", b"use"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"key[0-9]", b"key7"));
+        assert!(glob_match(b"key[9-0]", b"key7"));
+        assert!(!glob_match(b"key[0-9]", b"keyx"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        // An unclosed class matches nothing
+        assert!(!glob_match(b"[ab", b"a"));
+    }
+
+    #[test]
+    fn replies_encode_per_protocol() {
+        assert_eq!(encode(Reply::Simple("OK"), 2), b"+OK\r\n");
+        assert_eq!(encode(Reply::Error("ERR a\r\nb".to_string()), 2), b"-ERR a  b\r\n");
+        assert_eq!(encode(Reply::Integer(-3), 2), b":-3\r\n");
+        assert_eq!(encode(Reply::Bulk(b"a\r\nb".to_vec()), 2), b"$4\r\na\r\nb\r\n");
+        assert_eq!(encode(Reply::Null, 2), b"$-1\r\n");
+        assert_eq!(encode(Reply::Null, 3), b"_\r\n");
+        assert_eq!(encode(Reply::Array(vec![Reply::Integer(1), Reply::Null]), 2), b"*2\r\n:1\r\n$-1\r\n");
+
+        let map = || Reply::Map(vec![("proto", Reply::Integer(2))]);
+        assert_eq!(encode(map(), 2), b"*2\r\n$5\r\nproto\r\n:2\r\n");
+        assert_eq!(encode(map(), 3), b"%1\r\n$5\r\nproto\r\n:2\r\n");
+    }
+
+    #[tokio::test]
+    async fn commands_are_read_as_arrays_or_inline() {
+        assert_eq!(read(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n").await.unwrap(), Some(vec![b"GET".to_vec(), b"a".to_vec()]));
+        // Bulk strings can hold line breaks
+        assert_eq!(read(b"*1\r\n$4\r\na\r\nb\r\n").await.unwrap(), Some(vec![b"a\r\nb".to_vec()]));
+        assert_eq!(read(b"SET  a 1\r\n").await.unwrap(), Some(vec![b"SET".to_vec(), b"a".to_vec(), b"1".to_vec()]));
+        assert_eq!(read(b"").await.unwrap(), None);
+
+        assert_eq!(read(b"*1\r\n$3\r\nGET").await.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(read(b"*1\r\n$3\r\nGETxx").await.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(read(b"*1\r\n:3\r\n").await.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(read(b"*x\r\n").await.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(read(format!("*1\r\n${}\r\n", MAX_BULK_LEN + 1).as_bytes()).await.unwrap_err().kind(), ErrorKind::InvalidData);
+        // A length alone allocates nothing, the missing bytes end the command
+        assert_eq!(read(format!("*1\r\n${MAX_BULK_LEN}\r\nab").as_bytes()).await.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+        let long = vec![b'a'; MAX_LINE_LEN + 2];
+        assert_eq!(read(&long).await.unwrap_err().kind(), ErrorKind::InvalidData);
+        let fits = [vec![b'a'; MAX_LINE_LEN], b"\r\n".to_vec()].concat();
+        assert_eq!(read(&fits).await.unwrap().unwrap()[0].len(), MAX_LINE_LEN);
+    }
+
+    fn open(name: &str) -> (Db, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("sstable_resp_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let options = sstable::options::Options { merge_operator: Some(Arc::new(sstable::merge::U64AddOperator)), ..Default::default() };
+        (Db::open(&dir, options).unwrap(), dir)
+    }
+
+    fn run(db: &Db, command: &str) -> Result<Reply, Reply> {
+        let mut args = command.split(' ').map(|arg| arg.as_bytes().to_vec());
+        let name = String::from_utf8(args.next().unwrap()).unwrap();
+        execute(db, &name, &args.collect::<Vec<_>>(), &mut None)
+    }
+
+    fn integer_reply(reply: Result<Reply, Reply>) -> i64 {
+        match reply {
+            Ok(Reply::Integer(value)) => value,
+            _ => panic!(),
+        }
+    }
+
+    fn bulk_reply(reply: Result<Reply, Reply>) -> Option<String> {
+        match reply {
+            Ok(Reply::Bulk(value)) => Some(String::from_utf8(value).unwrap()),
+            Ok(Reply::Null) => None,
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn deletes_count_the_keys_they_remove() {
+        let (db, dir) = open("del");
+        db.set("flushed", "1").unwrap();
+        db.flush(true).unwrap();
+        db.set("active", "1").unwrap();
+
+        assert_eq!(integer_reply(run(&db, "DEL flushed active missing")), 2);
+        assert_eq!(db.get("flushed").unwrap(), None);
+        assert_eq!(integer_reply(run(&db, "DEL flushed")), 0);
+
+        drop(db);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keys_expire_after_their_deadline() {
+        let (db, dir) = open("expire");
+        assert!(matches!(run(&db, "SET a 1 PX 50"), Ok(Reply::Simple("OK"))));
+        assert!(matches!(run(&db, "SET b 1 EX 100"), Ok(Reply::Simple("OK"))));
+        assert!(matches!(run(&db, "SET c 1 PX 50"), Ok(Reply::Simple("OK"))));
+        // A plain SET drops the deadline, KEEPTTL keeps it
+        assert!(matches!(run(&db, "SET c 2"), Ok(Reply::Simple("OK"))));
+        assert!(matches!(run(&db, "SET b 2 KEEPTTL"), Ok(Reply::Simple("OK"))));
+        assert_eq!(bulk_reply(run(&db, "GET a")).as_deref(), Some("1"));
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(bulk_reply(run(&db, "GET a")), None);
+        assert_eq!(db.get("a").unwrap(), None);
+        assert_eq!(bulk_reply(run(&db, "GET b")).as_deref(), Some("2"));
+        assert_eq!(bulk_reply(run(&db, "GET c")).as_deref(), Some("2"));
+        assert!(matches!(run(&db, "SET a 1 NX"), Ok(Reply::Simple("OK"))));
+
+        for invalid in ["SET a 1 EX 0", "SET a 1 PX -1", "SET a 1 EX 9223372036854775807"] {
+            assert!(matches!(run(&db, invalid), Err(Reply::Error(message)) if message.contains("invalid expire time")));
+        }
+        assert!(matches!(run(&db, "SET a 1 EX 1 KEEPTTL"), Err(Reply::Error(_))));
+        assert!(matches!(run(&db, "SET a 1 EX 1 PX 1"), Err(Reply::Error(_))));
+
+        drop(db);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn increments_can_be_negative() {
+        let (db, dir) = open("incrby");
+        assert_eq!(integer_reply(run(&db, "INCRBY n 5")), 5);
+        assert_eq!(integer_reply(run(&db, "INCRBY n -3")), 2);
+        assert_eq!(integer_reply(run(&db, "INCR n")), 3);
+        // Values are unsigned, the counter can't go below zero
+        assert!(matches!(run(&db, "INCRBY n -4"), Err(Reply::Error(message)) if message.contains("overflow")));
+        assert_eq!(bulk_reply(run(&db, "GET n")).as_deref(), Some("3"));
+
+        drop(db);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scans_page_through_one_snapshot() {
+        let dir = std::env::temp_dir().join(format!("sstable_resp_scan_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = Db::open(&dir, sstable::options::Options::default()).unwrap();
+        for key in ["a", "b", "c"] {
+            db.set(key, "1").unwrap();
+        }
+
+        let mut scanning = None;
+        let mut scan = |cursor: &str| {
+            let args = [cursor.as_bytes().to_vec(), b"COUNT".to_vec(), b"2".to_vec()];
+            let Ok(Reply::Array(reply)) = execute(&db, "SCAN", &args, &mut scanning) else { panic!() };
+            let [Reply::Bulk(next), Reply::Array(keys)] = reply.as_slice() else { panic!() };
+            let keys = keys.iter().map(|key| match key { Reply::Bulk(key) => key.clone(), _ => panic!() }).collect::<Vec<_>>();
+            (String::from_utf8(next.clone()).unwrap(), keys)
+        };
+
+        assert_eq!(scan("0"), ("2".to_string(), vec![b"a".to_vec(), b"b".to_vec()]));
+        // Keys written after the first page are not in the scan
+        db.set("aa", "1").unwrap();
+        assert_eq!(scan("2"), ("0".to_string(), vec![b"c".to_vec()]));
+        // A new scan sees them
+        assert_eq!(scan("0"), ("2".to_string(), vec![b"a".to_vec(), b"aa".to_vec()]));
+
+        drop(db);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,52 @@
+use std::fs;
+use std::io::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use crate::column_family::ColumnFamily;
+use crate::idx::{MergedEntries, IDX};
+use crate::options::Options;
+
+/// Tells snapshots taken at the same time apart.
+static NEXT_SNAPSHOT: AtomicU64 = AtomicU64::new(0);
+
+/// A column family frozen in time, reads of it never see later writes.
+///
+/// The tables are hard linked into a hidden directory next to them and the memtables are
+/// written there as tables of their own, so taking one costs a link per file and a write of
+/// the memtables, without flushing the family. The directory is removed on drop, and by
+/// `ColumnFamily::open` when the process died first.
+pub struct Snapshot {
+    dir: PathBuf,
+    options: Arc<Options>,
+}
+
+impl Snapshot {
+    pub(crate) const DIR_PREFIX: &'static str = ".snapshot_";
+
+    pub fn new(column_family: &ColumnFamily) -> Result<Snapshot, Error> {
+        let id = NEXT_SNAPSHOT.fetch_add(1, Ordering::Relaxed);
+        let dir = column_family.path().join(format!("{}{}_{}", Self::DIR_PREFIX, std::process::id(), id));
+        fs::create_dir_all(&dir)?;
+
+        let snapshot = Snapshot { dir, options: Arc::clone(column_family.options()) };
+        column_family.freeze(&snapshot.dir)?;
+        Ok(snapshot)
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        let index_value = IDX::search_key_in_all_files(&self.dir, key, Vec::new(), &self.options)?;
+        Ok(index_value.map(|index_value| index_value.value))
+    }
+
+    /// Every live key with its value, in key order.
+    pub fn entries(&self) -> Result<MergedEntries, Error> {
+        IDX::merged(&self.dir, &self.options)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
@@ -1,18 +1,107 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Error, Seek, SeekFrom, Read, Write};
-use std::path::{PathBuf};
+use std::collections::HashMap;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{BufReader, Error, ErrorKind, Seek, SeekFrom, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::SystemTime;
+use memmap2::Mmap;
 use crate::idx::IDX;
+use crate::options::{Options, ReaderBackend};
+use crate::vlog::{ValueLog, ValuePointer};
+
+/// Maps of the tables read with `ReaderBackend::Mmap`, shared by every `SST` of a file since
+/// readers open a new `SST` per lookup. Emptied once it holds `Options::map_cache_size` maps,
+/// tables removed by compaction stay in it until then.
+static MAPS: LazyLock<Mutex<HashMap<PathBuf, CachedMap>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+struct CachedMap {
+    map: Arc<Mmap>,
+    /// File the map was made of, a file renamed over the path or grown since gets mapped again.
+    id: (u64, u64, Option<SystemTime>),
+}
+
+fn file_id(metadata: &Metadata) -> (u64, u64, Option<SystemTime>) {
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+    #[cfg(not(unix))]
+    let inode = 0;
+    (inode, metadata.len(), metadata.modified().ok())
+}
+
+/// Drops the cached map of a removed file, so its pages are released right away.
+pub(crate) fn forget_map(path: &Path) {
+    MAPS.lock().unwrap().remove(path);
+}
 
 pub struct SST {
     pub path: PathBuf,
+    options: Arc<Options>,
+}
+
+/// A value borrowed straight from a memory-mapped table, without copying it out.
+pub struct MappedValue {
+    map: Arc<Mmap>,
+    range: Range<usize>,
+}
+
+impl MappedValue {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.map[self.range.clone()]
+    }
+
+    pub fn as_str(&self) -> Result<&str, Error> {
+        std::str::from_utf8(self.as_bytes()).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+/// A value as it is stored in a table record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SSTValue {
+    Inline(String),
+    Pointer(ValuePointer),
+    /// Operands still waiting for an older value, oldest first.
+    Merge(Vec<String>),
+    /// The key was deleted, older values of it are hidden.
+    Tombstone,
+}
+
+#[derive(Debug)]
+pub struct SSTRecord {
+    pub offset: u64,
+    pub key: String,
+    pub value: SSTValue,
 }
 
 impl SST {
     const KEY_LEN: usize = IDX::KEY_LEN;
     const VALUE_LEN: usize = 4;
-    
-    pub fn new(path: PathBuf) -> SST {
-        SST { path }
+    /// Set in the value length of records that hold a `ValuePointer` instead of the value.
+    const POINTER_FLAG: u32 = 1 << 31;
+    /// Set in the value length of records that hold merge operands instead of the value.
+    const MERGE_FLAG: u32 = 1 << 30;
+    const FLAGS: u32 = SST::POINTER_FLAG | SST::MERGE_FLAG;
+    /// Both flags and no payload mark a deleted key.
+    const TOMBSTONE: u32 = SST::FLAGS;
+    /// Key of the record every table starts with, which holds the name of the comparator that
+    /// ordered it. It is never indexed, and no valid key can look like it.
+    const COMPARATOR_KEY: &'static str = "#comparator";
+    /// Longest value a record holds inline, longer ones would run into the flags.
+    pub const MAX_VALUE_LEN: usize = !SST::FLAGS as usize;
+
+    pub fn check_value(value: &str) -> Result<(), Error> {
+        if value.len() > SST::MAX_VALUE_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Value must be at most {} bytes", SST::MAX_VALUE_LEN)));
+        }
+        Ok(())
+    }
+
+    pub fn new(path: PathBuf, options: Arc<Options>) -> SST {
+        SST { path, options }
+    }
+
+    pub fn options(&self) -> &Arc<Options> {
+        &self.options
     }
 
     fn get_file(&self, create: bool) -> Result<File, Error> {
@@ -25,52 +114,293 @@ impl SST {
         Ok(size_in_bytes as f64 / 1024.0 / 1024.0)
     }
 
-    fn get_key_size_from_byte_file(&self, file: &mut File) -> Result<u8, Error> {
+    /// Name of the comparator the table was written with, `None` for tables from before it was recorded.
+    pub fn comparator(&self) -> Result<Option<String>, Error> {
+        let mut file = BufReader::new(self.get_file(false)?);
+        match self.read_record(&mut file) {
+            Ok((key, SSTValue::Inline(name), _)) if key == SST::COMPARATOR_KEY => Ok(Some(name)),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Fails when the table was ordered by another comparator than the one of the options.
+    pub fn check_comparator(&self) -> Result<(), Error> {
+        match self.comparator()? {
+            Some(name) if name != self.options.comparator.name() => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} was written with the {} comparator, not {}", self.path.display(), name, self.options.comparator.name()),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn get_key_size_from_byte_file(&self, file: &mut impl Read) -> Result<u8, Error> {
         /* Extract key size */
         let mut key_len_buf = [0u8; SST::KEY_LEN];
         file.read_exact(&mut key_len_buf)?;
         Ok(u8::from_le_bytes(key_len_buf))
     }
 
-    fn get_key_from_byte_file(&self, file: &mut File, key_size: usize) -> Result<String, Error> {
+    fn get_key_from_byte_file(&self, file: &mut impl Read, key_size: usize) -> Result<String, Error> {
         /* Extract key */
         let mut key_buf = vec![0u8; key_size];
         file.read_exact(&mut key_buf)?;
         Ok(String::from_utf8_lossy(&key_buf).to_string())
     }
 
-    fn get_value_size_from_byte_file(&self, file: &mut File) -> Result<u32, Error> {
+    fn get_value_size_from_byte_file(&self, file: &mut impl Read) -> Result<u32, Error> {
         /* Extract key size */
         let mut key_len_buf = [0u8; SST::VALUE_LEN];
         file.read_exact(&mut key_len_buf)?;
         Ok(u32::from_le_bytes(key_len_buf))
     }
-    
+
     pub fn get(&self, key: &str, offset: u64) -> Result<String, Error> {
+        match self.options.reader {
+            ReaderBackend::Buffered => {
+                let value = self.get_entry(key, offset)?;
+                self.resolve(key, value)
+            },
+            // Invalid UTF-8 is replaced like the buffered reader does, not refused
+            ReaderBackend::Mmap => Ok(String::from_utf8_lossy(self.get_mapped(key, offset)?.as_bytes()).into_owned()),
+        }
+    }
+
+    /// Turns a stored value into the value itself, following value log pointers.
+    pub fn resolve(&self, key: &str, value: SSTValue) -> Result<String, Error> {
+        match value {
+            SSTValue::Inline(value) => Ok(value),
+            SSTValue::Pointer(pointer) => self.value_log_segment(&pointer).get(key, pointer.offset),
+            SSTValue::Merge(_) => Err(Error::new(ErrorKind::InvalidData, "Merge operands have to be folded first")),
+            SSTValue::Tombstone => Err(Error::new(ErrorKind::NotFound, "Key was deleted")),
+        }
+    }
+
+    /// Reads the record as it is stored, without following value log pointers.
+    pub fn get_entry(&self, key: &str, offset: u64) -> Result<SSTValue, Error> {
+        if self.options.reader == ReaderBackend::Mmap {
+            let map = self.map()?;
+            let (value_len, payload) = self.locate_mapped(&map, key, offset)?;
+            return SST::decode_value(value_len, &map[payload]);
+        }
+
         let mut file = self.get_file(false)?;
         file.seek(SeekFrom::Start(offset))?;
-        
-        let key_size = self.get_key_size_from_byte_file(&mut file)?;
-        let found_key = self.get_key_from_byte_file(&mut file, key_size as usize)?;
+
+        let (found_key, value, _) = self.read_record(&mut file)?;
         if key != found_key {
             return Err(Error::other("key not found"));
         }
-        
-        let value_len = self.get_value_size_from_byte_file(&mut file)?;
-        self.get_key_from_byte_file(&mut file, value_len as usize)
+
+        Ok(value)
+    }
+
+    fn read_record(&self, file: &mut impl Read) -> Result<(String, SSTValue, u64), Error> {
+        /* Returns the key, the value and the size of the whole record */
+        let key_size = self.get_key_size_from_byte_file(file)?;
+        let key = self.get_key_from_byte_file(file, key_size as usize)?;
+
+        let value_len = self.get_value_size_from_byte_file(file)?;
+        let mut payload = vec![0u8; (value_len & !SST::FLAGS) as usize];
+        file.read_exact(&mut payload)?;
+        let value = SST::decode_value(value_len, &payload)?;
+
+        let record_len = (SST::KEY_LEN + key_size as usize + SST::VALUE_LEN + payload.len()) as u64;
+        Ok((key, value, record_len))
+    }
+
+    fn decode_value(value_len: u32, payload: &[u8]) -> Result<SSTValue, Error> {
+        if value_len == SST::TOMBSTONE {
+            return Ok(SSTValue::Tombstone);
+        }
+
+        if value_len & SST::POINTER_FLAG != 0 {
+            return Ok(SSTValue::Pointer(ValuePointer::decode(payload)?));
+        }
+
+        if value_len & SST::MERGE_FLAG != 0 {
+            return Ok(SSTValue::Merge(decode_operands(payload)?));
+        }
+
+        Ok(SSTValue::Inline(String::from_utf8_lossy(payload).to_string()))
+    }
+
+    fn value_log_segment(&self, pointer: &ValuePointer) -> SST {
+        SST::new(self.path.with_file_name(ValueLog::segment_path(pointer.segment)), Arc::clone(&self.options))
+    }
+
+    pub fn iter(&self) -> Result<SSTIter<'_>, Error> {
+        let file = OpenOptions::new().read(true).open(&self.path)?;
+        Ok(SSTIter { sst: self, reader: BufReader::new(file), offset: 0 })
+    }
+
+    fn map(&self) -> Result<Arc<Mmap>, Error> {
+        let file = OpenOptions::new().read(true).open(&self.path)?;
+        let id = file_id(&file.metadata()?);
+
+        let mut maps = MAPS.lock().unwrap();
+        if let Some(cached) = maps.get(&self.path).filter(|cached| cached.id == id) {
+            return Ok(Arc::clone(&cached.map));
+        }
+
+        // SAFETY: records are only ever appended to a table while it is being written, and a
+        // record is not referenced by an index until it has been fully written. Nothing truncates
+        // or rewrites a table in place: compaction and GC write new files and unlink the old
+        // ones, and an unlinked file stays readable through its map until the map is dropped.
+        // A new file renamed over the path has another inode, so it never reuses this map.
+        let map = Arc::new(unsafe { Mmap::map(&file) }?);
+        if maps.len() >= self.options.map_cache_size {
+            maps.clear();
+        }
+        maps.insert(self.path.clone(), CachedMap { map: Arc::clone(&map), id });
+        Ok(map)
+    }
+
+    fn locate_mapped(&self, map: &Mmap, key: &str, offset: u64) -> Result<(u32, Range<usize>), Error> {
+        /* Returns the raw value length and where the payload is in the map */
+        let out_of_bounds = || Error::new(ErrorKind::UnexpectedEof, "record is out of the table bounds");
+
+        let mut position = offset as usize;
+        let key_size = *map.get(position).ok_or_else(out_of_bounds)? as usize;
+        position += SST::KEY_LEN;
+
+        let found_key = map.get(position..position + key_size).ok_or_else(out_of_bounds)?;
+        if key.as_bytes() != found_key {
+            return Err(Error::other("key not found"));
+        }
+        position += key_size;
+
+        let value_len_buf = map.get(position..position + SST::VALUE_LEN).ok_or_else(out_of_bounds)?;
+        let value_len = u32::from_le_bytes(value_len_buf.try_into().unwrap());
+        let payload_len = (value_len & !SST::FLAGS) as usize;
+        position += SST::VALUE_LEN;
+
+        if position + payload_len > map.len() {
+            return Err(out_of_bounds());
+        }
+
+        Ok((value_len, position..position + payload_len))
+    }
+
+    pub fn get_mapped(&self, key: &str, offset: u64) -> Result<MappedValue, Error> {
+        let map = self.map()?;
+        let (value_len, payload) = self.locate_mapped(&map, key, offset)?;
+
+        if value_len == SST::TOMBSTONE {
+            return Err(Error::new(ErrorKind::NotFound, "Key was deleted"));
+        }
+
+        if value_len & SST::POINTER_FLAG != 0 {
+            let pointer = ValuePointer::decode(&map[payload])?;
+            return self.value_log_segment(&pointer).get_mapped(key, pointer.offset);
+        }
+
+        if value_len & SST::MERGE_FLAG != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "Merge operands have to be folded first"));
+        }
+
+        Ok(MappedValue { map, range: payload })
     }
 
     pub fn set(&self, key: &str, value: &str) -> Result<u64, Error> {
+        SST::check_value(value)?;
+        self.write_record(key, value.len() as u32, value.as_bytes())
+    }
+
+    pub fn set_entry(&self, key: &str, value: &SSTValue) -> Result<u64, Error> {
+        match value {
+            SSTValue::Inline(value) => self.set(key, value),
+            SSTValue::Pointer(pointer) => {
+                let value_len = SST::POINTER_FLAG | ValuePointer::ENCODED_LEN as u32;
+                self.write_record(key, value_len, &pointer.encode())
+            }
+            SSTValue::Merge(operands) => {
+                let payload = encode_operands(operands);
+                self.write_record(key, SST::MERGE_FLAG | payload.len() as u32, &payload)
+            }
+            SSTValue::Tombstone => self.write_record(key, SST::TOMBSTONE, &[]),
+        }
+    }
+
+    fn write_record(&self, key: &str, value_len: u32, payload: &[u8]) -> Result<u64, Error> {
+        if payload.len() > SST::MAX_VALUE_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Record of {} must be at most {} bytes", key, SST::MAX_VALUE_LEN)));
+        }
         let mut file = self.get_file(true)?;
 
         file.seek(SeekFrom::End(0))?;
-        let offset = file.stream_position()?;
+        let mut offset = file.stream_position()?;
+
+        /* Value log segments are SSTs as well, only tables are bound to a key order */
+        if offset == 0 && self.path.extension().is_some_and(|ext| ext == "sst") {
+            let name = self.options.comparator.name();
+            file.write_all(&(SST::COMPARATOR_KEY.len() as u8).to_le_bytes())?;
+            file.write_all(SST::COMPARATOR_KEY.as_bytes())?;
+            file.write_all(&(name.len() as u32).to_le_bytes())?;
+            file.write_all(name.as_bytes())?;
+            offset = file.stream_position()?;
+        }
 
         file.write_all(&(key.len() as u8).to_le_bytes())?;
         file.write_all(key.as_bytes())?;
-        file.write_all(&(value.len() as u32).to_le_bytes())?;
-        file.write_all(value.as_bytes())?;
-        
+        file.write_all(&value_len.to_le_bytes())?;
+        file.write_all(payload)?;
+
         Ok(offset)
     }
-}
\ No newline at end of file
+}
+
+fn encode_operands(operands: &[String]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for operand in operands {
+        payload.extend_from_slice(&(operand.len() as u32).to_le_bytes());
+        payload.extend_from_slice(operand.as_bytes());
+    }
+    payload
+}
+
+fn decode_operands(mut payload: &[u8]) -> Result<Vec<String>, Error> {
+    let mut operands = Vec::new();
+    while !payload.is_empty() {
+        let mut operand_len_buf = [0u8; SST::VALUE_LEN];
+        payload.read_exact(&mut operand_len_buf)?;
+
+        let mut operand = vec![0u8; u32::from_le_bytes(operand_len_buf) as usize];
+        payload.read_exact(&mut operand)?;
+        operands.push(String::from_utf8_lossy(&operand).to_string());
+    }
+    Ok(operands)
+}
+
+pub struct SSTIter<'a> {
+    sst: &'a SST,
+    reader: BufReader<File>,
+    offset: u64,
+}
+
+impl SSTIter<'_> {
+    /// Bytes read so far, short of the file length when a record could not be read.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl<'a> Iterator for SSTIter<'a> {
+
+    type Item = SSTRecord;
+
+    /// Skips comparator records, compacted tables hold one per subcompaction.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, value, record_len) = self.sst.read_record(&mut self.reader).ok()?;
+            let offset = self.offset;
+            self.offset += record_len;
+
+            if key != SST::COMPARATOR_KEY {
+                return Some(SSTRecord { offset, key, value });
+            }
+        }
+    }
+}
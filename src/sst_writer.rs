@@ -0,0 +1,73 @@
+use std::cmp::Ordering;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::idx::IDX;
+use crate::options::Options;
+use crate::sst::SSTValue;
+
+/// Builds a table outside of any database, for `Db::ingest_external_files`.
+///
+/// Keys have to come in ascending order of the comparator in `options`, which must be the one
+/// of the database the table is ingested into.
+pub struct SstWriter {
+    idx: IDX,
+    options: Arc<Options>,
+    last_key: Option<String>,
+    entries: usize,
+}
+
+impl SstWriter {
+    /// Writes `<path>.idx` and `<path>.sst`, neither may exist yet.
+    pub fn create(path: impl AsRef<Path>, options: Arc<Options>) -> Result<SstWriter, Error> {
+        let path = path.as_ref();
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let name = path.file_name()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Table path has no file name"))?
+            .to_string_lossy()
+            .to_string();
+
+        for extension in ["idx", "sst"] {
+            let file = dir.join(format!("{name}.{extension}"));
+            if file.exists() {
+                return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", file.display())));
+            }
+        }
+
+        Ok(SstWriter { idx: IDX::new(dir, Some(name), Arc::clone(&options)), options, last_key: None, entries: 0 })
+    }
+
+    pub fn put(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        self.add(key, &SSTValue::Inline(value.to_string()))
+    }
+
+    /// Stored as a merge record, folded into the value below it by the database's merge operator.
+    pub fn merge(&mut self, key: &str, operand: &str) -> Result<(), Error> {
+        self.add(key, &SSTValue::Merge(vec![operand.to_string()]))
+    }
+
+    fn add(&mut self, key: &str, value: &SSTValue) -> Result<(), Error> {
+        if let Some(last_key) = &self.last_key {
+            if self.options.comparator.compare(last_key, key) != Ordering::Less {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("Key {key:?} does not come after {last_key:?}")));
+            }
+        }
+
+        self.idx.set_entry(key, value)?;
+        self.last_key = Some(key.to_string());
+        self.entries += 1;
+        Ok(())
+    }
+
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// Returns the path of the `.idx` file, an empty table is refused.
+    pub fn finish(self) -> Result<PathBuf, Error> {
+        if self.entries == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Table has no entries"));
+        }
+        Ok(self.idx.path().to_path_buf())
+    }
+}
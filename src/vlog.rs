@@ -0,0 +1,120 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use crate::db::Db;
+use crate::options::Options;
+use crate::sst::SST;
+
+/// Where a separated value lives inside the value log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValuePointer {
+    pub segment: u64,
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl ValuePointer {
+    pub const ENCODED_LEN: usize = 20;
+
+    pub fn encode(&self) -> [u8; ValuePointer::ENCODED_LEN] {
+        let mut buf = [0u8; ValuePointer::ENCODED_LEN];
+        buf[..8].copy_from_slice(&self.segment.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        buf[16..].copy_from_slice(&self.len.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<ValuePointer, Error> {
+        if buf.len() != ValuePointer::ENCODED_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "Malformed value pointer"));
+        }
+
+        Ok(ValuePointer {
+            segment: u64::from_le_bytes(buf[..8].try_into().unwrap()),
+            offset: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+            len: u32::from_le_bytes(buf[16..].try_into().unwrap()),
+        })
+    }
+}
+
+/// Append-only log of values too large to be copied around by compaction.
+///
+/// Segments are `<id>.vlog` files that use the same record layout as an `.sst`,
+/// so a pointer is resolved with a plain `SST::get`.
+pub struct ValueLog {
+    dir: PathBuf,
+    options: Arc<Options>,
+    active: Mutex<u64>,
+}
+
+impl ValueLog {
+    const EXTENSION: &'static str = "vlog";
+
+    pub fn open(dir: &Path, options: Arc<Options>) -> ValueLog {
+        let mut value_log = ValueLog { dir: dir.to_path_buf(), options, active: Mutex::new(1) };
+        if let Some(active) = value_log.segments().last() {
+            value_log.active = Mutex::new(*active);
+        }
+        value_log
+    }
+
+    pub fn segment_path(segment: u64) -> PathBuf {
+        Path::new(&format!("{}.{}", segment, Self::EXTENSION)).to_path_buf()
+    }
+
+    /// Ids of every segment on disk, oldest first.
+    pub fn segments(&self) -> Vec<u64> {
+        let mut segments = fs::read_dir(&self.dir)
+            .unwrap()
+            .filter_map(|res| res.ok())
+            .map(|dir_entry| dir_entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == Self::EXTENSION))
+            .filter_map(|path| path.file_stem()?.to_str()?.parse::<u64>().ok())
+            .collect::<Vec<_>>();
+        segments.sort();
+        segments
+    }
+
+    pub fn segment(&self, segment: u64) -> SST {
+        SST::new(self.dir.join(Self::segment_path(segment)), Arc::clone(&self.options))
+    }
+
+    /// The segment new values are appended to, segments below it are sealed.
+    pub fn active_segment(&self) -> u64 {
+        *self.active.lock().unwrap()
+    }
+
+    /// Moves the value to the log if it is over the threshold.
+    pub fn separate(&self, key: &str, value: &str) -> Result<Option<ValuePointer>, Error> {
+        match self.options.value_log_threshold {
+            Some(threshold) if value.len() > threshold => {}
+            _ => return Ok(None),
+        }
+
+        let mut active = self.active.lock().map_err(|_| Error::other("value log lock is poisoned"))?;
+        let pointer_segment = *active;
+        let segment = self.segment(pointer_segment);
+        let offset = segment.set(key, value)?;
+
+        if fs::metadata(&segment.path)?.len() >= self.options.value_log_segment_size {
+            *active += 1;
+        }
+
+        Ok(Some(ValuePointer { segment: pointer_segment, offset, len: value.len() as u32 }))
+    }
+}
+
+pub fn collect_garbage(db: Arc<Db>) {
+    while !db.wait_for_shutdown(db.options().value_log_gc_interval) {
+        for column_family in db.column_families() {
+            tracing::debug!("Checking value log segments of {} to collect", column_family.name());
+
+            match column_family.collect_value_log_garbage() {
+                Ok(0) => {}
+                Ok(rewritten) => tracing::info!("Value log garbage collected, {rewritten} live values rewritten"),
+                Err(e) => tracing::error!("Failed to collect value log garbage: {}", e),
+            }
+        }
+    }
+}
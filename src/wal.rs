@@ -0,0 +1,294 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use crate::write_batch::{BatchOp, WriteBatch};
+
+/// Log of the writes still in memtables, one for all column families of a database.
+///
+/// Every write is appended as one record before it reaches a memtable, a batch as a single
+/// record, and `Db::open` replays what the tables don't hold yet. Records are written but not
+/// synced, so they survive the process crashing, not the machine.
+///
+/// The log is split into `<n>.wal` segments in the database directory. A new one starts with
+/// every memtable switch, and a segment is removed once no memtable has writes in it.
+pub struct Wal {
+    dir: PathBuf,
+    state: Mutex<WalState>,
+}
+
+struct WalState {
+    segment: u64,
+    file: File,
+    written: u64,
+    /// Oldest segment still on disk.
+    oldest: u64,
+    /// Per family, oldest memtable first, the first segment each memtable has writes in.
+    memtables: HashMap<String, VecDeque<Option<u64>>>,
+}
+
+const OPS: u8 = 0;
+const DROP_FAMILY: u8 = 1;
+
+const PUT: u8 = 0;
+const DELETE: u8 = 1;
+const MERGE: u8 = 2;
+
+impl Wal {
+    pub fn segment_path(segment: u64) -> PathBuf {
+        PathBuf::from(format!("{segment}.wal"))
+    }
+
+    /// Segments in `dir`, oldest first.
+    pub fn segments(dir: &Path) -> Result<Vec<u64>, Error> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "wal") {
+                if let Some(segment) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) {
+                    segments.push(segment);
+                }
+            }
+        }
+        segments.sort();
+        Ok(segments)
+    }
+
+    /// Starts a new segment after the ones in `dir`, which are left for `replay`.
+    pub(crate) fn open(dir: &Path) -> Result<Wal, Error> {
+        let existing = Self::segments(dir)?;
+        let segment = existing.last().map_or(0, |last| last + 1);
+        let file = OpenOptions::new().create(true).append(true).open(dir.join(Self::segment_path(segment)))?;
+
+        Ok(Wal {
+            dir: dir.to_path_buf(),
+            state: Mutex::new(WalState {
+                segment,
+                file,
+                written: 0,
+                oldest: existing.first().copied().unwrap_or(segment),
+                memtables: HashMap::new(),
+            }),
+        })
+    }
+
+    /// The writes of the segments from before `open`, a batch per record.
+    ///
+    /// A record cut short by a crash ends the replay. Writes to a family from before it was
+    /// dropped are left out, a family created later with the same name starts empty.
+    pub(crate) fn replay(&self) -> Result<Vec<WriteBatch>, Error> {
+        let (oldest, active) = {
+            let state = self.state.lock().unwrap();
+            (state.oldest, state.segment)
+        };
+
+        let mut batches = Vec::new();
+        for segment in Self::segments(&self.dir)?.into_iter().filter(|segment| (oldest..active).contains(segment)) {
+            let content = fs::read(self.dir.join(Self::segment_path(segment)))?;
+            let mut rest = content.as_slice();
+            while let Some((record, next)) = split_record(rest) {
+                rest = next;
+                match decode_record(record)? {
+                    Record::Ops(ops) => {
+                        let mut batch = WriteBatch::new();
+                        for op in ops {
+                            batch.push(op);
+                        }
+                        batches.push(batch);
+                    }
+                    Record::DropFamily(name) => {
+                        for batch in batches.iter_mut() {
+                            let kept = batch.ops().iter().filter(|op| op.family() != name).cloned().collect::<Vec<_>>();
+                            *batch = WriteBatch::new();
+                            for op in kept {
+                                batch.push(op);
+                            }
+                        }
+                    }
+                }
+            }
+            if !rest.is_empty() {
+                tracing::warn!("Write-ahead log segment {} ends with {} unreadable bytes", segment, rest.len());
+                break;
+            }
+        }
+        Ok(batches)
+    }
+
+    pub(crate) fn add_family(&self, name: &str) {
+        self.state.lock().unwrap().memtables.insert(name.to_string(), VecDeque::from([None]));
+    }
+
+    /// Keeps the writes to the family from being replayed, and its memtables from holding segments.
+    pub(crate) fn drop_family(&self, name: &str) -> Result<(), Error> {
+        let mut record = vec![DROP_FAMILY];
+        put_short(&mut record, name)?;
+
+        let mut state = self.state.lock().unwrap();
+        state.write(&record)?;
+        state.memtables.remove(name);
+        self.remove_obsolete(&mut state);
+        Ok(())
+    }
+
+    /// Appends the ops as one record, before they are applied to the memtables.
+    pub(crate) fn append(&self, ops: &[BatchOp]) -> Result<(), Error> {
+        let mut record = vec![OPS];
+        for op in ops {
+            let (kind, value) = match op {
+                BatchOp::Put { value, .. } => (PUT, value.as_str()),
+                BatchOp::Delete { .. } => (DELETE, ""),
+                BatchOp::Merge { operand, .. } => (MERGE, operand.as_str()),
+            };
+            record.push(kind);
+            put_short(&mut record, op.family())?;
+            put_short(&mut record, op.key())?;
+            record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            record.extend_from_slice(value.as_bytes());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.write(&record)?;
+        let segment = state.segment;
+        for op in ops {
+            if let Some(first) = state.memtables.get_mut(op.family()).and_then(|memtables| memtables.back_mut()) {
+                first.get_or_insert(segment);
+            }
+        }
+        Ok(())
+    }
+
+    /// Called with the memtable of the family locked, right after it was switched.
+    pub(crate) fn memtable_switched(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.written > 0 {
+            let segment = state.segment + 1;
+            match OpenOptions::new().create(true).append(true).open(self.dir.join(Self::segment_path(segment))) {
+                Ok(file) => {
+                    state.segment = segment;
+                    state.file = file;
+                    state.written = 0;
+                }
+                // Writes go on in the current segment, it is only removed later
+                Err(e) => tracing::warn!("Failed to start write-ahead log segment {}: {}", segment, e),
+            }
+        }
+        if let Some(memtables) = state.memtables.get_mut(name) {
+            memtables.push_back(None);
+        }
+    }
+
+    /// Called once the oldest immutable memtable of the family is in a table.
+    pub(crate) fn memtable_flushed(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(memtables) = state.memtables.get_mut(name) {
+            memtables.pop_front();
+        }
+        self.remove_obsolete(&mut state);
+    }
+
+    /// Removes the segments before the oldest one a memtable has writes in.
+    fn remove_obsolete(&self, state: &mut WalState) {
+        let needed = state.memtables.values().flatten().flatten().min().copied().unwrap_or(state.segment);
+        while state.oldest < needed.min(state.segment) {
+            let path = self.dir.join(Self::segment_path(state.oldest));
+            if let Err(e) = fs::remove_file(&path) {
+                if e.kind() != ErrorKind::NotFound {
+                    tracing::warn!("Failed to remove write-ahead log segment {}: {}", path.display(), e);
+                    return;
+                }
+            }
+            state.oldest += 1;
+        }
+    }
+
+    /// Removes every segment no memtable has writes in, see `Db::open`.
+    pub(crate) fn remove_replayed(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.remove_obsolete(&mut state);
+    }
+}
+
+impl WalState {
+    fn write(&mut self, record: &[u8]) -> Result<(), Error> {
+        /* Length and checksum first, so a torn record is told apart from a complete one */
+        let mut frame = Vec::with_capacity(record.len() + 8);
+        frame.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(record).to_le_bytes());
+        frame.extend_from_slice(record);
+        self.file.write_all(&frame)?;
+        self.written += frame.len() as u64;
+        Ok(())
+    }
+}
+
+enum Record {
+    Ops(Vec<BatchOp>),
+    DropFamily(String),
+}
+
+fn put_short(record: &mut Vec<u8>, value: &str) -> Result<(), Error> {
+    let len = u8::try_from(value.len()).map_err(|_| Error::new(ErrorKind::InvalidInput, format!("{value:?} is too long")))?;
+    record.push(len);
+    record.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+/// The record at the start of `content` and what follows it, `None` when it is incomplete or damaged.
+fn split_record(content: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_le_bytes(content.get(..4)?.try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(content.get(4..8)?.try_into().unwrap());
+    let record = content.get(8..8 + len)?;
+    (crc32fast::hash(record) == crc).then(|| (record, &content[8 + len..]))
+}
+
+/// Reads the fields of a record front to back.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let (taken, rest) = self.0.split_at_checked(len)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Write-ahead log record is cut short"))?;
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn short_string(&mut self) -> Result<String, Error> {
+        let len = self.byte()? as usize;
+        self.string(len)
+    }
+
+    fn string(&mut self, len: usize) -> Result<String, Error> {
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+fn decode_record(record: &[u8]) -> Result<Record, Error> {
+    let mut fields = Fields(record);
+    match fields.byte()? {
+        DROP_FAMILY => Ok(Record::DropFamily(fields.short_string()?)),
+        OPS => {
+            let mut ops = Vec::new();
+            while !fields.0.is_empty() {
+                let kind = fields.byte()?;
+                let family = fields.short_string()?;
+                let key = fields.short_string()?;
+                let len = u32::from_le_bytes(fields.take(4)?.try_into().unwrap()) as usize;
+                let value = fields.string(len)?;
+                ops.push(match kind {
+                    PUT => BatchOp::Put { family, key, value },
+                    DELETE => BatchOp::Delete { family, key },
+                    MERGE => BatchOp::Merge { family, key, operand: value },
+                    kind => return Err(Error::new(ErrorKind::InvalidData, format!("Unknown write-ahead log op {kind}"))),
+                });
+            }
+            Ok(Record::Ops(ops))
+        }
+        kind => Err(Error::new(ErrorKind::InvalidData, format!("Unknown write-ahead log record {kind}"))),
+    }
+}
@@ -0,0 +1,65 @@
+/// A write to one key of a column family, see `WriteBatch`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchOp {
+    Put { family: String, key: String, value: String },
+    Delete { family: String, key: String },
+    Merge { family: String, key: String, operand: String },
+}
+
+impl BatchOp {
+    pub fn family(&self) -> &str {
+        match self {
+            BatchOp::Put { family, .. } | BatchOp::Delete { family, .. } | BatchOp::Merge { family, .. } => family,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        match self {
+            BatchOp::Put { key, .. } | BatchOp::Delete { key, .. } | BatchOp::Merge { key, .. } => key,
+        }
+    }
+}
+
+/// Writes to any number of column families, applied by `Db::write` all together or not at all.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, family: &str, key: &str, value: &str) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Put { family: family.to_string(), key: key.to_string(), value: value.to_string() });
+        self
+    }
+
+    pub fn delete(&mut self, family: &str, key: &str) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Delete { family: family.to_string(), key: key.to_string() });
+        self
+    }
+
+    pub fn merge(&mut self, family: &str, key: &str, operand: &str) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Merge { family: family.to_string(), key: key.to_string(), operand: operand.to_string() });
+        self
+    }
+
+    pub fn push(&mut self, op: BatchOp) -> &mut WriteBatch {
+        self.ops.push(op);
+        self
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
@@ -1,4 +1,6 @@
+use std::sync::Arc;
 use sstable::avl::AVLTree;
+use sstable::comparator::{Comparator, NumericComparator, ReverseBytewiseComparator};
 
 #[test]
 fn valid_tree() {
@@ -99,4 +101,61 @@ fn valid_tree() {
     let root_right = root.right.as_ref().unwrap();
     assert_eq!(root_right.key, fifth_pair.0);
     assert_eq!(root_right.height, 1);
-}
\ No newline at end of file
+}
+
+#[test]
+fn custom_comparator() {
+    let mut tree = AVLTree::with_comparator(Arc::new(NumericComparator));
+    tree.set("10", "ten");
+    tree.set("9", "nine");
+    tree.set("100", "hundred");
+
+    // "9" goes before "10" only in numeric order
+    let root = tree.root.as_ref().unwrap();
+    assert_eq!(root.key, "10");
+    assert_eq!(root.left.as_ref().unwrap().key, "9");
+    assert_eq!(root.right.as_ref().unwrap().key, "100");
+    assert_eq!(tree.get("9").unwrap().value, "nine");
+
+    // Leading zeros make another key of the same number
+    tree.set("009", "padded");
+    assert_eq!(tree.get("9").unwrap().value, "nine");
+    assert_eq!(tree.get("009").unwrap().value, "padded");
+    assert_eq!(NumericComparator.compare("009", "9"), std::cmp::Ordering::Less);
+
+    let mut tree = AVLTree::with_comparator(Arc::new(ReverseBytewiseComparator));
+    tree.set("b", "second");
+    tree.set("a", "first");
+    tree.set("c", "third");
+
+    let root = tree.root.as_ref().unwrap();
+    assert_eq!(root.left.as_ref().unwrap().key, "c");
+    assert_eq!(root.right.as_ref().unwrap().key, "a");
+
+    tree.unset("c");
+    assert!(tree.get("c").is_none());
+    assert_eq!(tree.get("a").unwrap().value, "first");
+}
+
+#[test]
+fn tracks_size_and_count() {
+    let mut tree = AVLTree::new();
+    tree.set("a", "1");
+    tree.set("b", "22");
+    let size = tree.size();
+    assert_eq!(tree.count(), 2);
+
+    tree.set("b", "2");
+    assert_eq!(tree.size(), size - 1);
+    assert_eq!(tree.count(), 2);
+
+    tree.merge("c", "333");
+    assert_eq!(tree.count(), 3);
+
+    tree.unset("a");
+    tree.unset("b");
+    tree.unset("c");
+    tree.unset("missing");
+    assert_eq!(tree.size(), 0);
+    assert_eq!(tree.count(), 0);
+}
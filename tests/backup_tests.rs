@@ -0,0 +1,87 @@
+use std::fs;
+use std::path::PathBuf;
+use std::io::ErrorKind;
+use std::thread;
+use sstable::backup::BackupEngine;
+use sstable::db::Db;
+use sstable::options::Options;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sstable_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn backups_are_incremental_and_restorable() {
+    let dir = temp_dir("backup_source");
+    let backup_dir = temp_dir("backups");
+    let restored = temp_dir("backup_restored");
+
+    let db = Db::open(&dir, Options::default()).unwrap();
+    let sessions = db.create_column_family("sessions", Options::default()).unwrap();
+    db.set("a", "1").unwrap();
+    sessions.set("token", "x").unwrap();
+
+    let engine = BackupEngine::open(&backup_dir).unwrap();
+    let first = engine.create_backup(&db).unwrap();
+    let shared = || fs::read_dir(backup_dir.join("shared")).unwrap().count();
+    // A table per family and the manifest
+    assert_eq!(shared(), 5);
+
+    db.set("a", "2").unwrap();
+    let second = engine.create_backup(&db).unwrap();
+    assert_eq!(second.id, first.id + 1);
+    // Only the new table is copied
+    assert_eq!(shared(), 7);
+
+    engine.restore(first.id, &restored).unwrap();
+    let copy = Db::open(&restored, Options::default()).unwrap();
+    assert_eq!(copy.get("a").unwrap().as_deref(), Some("1"));
+    assert_eq!(copy.column_family("sessions").unwrap().get("token").unwrap().as_deref(), Some("x"));
+    drop(copy);
+    fs::remove_dir_all(&restored).unwrap();
+
+    // Purging the first backup keeps the files the second one shares with it
+    assert_eq!(engine.purge_old_backups(1).unwrap(), 1);
+    assert_eq!(engine.list_backups().unwrap().iter().map(|backup| backup.id).collect::<Vec<_>>(), [second.id]);
+    engine.restore(second.id, &restored).unwrap();
+    assert_eq!(Db::open(&restored, Options::default()).unwrap().get("a").unwrap().as_deref(), Some("2"));
+    fs::remove_dir_all(&restored).unwrap();
+
+    // A damaged file fails the restore and leaves nothing behind
+    let damaged = fs::read_dir(backup_dir.join("shared")).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "sst"))
+        .unwrap();
+    fs::write(&damaged, b"damaged").unwrap();
+    assert_eq!(engine.restore(second.id, &restored).unwrap_err().kind(), ErrorKind::InvalidData);
+    assert!(!restored.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&backup_dir).unwrap();
+}
+
+#[test]
+fn concurrent_backups_get_their_own_ids() {
+    let dir = temp_dir("backup_concurrent_source");
+    let backup_dir = temp_dir("backups_concurrent");
+
+    let db = Db::open(&dir, Options::default()).unwrap();
+    db.set("a", "1").unwrap();
+
+    let mut ids = thread::scope(|scope| {
+        let handles = (0..4).map(|_| scope.spawn(|| {
+            BackupEngine::open(&backup_dir).unwrap().create_backup(&db).unwrap().id
+        })).collect::<Vec<_>>();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect::<Vec<_>>()
+    });
+    ids.sort();
+    assert_eq!(ids, [1, 2, 3, 4]);
+
+    // No checkpoint is left in the database directory
+    assert!(fs::read_dir(&dir).unwrap().all(|entry| !entry.unwrap().file_name().to_string_lossy().starts_with(".backup")));
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&backup_dir).unwrap();
+}
@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+use sstable::compaction_strategy::{
+    CompactionStrategy, LeveledCompaction, SizeTieredCompaction, TableInfo, UniversalCompaction,
+};
+use sstable::options::Options;
+
+const MB: u64 = 1024 * 1024;
+
+/// Synthetic tables, oldest first, sizes in MB.
+fn tables(sizes: &[u64]) -> Vec<TableInfo> {
+    sizes.iter().enumerate().map(|(i, size)| TableInfo {
+        path: PathBuf::from(format!("{}.idx", 100 + i)),
+        timestamp: 100 + i as u64,
+        generation: 0,
+        size: size * MB,
+        busy: false,
+    }).collect()
+}
+
+#[test]
+fn leveled_merges_small_neighbours() {
+    let options = Options { compaction_size_limit: 5 * MB, ..Options::default() };
+
+    assert_eq!(LeveledCompaction.pick(&tables(&[1, 1, 1]), &options), [vec![0, 1]]);
+    assert_eq!(LeveledCompaction.pick(&tables(&[1, 1, 1, 1, 1, 1]), &options), [vec![0, 1], vec![2, 3]]);
+    // A large table in between is never merged across
+    assert_eq!(LeveledCompaction.pick(&tables(&[1, 8, 1, 1, 1]), &options), [vec![2, 3]]);
+    assert!(LeveledCompaction.pick(&tables(&[1, 1]), &options).is_empty());
+
+    let mut busy = tables(&[1, 1, 1, 1, 1, 1]);
+    busy[1].busy = true;
+    assert_eq!(LeveledCompaction.pick(&busy, &options), [vec![2, 3], vec![4, 5]]);
+}
+
+#[test]
+fn size_tiered_merges_runs_of_similar_size() {
+    let options = Options::default();
+    let strategy = SizeTieredCompaction::default();
+
+    assert_eq!(strategy.pick(&tables(&[64, 10, 10, 12, 9, 1]), &options), [vec![1, 2, 3, 4]]);
+    assert!(strategy.pick(&tables(&[64, 10, 10, 12, 1]), &options).is_empty());
+    assert_eq!(
+        strategy.pick(&tables(&[1, 1, 1, 1, 50, 40, 45, 60]), &options),
+        [vec![0, 1, 2, 3], vec![4, 5, 6, 7]],
+    );
+
+    let limited = SizeTieredCompaction { max_threshold: 4, ..SizeTieredCompaction::default() };
+    assert_eq!(limited.pick(&tables(&[1, 1, 1, 1, 1, 1]), &options), [vec![0, 1, 2, 3]]);
+
+    let mut busy = tables(&[10, 10, 10, 10, 10]);
+    busy[2].busy = true;
+    assert!(strategy.pick(&busy, &options).is_empty());
+}
+
+#[test]
+fn universal_bounds_size_amplification() {
+    let options = Options::default();
+    let strategy = UniversalCompaction::default();
+
+    // Newer runs take more than twice the oldest one, everything is merged
+    assert_eq!(strategy.pick(&tables(&[10, 8, 8, 8]), &options), [vec![0, 1, 2, 3]]);
+    // Otherwise the newest runs are merged while the next one is no larger than all of them
+    assert_eq!(strategy.pick(&tables(&[100, 40, 2, 1, 1]), &options), [vec![2, 3, 4]]);
+    assert!(strategy.pick(&tables(&[100, 40, 20, 1]), &options).is_empty());
+    assert!(strategy.pick(&tables(&[1, 1, 1]), &options).is_empty());
+
+    let narrow = UniversalCompaction { max_merge_width: 2, ..UniversalCompaction::default() };
+    assert_eq!(narrow.pick(&tables(&[100, 40, 1, 1, 1]), &options), [vec![3, 4]]);
+}
@@ -0,0 +1,234 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use sstable::compaction_filter::{CompactionFilter, Decision};
+use sstable::compaction_strategy::UniversalCompaction;
+use sstable::db::{Db, MAX_FINISHED_COMPACTIONS};
+use sstable::idx::IDX;
+use sstable::merge::U64AddOperator;
+use sstable::options::Options;
+use sstable::sst::SSTValue;
+use sstable::vlog::ValueLog;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sstable_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn table_names(dir: &Path) -> Vec<String> {
+    let mut names = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "idx"))
+        .map(|path| path.file_stem().unwrap().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn subcompactions_publish_one_table() {
+    let dir = temp_dir("subcompactions");
+    let options = Arc::new(Options { max_subcompactions: 3, ..Options::default() });
+
+    for (table, version) in [("100", "a"), ("101", "b"), ("102", "c"), ("103", "d")] {
+        let idx = IDX::new(&dir, Some(table.to_string()), Arc::clone(&options));
+        for key in 0..30 {
+            // Every table overwrites the keys of the one before, and adds a key of its own
+            idx.set_key(&format!("key{key:02}"), &format!("{version}{key}")).unwrap();
+        }
+        idx.set_key(&format!("only{table}"), version).unwrap();
+    }
+
+    IDX::compact(&dir, &options);
+    // Two tables are always left, the inputs of each round are neighbours
+    assert_eq!(table_names(&dir), ["100_2", "103"]);
+    assert!(!fs::read_dir(&dir).unwrap().any(|entry| entry.unwrap().path().is_dir()));
+
+    let merged = IDX::from(dir.join("100_2.idx"), Arc::clone(&options)).unwrap();
+    let keys = merged.entries().unwrap().map(|entry| entry.unwrap().0).collect::<Vec<_>>();
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(keys.len(), 33);
+    assert_eq!(keys, sorted);
+
+    for (key, value) in [("key07", "d7"), ("only100", "a"), ("only101", "b"), ("only103", "d")] {
+        let found = IDX::search_key_in_all_files(&dir, key, Vec::new(), &options).unwrap().unwrap();
+        assert_eq!(found.value, value);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn damaged_tables_fail_the_compaction() {
+    let dir = temp_dir("damaged_compaction");
+    let options = Arc::new(Options { max_subcompactions: 2, ..Options::default() });
+
+    let mut inputs = Vec::new();
+    for table in ["100", "101"] {
+        let idx = IDX::new(&dir, Some(table.to_string()), Arc::clone(&options));
+        for key in 0..20 {
+            idx.set_key(&format!("key{key:02}"), table).unwrap();
+        }
+        inputs.push(idx.path().to_path_buf());
+    }
+    // The records of the newer table are cut off
+    fs::File::options().write(true).open(dir.join("101.sst")).unwrap().set_len(40).unwrap();
+
+    assert!(IDX::compact_tables(&dir, &inputs, &options).is_err());
+    // Nothing was published and the inputs are left in place
+    assert_eq!(table_names(&dir), ["100", "101"]);
+    assert!(!fs::read_dir(&dir).unwrap().any(|entry| entry.unwrap().path().is_dir()));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn universal_merges_every_table_at_once() {
+    let dir = temp_dir("universal");
+    let options = Arc::new(Options { compaction_strategy: Arc::new(UniversalCompaction::default()), ..Options::default() });
+
+    for (table, version) in [("200", "a"), ("201", "b"), ("202", "c"), ("203", "d")] {
+        let idx = IDX::new(&dir, Some(table.to_string()), Arc::clone(&options));
+        idx.set_key(&format!("only{table}"), version).unwrap();
+        idx.set_key("shared", version).unwrap();
+    }
+
+    IDX::compact(&dir, &options);
+    assert_eq!(table_names(&dir), ["200_1"]);
+
+    for (key, value) in [("shared", "d"), ("only200", "a"), ("only202", "c")] {
+        let found = IDX::search_key_in_all_files(&dir, key, Vec::new(), &options).unwrap().unwrap();
+        assert_eq!(found.value, value);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn compact_range_merges_overlapping_tables() {
+    let dir = temp_dir("compact_range");
+    let db = Db::open(&dir, Options::default()).unwrap();
+    let options = Arc::clone(db.options());
+
+    for (table, keys) in [("300", ["a", "b"]), ("301", ["c", "d"]), ("302", ["e", "f"]), ("303", ["x", "y"])] {
+        let idx = IDX::new(&dir, Some(table.to_string()), Arc::clone(&options));
+        for key in keys {
+            idx.set_key(key, table).unwrap();
+        }
+    }
+
+    // "b" and "e" sit in the first and third table, the second one is merged with them
+    let progress = db.compact_range(Some("b"), Some("e")).unwrap();
+    assert_eq!(progress.tables, 3);
+    assert_eq!(progress.compacted_tables, 3);
+    assert!(progress.done);
+    assert_eq!(table_names(&dir), ["300_1", "303"]);
+    assert_eq!(db.get("d").unwrap().as_deref(), Some("301"));
+
+    // Nothing overlaps
+    assert_eq!(db.compact_range(Some("g"), Some("h")).unwrap().tables, 0);
+    assert_eq!(table_names(&dir), ["300_1", "303"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn finished_manual_compactions_are_evicted() {
+    let dir = temp_dir("manual_compactions");
+    let db = Db::open(&dir, Options::default()).unwrap();
+
+    let mut ids = Vec::new();
+    for _ in 0..MAX_FINISHED_COMPACTIONS + 1 {
+        let id = db.start_compact_range("default", None, None).unwrap();
+        while !db.manual_compaction(id).unwrap().done {
+            thread::sleep(Duration::from_millis(1));
+        }
+        ids.push(id);
+    }
+
+    // Starting the last one evicted the oldest finished compaction
+    assert!(db.manual_compaction(ids[0]).is_none());
+    assert!(ids[1..].iter().all(|id| db.manual_compaction(*id).is_some()));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Removes keys starting with `drop`, doubles values of keys starting with `change`.
+#[derive(Default)]
+struct RecordingFilter {
+    seen: Mutex<Vec<(u32, String, String)>>,
+}
+
+impl CompactionFilter for RecordingFilter {
+    fn name(&self) -> &str {
+        "recording"
+    }
+
+    fn filter(&self, level: u32, key: &str, value: &str) -> Decision {
+        self.seen.lock().unwrap().push((level, key.to_string(), value.to_string()));
+        if key.starts_with("drop") {
+            Decision::Remove
+        } else if key.starts_with("change") {
+            Decision::ChangeValue(format!("{value}{value}"))
+        } else {
+            Decision::Keep
+        }
+    }
+}
+
+#[test]
+fn compaction_filter_decisions() {
+    let dir = temp_dir("compaction_filter");
+    let filter = Arc::new(RecordingFilter::default());
+    let options = Arc::new(Options {
+        compaction_filter: Some(filter.clone()),
+        merge_operator: Some(Arc::new(U64AddOperator)),
+        value_log_threshold: Some(8),
+        ..Options::default()
+    });
+
+    // Left out of the compaction below
+    IDX::new(&dir, Some("400".to_string()), Arc::clone(&options)).set_key("drop1", "old").unwrap();
+
+    let older = IDX::new(&dir, Some("401".to_string()), Arc::clone(&options));
+    older.set_key("counter", "1").unwrap();
+    older.set_key("drop1", "newer").unwrap();
+    older.set_key("drop2", "only").unwrap();
+
+    let newer = IDX::new(&dir, Some("402".to_string()), Arc::clone(&options));
+    let pointer = ValueLog::open(&dir, Arc::clone(&options)).separate("keep", "a separated value").unwrap().unwrap();
+    newer.set_entry("change", &SSTValue::Inline("ab".to_string())).unwrap();
+    newer.set_entry("counter", &SSTValue::Merge(vec!["2".to_string()])).unwrap();
+    newer.set_entry("keep", &SSTValue::Pointer(pointer)).unwrap();
+    newer.set_entry("pending", &SSTValue::Merge(vec!["5".to_string()])).unwrap();
+
+    let inputs = [dir.join("401.idx"), dir.join("402.idx")];
+    IDX::compact_tables(&dir, &inputs, &options).unwrap();
+
+    // Values are folded and resolved first, operands without a value below them are skipped
+    let mut seen = filter.seen.lock().unwrap().clone();
+    seen.sort();
+    assert_eq!(seen, [
+        (1, "change".to_string(), "ab".to_string()),
+        (1, "counter".to_string(), "3".to_string()),
+        (1, "drop1".to_string(), "newer".to_string()),
+        (1, "drop2".to_string(), "only".to_string()),
+        (1, "keep".to_string(), "a separated value".to_string()),
+    ]);
+
+    let read = |key| IDX::search_key_in_all_files(&dir, key, Vec::new(), &options).unwrap().map(|found| found.value);
+    assert_eq!(read("change").as_deref(), Some("abab"));
+    assert_eq!(read("counter").as_deref(), Some("3"));
+    assert_eq!(read("keep").as_deref(), Some("a separated value"));
+    assert_eq!(read("pending").as_deref(), Some("5"));
+    assert_eq!(read("drop2"), None);
+    // Without tombstones the value in the table left out shows up again
+    assert_eq!(read("drop1").as_deref(), Some("old"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,538 @@
+use std::fs;
+use std::path::PathBuf;
+use std::io::{ErrorKind, Write};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use sstable::comparator::NumericComparator;
+use sstable::db::{Db, DEFAULT_COLUMN_FAMILY};
+use sstable::idx::IDX;
+use sstable::merge::U64AddOperator;
+use sstable::metrics::StallReason;
+use sstable::migrate;
+use sstable::options::Options;
+use sstable::sst_writer::SstWriter;
+use sstable::vlog::ValueLog;
+use sstable::wal::Wal;
+use sstable::write_batch::WriteBatch;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sstable_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn column_families_are_separate() {
+    let dir = temp_dir("column_families");
+
+    let db = Db::open(&dir, Options::default()).unwrap();
+    let sessions = db.create_column_family("sessions", Options::default()).unwrap();
+    assert!(db.create_column_family("sessions", Options::default()).is_err());
+    assert!(db.create_column_family("../escape", Options::default()).is_err());
+
+    db.set("key", "default").unwrap();
+    sessions.set("key", "session").unwrap();
+
+    // Flushed tables of a family only land in its own directory
+    let tree = sessions.memtable().get_instance().read().unwrap();
+    IDX::new(sessions.path(), None, Arc::clone(sessions.options())).fill_from_avl(&tree, sessions.value_log()).unwrap();
+    drop(tree);
+    sessions.memtable().get_instance().write().unwrap().clear();
+
+    assert_eq!(db.get("key").unwrap().as_deref(), Some("default"));
+    assert_eq!(sessions.get("key").unwrap().as_deref(), Some("session"));
+    drop(sessions);
+    drop(db);
+
+    let db = Db::open(&dir, Options::default()).unwrap();
+    let names = db.column_families().iter().map(|cf| cf.name().to_string()).collect::<Vec<_>>();
+    assert_eq!(names, [DEFAULT_COLUMN_FAMILY, "sessions"]);
+    assert_eq!(db.column_family("sessions").unwrap().get("key").unwrap().as_deref(), Some("session"));
+
+    assert!(db.drop_column_family(DEFAULT_COLUMN_FAMILY).is_err());
+    db.drop_column_family("sessions").unwrap();
+    assert!(db.column_family("sessions").is_none());
+    assert!(!dir.join("sessions").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn write_batch_is_all_or_nothing() {
+    let dir = temp_dir("write_batch");
+
+    let options = || Options { merge_operator: Some(Arc::new(U64AddOperator)), ..Options::default() };
+    let db = Db::open(&dir, options()).unwrap();
+    let audit = db.create_column_family("audit", options()).unwrap();
+    db.set("counter", "1").unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch.put(DEFAULT_COLUMN_FAMILY, "user", "alice")
+        .merge(DEFAULT_COLUMN_FAMILY, "counter", "2")
+        .merge("audit", "events", "1")
+        .merge("audit", "events", "1");
+    db.write(&batch).unwrap();
+
+    assert_eq!(db.get("user").unwrap().as_deref(), Some("alice"));
+    assert_eq!(db.get("counter").unwrap().as_deref(), Some("3"));
+    assert_eq!(audit.get("events").unwrap().as_deref(), Some("2"));
+
+    // The failing merge comes last, the put before it must not be applied either
+    let mut batch = WriteBatch::new();
+    batch.put("audit", "events", "0").merge(DEFAULT_COLUMN_FAMILY, "user", "1");
+    assert!(db.write(&batch).is_err());
+    assert_eq!(audit.get("events").unwrap().as_deref(), Some("2"));
+
+    let mut batch = WriteBatch::new();
+    batch.put("missing", "key", "value");
+    assert!(db.write(&batch).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn delete_then_merge_folds_onto_nothing() {
+    let dir = temp_dir("delete_then_merge");
+
+    let db = Db::open(&dir, Options { merge_operator: Some(Arc::new(U64AddOperator)), ..Options::default() }).unwrap();
+    db.set("counter", "5").unwrap();
+    db.flush(true).unwrap();
+
+    // The tombstone hides the table value from the operand
+    let mut batch = WriteBatch::new();
+    batch.delete(DEFAULT_COLUMN_FAMILY, "counter").merge(DEFAULT_COLUMN_FAMILY, "counter", "1");
+    db.write(&batch).unwrap();
+    assert_eq!(db.get("counter").unwrap().as_deref(), Some("1"));
+
+    db.set("other", "5").unwrap();
+    db.flush(true).unwrap();
+    db.delete("other").unwrap();
+    db.merge("other", "2").unwrap();
+    assert_eq!(db.get("other").unwrap().as_deref(), Some("2"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn deletes_hide_older_values() {
+    let dir = temp_dir("delete_hides");
+
+    let db = Db::open(&dir, Options::default()).unwrap();
+    db.set("active", "1").unwrap();
+    db.delete("active").unwrap();
+    assert_eq!(db.get("active").unwrap(), None);
+
+    // Queued for the flush thread, which doesn't run here
+    db.set("queued", "1").unwrap();
+    db.flush(false).unwrap();
+    db.delete("queued").unwrap();
+    assert_eq!(db.get("queued").unwrap(), None);
+
+    db.set("flushed", "1").unwrap();
+    db.flush(true).unwrap();
+    db.delete("flushed").unwrap();
+    assert_eq!(db.get("flushed").unwrap(), None);
+
+    // The tombstones reach a table and hide the older one
+    db.flush(true).unwrap();
+    assert_eq!(db.get("queued").unwrap(), None);
+    assert_eq!(db.get("flushed").unwrap(), None);
+    db.set("flushed", "2").unwrap();
+    assert_eq!(db.get("flushed").unwrap().as_deref(), Some("2"));
+    db.delete("flushed").unwrap();
+    drop(db);
+
+    let db = Db::open(&dir, Options::default()).unwrap();
+    assert_eq!(db.get("flushed").unwrap(), None);
+    assert_eq!(db.get("queued").unwrap(), None);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn compacting_the_oldest_table_drops_tombstones() {
+    let dir = temp_dir("tombstone_compaction");
+
+    let db = Db::open(&dir, Options::default()).unwrap();
+    db.set("a", "1").unwrap();
+    db.set("b", "1").unwrap();
+    db.flush(true).unwrap();
+    db.delete("a").unwrap();
+    db.flush(true).unwrap();
+
+    db.compact_range(None, None).unwrap();
+    assert_eq!(db.get("a").unwrap(), None);
+    assert_eq!(db.get("b").unwrap().as_deref(), Some("1"));
+    let tables = fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "idx"))
+        .collect::<Vec<_>>();
+    assert_eq!(tables.len(), 1);
+    let keys = IDX::from(tables[0].clone(), Arc::clone(db.options())).unwrap().records().unwrap()
+        .map(|record| record.unwrap().key)
+        .collect::<Vec<_>>();
+    assert_eq!(keys, ["b"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn write_ahead_log_is_replayed_on_open() {
+    let dir = temp_dir("write_ahead_log");
+
+    let options = || Options { merge_operator: Some(Arc::new(U64AddOperator)), ..Options::default() };
+    let db = Db::open(&dir, options()).unwrap();
+    let audit = db.create_column_family("audit", options()).unwrap();
+    let scratch = db.create_column_family("scratch", options()).unwrap();
+    db.set("flushed", "1").unwrap();
+    db.flush(true).unwrap();
+    db.set("user", "alice").unwrap();
+    db.merge("flushed", "2").unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(DEFAULT_COLUMN_FAMILY, "user", "bob").merge("audit", "events", "1");
+    db.write(&batch).unwrap();
+    scratch.set("gone", "1").unwrap();
+    drop((audit, scratch));
+    db.drop_column_family("scratch").unwrap();
+    // Nothing is flushed, the memtables die with the handle like in a crash
+    drop(db);
+
+    // A record cut short by the crash is ignored
+    let segment = Wal::segments(&dir).unwrap().pop().unwrap();
+    fs::OpenOptions::new().append(true).open(dir.join(Wal::segment_path(segment))).unwrap().write_all(&[9, 0, 0]).unwrap();
+
+    let db = Db::open(&dir, options()).unwrap();
+    let scratch = db.create_column_family("scratch", options()).unwrap();
+    assert_eq!(db.get("user").unwrap().as_deref(), Some("bob"));
+    assert_eq!(db.get("flushed").unwrap().as_deref(), Some("3"));
+    assert_eq!(db.column_family("audit").unwrap().get("events").unwrap().as_deref(), Some("1"));
+    assert_eq!(scratch.get("gone").unwrap(), None);
+
+    // Replayed writes were flushed, only the new segment is left
+    assert_eq!(db.default_column_family().memtable().get_instance().read().unwrap().count(), 0);
+    assert_eq!(Wal::segments(&dir).unwrap(), [segment + 1]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn invalid_keys_never_reach_the_log() {
+    let dir = temp_dir("invalid_keys");
+
+    let options = || Options { merge_operator: Some(Arc::new(U64AddOperator)), ..Options::default() };
+    let db = Db::open(&dir, options()).unwrap();
+    db.set("ok", "1").unwrap();
+    assert_eq!(db.set("bad-key", "v").unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(db.merge("bad-key", "1").unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(db.delete("bad-key").unwrap_err().kind(), ErrorKind::InvalidInput);
+    let mut batch = WriteBatch::new();
+    batch.put(DEFAULT_COLUMN_FAMILY, "fine", "1").put(DEFAULT_COLUMN_FAMILY, "waytoolongkey", "1");
+    assert_eq!(db.write(&batch).unwrap_err().kind(), ErrorKind::InvalidInput);
+    drop(db);
+
+    // The log replays and flushes on the next open
+    let db = Db::open(&dir, options()).unwrap();
+    assert_eq!(db.get("ok").unwrap().as_deref(), Some("1"));
+    assert_eq!(db.get("fine").unwrap(), None);
+    db.set("after", "2").unwrap();
+    db.flush(true).unwrap();
+    drop(db);
+    assert_eq!(Db::open(&dir, options()).unwrap().get("after").unwrap().as_deref(), Some("2"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn full_memtable_requests_flush() {
+    let dir = temp_dir("flush_signal");
+
+    let db = Db::open(&dir, Options { memtable_count_limit: Some(2), ..Options::default() }).unwrap();
+    db.set("a", "1").unwrap();
+    db.set("a", "2").unwrap();
+    db.set("b", "1").unwrap();
+
+    // Already pending, so `wait` returns right away
+    assert_eq!(db.flush_signal().wait().into_iter().collect::<Vec<_>>(), [DEFAULT_COLUMN_FAMILY]);
+
+    // The full memtable is still readable while it waits for the flush
+    let default = db.default_column_family();
+    assert_eq!(default.immutable_memtables(), 1);
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("2"));
+
+    assert_eq!(default.flush_immutables().unwrap(), 1);
+    assert_eq!(default.immutable_memtables(), 0);
+    assert_eq!(default.level0_files(), 1);
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("2"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn writes_stop_until_flushed() {
+    let dir = temp_dir("write_stall");
+
+    let db = Db::open(&dir, Options {
+        memtable_count_limit: Some(1),
+        max_immutable_memtables: 1,
+        write_stall_timeout: Duration::from_millis(50),
+        ..Options::default()
+    }).unwrap();
+    db.set("a", "1").unwrap();
+
+    let error = db.set("b", "1").unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::TimedOut);
+    assert_eq!(db.metrics().write_stops(StallReason::ImmutableMemtables), 1);
+
+    db.default_column_family().flush_immutables().unwrap();
+    db.set("b", "1").unwrap();
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn flush_writes_the_memtable() {
+    let dir = temp_dir("explicit_flush");
+
+    let db = Db::open(&dir, Options::default()).unwrap();
+    assert_eq!(db.flush(true).unwrap(), None);
+
+    db.set("a", "1").unwrap();
+    db.set("b", "2").unwrap();
+    let table = db.flush(true).unwrap().unwrap();
+    assert_eq!(fs::metadata(dir.join(format!("{}.sst", table.id))).unwrap().len(), table.size);
+
+    let default = db.default_column_family();
+    assert_eq!(default.memtable().get_instance().read().unwrap().count(), 0);
+    assert_eq!(default.immutable_memtables(), 0);
+    assert_eq!(default.level0_files(), 1);
+    assert_eq!(db.get("b").unwrap().as_deref(), Some("2"));
+
+    // Without waiting the memtable is only queued for the flush thread
+    db.set("c", "3").unwrap();
+    assert_eq!(db.flush(false).unwrap(), None);
+    assert_eq!(default.immutable_memtables(), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn value_log_gc_flushes_before_removing_the_segment() {
+    let dir = temp_dir("value_log_gc");
+
+    let options = Options { value_log_threshold: Some(4), value_log_segment_size: 80, ..Options::default() };
+    let db = Db::open(&dir, options).unwrap();
+    for key in ["a", "b", "c", "d"] {
+        db.set(key, &format!("first value of {key}")).unwrap();
+    }
+    db.flush(true).unwrap();
+    for key in ["a", "b", "c"] {
+        db.set(key, &format!("second value of {key}")).unwrap();
+    }
+    db.flush(true).unwrap();
+
+    let default = db.default_column_family();
+    let segment = default.value_log().segment(0).path;
+    assert_eq!(default.collect_value_log_garbage().unwrap(), 1);
+    assert!(!segment.exists());
+
+    // The rewritten value is in a table already, nothing is left to lose in the memtable
+    assert_eq!(default.memtable().get_instance().read().unwrap().count(), 0);
+    assert_eq!(default.immutable_memtables(), 0);
+    assert_eq!(db.get("d").unwrap().as_deref(), Some("first value of d"));
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("second value of a"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn background_threads_stop_on_shutdown() {
+    let dir = temp_dir("shutdown");
+
+    let db = Arc::new(Db::open(&dir, Options { memtable_count_limit: Some(1), ..Options::default() }).unwrap());
+    let flusher = std::thread::spawn({
+        let db = Arc::clone(&db);
+        move || sstable::avl::check_size(db)
+    });
+    let compactor = std::thread::spawn({
+        let db = Arc::clone(&db);
+        move || IDX::compaction(db)
+    });
+
+    db.set("a", "1").unwrap();
+    db.shutdown();
+    flusher.join().unwrap();
+    compactor.join().unwrap();
+
+    // The full memtable was flushed before the flush thread stopped
+    assert_eq!(db.default_column_family().immutable_memtables(), 0);
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("1"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn failing_flushes_do_not_hold_up_shutdown() {
+    let dir = temp_dir("shutdown_failing_flush");
+
+    let options = || Options { memtable_count_limit: Some(1), value_log_threshold: Some(0), ..Options::default() };
+    let db = Arc::new(Db::open(&dir, options()).unwrap());
+    let broken = db.create_column_family("broken", options()).unwrap();
+    // Values can't be appended to a segment that is a directory
+    fs::create_dir(dir.join("broken").join(ValueLog::segment_path(1))).unwrap();
+    broken.set("a", "1").unwrap();
+
+    let flusher = std::thread::spawn({
+        let db = Arc::clone(&db);
+        move || sstable::avl::check_size(db)
+    });
+    std::thread::sleep(Duration::from_millis(50));
+    let started = SystemTime::now();
+    db.shutdown();
+    flusher.join().unwrap();
+    assert!(started.elapsed().unwrap() < Duration::from_secs(1));
+    assert_eq!(broken.immutable_memtables(), 1);
+
+    drop((broken, db));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn checkpoint_is_an_openable_copy() {
+    let dir = temp_dir("checkpoint_source");
+    let dest = temp_dir("checkpoint");
+
+    let options = || Options { value_log_threshold: Some(8), ..Options::default() };
+    let db = Db::open(&dir, options()).unwrap();
+    let sessions = db.create_column_family("sessions", options()).unwrap();
+    db.set("flushed", "in a table").unwrap();
+    db.flush(true).unwrap();
+    db.set("memtable", "1").unwrap();
+    sessions.set("token", "a value long enough for the value log").unwrap();
+
+    db.checkpoint(&dest).unwrap();
+    assert!(db.checkpoint(&dest).is_err());
+
+    // Later writes to the source don't show up in the checkpoint
+    db.set("memtable", "2").unwrap();
+    db.flush(true).unwrap();
+    db.compact_range(None, None).unwrap();
+
+    let copy = Db::open(&dest, options()).unwrap();
+    assert_eq!(copy.get("flushed").unwrap().as_deref(), Some("in a table"));
+    assert_eq!(copy.get("memtable").unwrap().as_deref(), Some("1"));
+    let token = copy.column_family("sessions").unwrap().get("token").unwrap();
+    assert_eq!(token.as_deref(), Some("a value long enough for the value log"));
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&dest).unwrap();
+}
+
+#[test]
+fn tables_record_their_comparator() {
+    let dir = temp_dir("table_comparator");
+    let external = temp_dir("table_comparator_external");
+    fs::create_dir_all(&external).unwrap();
+
+    let db = Db::open(&dir, Options::default()).unwrap();
+    let numeric = Arc::new(Options { comparator: Arc::new(NumericComparator), ..Options::default() });
+    let mut writer = SstWriter::create(external.join("bulk"), numeric).unwrap();
+    writer.put("9", "nine").unwrap();
+    writer.put("10", "ten").unwrap();
+    let table = writer.finish().unwrap();
+    assert_eq!(IDX::from(table.clone(), Arc::clone(db.options())).unwrap().sst().comparator().unwrap().as_deref(), Some("numeric"));
+
+    let error = db.ingest_external_files(std::slice::from_ref(&table)).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    assert_eq!(db.get("9").unwrap(), None);
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&external).unwrap();
+}
+
+#[test]
+fn flushes_sort_above_every_table() {
+    let dir = temp_dir("flush_order");
+    let db = Db::open(&dir, Options::default()).unwrap();
+
+    // A compacted table keeps the timestamp of its oldest input, which may be the current second
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    IDX::new(&dir, Some(format!("{now}_1")), Arc::clone(db.options())).set_key("a", "compacted").unwrap();
+
+    db.set("a", "flushed").unwrap();
+    db.flush(true).unwrap();
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("flushed"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn ingested_tables_are_newest() {
+    let dir = temp_dir("ingest");
+    let external = temp_dir("ingest_external");
+    fs::create_dir_all(&external).unwrap();
+
+    let db = Db::open(&dir, Options::default()).unwrap();
+    db.set("a", "old").unwrap();
+    db.set("z", "kept").unwrap();
+
+    let mut writer = SstWriter::create(external.join("bulk"), Arc::clone(db.options())).unwrap();
+    writer.put("a", "ingested").unwrap();
+    writer.put("b", "ingested").unwrap();
+    assert_eq!(writer.put("b", "again").unwrap_err().kind(), ErrorKind::InvalidInput);
+    let table = writer.finish().unwrap();
+
+    let ids = db.ingest_external_files(std::slice::from_ref(&table)).unwrap();
+    assert_eq!(ids.len(), 1);
+    assert!(table.exists());
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("ingested"));
+    assert_eq!(db.get("b").unwrap().as_deref(), Some("ingested"));
+    assert_eq!(db.get("z").unwrap().as_deref(), Some("kept"));
+    assert_eq!(db.default_column_family().level0_files(), 2);
+
+    assert_eq!(db.ingest_external_files(&[external.join("missing.idx")]).unwrap_err().kind(), ErrorKind::NotFound);
+
+    // A damaged table is refused instead of panicking
+    let mut writer = SstWriter::create(external.join("damaged"), Arc::clone(db.options())).unwrap();
+    writer.put("c", "damaged").unwrap();
+    let damaged = writer.finish().unwrap();
+    let sst = damaged.with_extension("sst");
+    let len = fs::metadata(&sst).unwrap().len();
+    fs::OpenOptions::new().write(true).open(&sst).unwrap().set_len(len - 3).unwrap();
+    assert_eq!(db.ingest_external_files(&[damaged]).unwrap_err().kind(), ErrorKind::InvalidData);
+    assert_eq!(db.get("c").unwrap(), None);
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&external).unwrap();
+}
+
+#[test]
+fn migration_keeps_the_newest_values() {
+    let source = temp_dir("migrate_source");
+    let dest = temp_dir("migrate_dest");
+
+    let db = Db::open(&source, Options::default()).unwrap();
+    let sessions = db.create_column_family("sessions", Options::default()).unwrap();
+    db.set("a", "old").unwrap();
+    db.flush(true).unwrap();
+    db.set("a", "new").unwrap();
+    db.set("b", "1").unwrap();
+    db.flush(true).unwrap();
+    sessions.set("s", "x").unwrap();
+    sessions.flush(true).unwrap();
+    drop(sessions);
+    drop(db);
+
+    let migrated = migrate::migrate(&source, &dest, Options::default()).unwrap();
+    let counts = migrated.iter().map(|family| (family.name.as_str(), family.tables, family.entries)).collect::<Vec<_>>();
+    assert_eq!(counts, vec![(DEFAULT_COLUMN_FAMILY, 2, 2), ("sessions", 1, 1)]);
+
+    let db = Db::open(&dest, Options::default()).unwrap();
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("new"));
+    assert_eq!(db.column_family("sessions").unwrap().get("s").unwrap().as_deref(), Some("x"));
+    assert_eq!(db.default_column_family().level0_files(), 1);
+
+    assert_eq!(migrate::migrate(&source, &dest, Options::default()).unwrap_err().kind(), ErrorKind::AlreadyExists);
+
+    fs::remove_dir_all(&source).unwrap();
+    fs::remove_dir_all(&dest).unwrap();
+}
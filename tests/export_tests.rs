@@ -0,0 +1,90 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use sstable::db::{Db, DEFAULT_COLUMN_FAMILY};
+use sstable::export::{self, Format};
+use sstable::merge::U64AddOperator;
+use sstable::options::Options;
+use sstable::snapshot::Snapshot;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sstable_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn options() -> Options {
+    Options { merge_operator: Some(Arc::new(U64AddOperator)), value_log_threshold: Some(16), ..Options::default() }
+}
+
+#[test]
+fn export_and_import_round_trip() {
+    let dir = temp_dir("export_source");
+    let db = Db::open(&dir, options()).unwrap();
+
+    db.set("counter", "1").unwrap();
+    db.set("quoted", "a \"b\", c\nd").unwrap();
+    db.flush(true).unwrap();
+    db.merge("counter", "2").unwrap();
+    db.set("large", "a value that goes to the value log").unwrap();
+
+    for (format, name) in [(Format::JsonLines, "jsonl"), (Format::Csv, "csv")] {
+        let mut out = Vec::new();
+        assert_eq!(export::export(&db.default_column_family(), format, &mut out).unwrap(), 3);
+
+        let copy_dir = temp_dir(&format!("export_{name}"));
+        let copy = Db::open(&copy_dir, options()).unwrap();
+        assert_eq!(export::import(&copy, DEFAULT_COLUMN_FAMILY, format, out.as_slice()).unwrap(), 3);
+
+        assert_eq!(copy.get("counter").unwrap().as_deref(), Some("3"));
+        assert_eq!(copy.get("quoted").unwrap().as_deref(), Some("a \"b\", c\nd"));
+        assert_eq!(copy.get("large").unwrap().as_deref(), Some("a value that goes to the value log"));
+        fs::remove_dir_all(&copy_dir).unwrap();
+    }
+
+    let mut out = Vec::new();
+    export::export(&db.default_column_family(), Format::JsonLines, &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap().lines().next().unwrap(), r#"{"key":"counter","value":"3"}"#);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn snapshot_ignores_later_writes() {
+    let dir = temp_dir("snapshot");
+    let db = Db::open(&dir, options()).unwrap();
+    db.set("a", "1").unwrap();
+
+    let snapshot = Snapshot::new(&db.default_column_family()).unwrap();
+    // The memtable went into the snapshot only, the family was not flushed
+    assert_eq!(db.default_column_family().level0_files(), 0);
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("1"));
+    db.set("a", "2").unwrap();
+    db.set("b", "2").unwrap();
+    db.flush(true).unwrap();
+    db.compact_range(None, None).unwrap();
+
+    assert_eq!(snapshot.get("a").unwrap().as_deref(), Some("1"));
+    assert_eq!(snapshot.entries().unwrap().map(|entry| entry.unwrap().0).collect::<Vec<_>>(), ["a"]);
+    drop(snapshot);
+    let dirs = || fs::read_dir(&dir).unwrap().filter(|entry| entry.as_ref().unwrap().path().is_dir()).count();
+    assert_eq!(dirs(), 0);
+
+    // A snapshot left behind by a process that died is removed on open
+    std::mem::forget(Snapshot::new(&db.default_column_family()).unwrap());
+    let stale = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).find(|path| path.is_dir()).unwrap();
+    let mut dead = std::process::Command::new("true").spawn().unwrap();
+    dead.wait().unwrap();
+    fs::rename(&stale, dir.join(format!(".snapshot_{}_0", dead.id()))).unwrap();
+    // One of a live process is kept
+    let live = Snapshot::new(&db.default_column_family()).unwrap();
+    drop(db);
+    assert_eq!(dirs(), 2);
+    let db = Db::open(&dir, options()).unwrap();
+    assert_eq!(dirs(), 1);
+    assert_eq!(live.get("a").unwrap().as_deref(), Some("2"));
+    drop((live, db));
+    assert_eq!(dirs(), 0);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,97 @@
+use std::fs;
+use std::sync::Arc;
+use sstable::avl::AVLTree;
+use sstable::db::{Db, DEFAULT_COLUMN_FAMILY};
+use sstable::idx::IDX;
+use sstable::options::Options;
+use sstable::sst::SSTValue;
+use sstable::merge::{MaxOperator, MergeOperator, StringAppendOperator, U64AddOperator};
+use sstable::write_batch::WriteBatch;
+
+fn operands(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+#[test]
+fn built_in_operators() {
+    let add = U64AddOperator;
+    assert_eq!(add.full_merge("counter", None, &operands(&["1", "2"])).unwrap(), "3");
+    assert_eq!(add.full_merge("counter", Some("10"), &operands(&["5"])).unwrap(), "15");
+    assert!(add.full_merge("counter", Some("ten"), &operands(&["5"])).is_err());
+    assert!(add.full_merge("counter", Some(&u64::MAX.to_string()), &operands(&["1"])).is_err());
+
+    let append = StringAppendOperator::default();
+    assert_eq!(append.full_merge("list", None, &operands(&["a", "b"])).unwrap(), "a,b");
+    assert_eq!(append.full_merge("list", Some("a"), &operands(&["b"])).unwrap(), "a,b");
+
+    let max = MaxOperator;
+    assert_eq!(max.full_merge("max", Some("9"), &operands(&["10", "3"])).unwrap(), "10");
+    assert_eq!(max.full_merge("max", None, &operands(&["3"])).unwrap(), "3");
+
+    assert!(add.check_operand("counter", "5").is_ok());
+    assert!(add.check_operand("counter", "-5").is_err());
+    assert!(max.check_operand("max", "abc").is_err());
+    assert!(append.check_operand("list", "abc").is_ok());
+}
+
+#[test]
+fn invalid_operands_are_refused_wherever_the_value_is() {
+    let dir = std::env::temp_dir().join(format!("sstable_invalid_operands_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let db = Db::open(&dir, Options { merge_operator: Some(Arc::new(U64AddOperator)), ..Options::default() }).unwrap();
+
+    db.set("flushed", "1").unwrap();
+    db.flush(true).unwrap();
+    db.set("memtable", "1").unwrap();
+    for key in ["flushed", "memtable", "absent"] {
+        assert!(db.merge(key, "abc").is_err(), "{key}");
+    }
+    let mut batch = WriteBatch::new();
+    batch.merge(DEFAULT_COLUMN_FAMILY, "flushed", "2").merge(DEFAULT_COLUMN_FAMILY, "absent", "abc");
+    assert!(db.write(&batch).is_err());
+
+    assert_eq!(db.get("flushed").unwrap().as_deref(), Some("1"));
+    assert_eq!(db.get("absent").unwrap(), None);
+    drop(db);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn memtable_keeps_operands_until_set() {
+    let mut tree = AVLTree::new();
+    tree.merge("counter", "1");
+    tree.merge("counter", "2");
+
+    let node = tree.get("counter").unwrap();
+    assert_eq!(node.operands, operands(&["1", "2"]));
+
+    tree.set("counter", "7");
+    let node = tree.get("counter").unwrap();
+    assert_eq!(node.value, "7");
+    assert!(node.operands.is_empty());
+}
+
+#[test]
+fn refused_operands_are_dropped_by_reads_and_compaction() {
+    let dir = std::env::temp_dir().join(format!("sstable_refused_operands_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let options = Arc::new(Options { merge_operator: Some(Arc::new(U64AddOperator)), ..Options::default() });
+
+    IDX::new(&dir, Some("100".to_string()), Arc::clone(&options)).set_key("counter", &(u64::MAX - 1).to_string()).unwrap();
+    let operands_table = IDX::new(&dir, Some("101".to_string()), Arc::clone(&options));
+    operands_table.set_entry("counter", &SSTValue::Merge(operands(&["5", "1"]))).unwrap();
+
+    // Iterating values can't fold a merge record on its own, but it doesn't panic either
+    assert!(operands_table.iter().unwrap().next().unwrap().is_err());
+
+    let read = IDX::search_key_in_all_files(&dir, "counter", Vec::new(), &options).unwrap().unwrap();
+    assert_eq!(read.value, u64::MAX.to_string());
+
+    let inputs = [dir.join("100.idx"), dir.join("101.idx")];
+    assert_eq!(IDX::compact_tables(&dir, &inputs, &options).unwrap(), Some(dir.join("100_1.idx")));
+    let read = IDX::search_key_in_all_files(&dir, "counter", Vec::new(), &options).unwrap().unwrap();
+    assert_eq!(read.value, u64::MAX.to_string());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
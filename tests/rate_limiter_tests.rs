@@ -0,0 +1,19 @@
+use std::time::{Duration, Instant};
+use sstable::rate_limiter::{IoPriority, RateLimiter};
+
+#[test]
+fn throttles_to_the_configured_rate() {
+    let rate_limiter = RateLimiter::new(1000);
+
+    // The bucket starts full, the next request has to wait for it to refill
+    rate_limiter.request(1000, IoPriority::High);
+    let started = Instant::now();
+    rate_limiter.request(500, IoPriority::Low);
+    assert!(started.elapsed() >= Duration::from_millis(400));
+
+    rate_limiter.set_bytes_per_second(0);
+    assert_eq!(rate_limiter.bytes_per_second(), 0);
+    let started = Instant::now();
+    rate_limiter.request(1024 * 1024, IoPriority::Low);
+    assert!(started.elapsed() < Duration::from_millis(100));
+}
@@ -0,0 +1,129 @@
+use std::fs;
+use std::io::Write;
+use std::sync::Arc;
+use sstable::idx::IDX;
+use sstable::options::{Options, ReaderBackend};
+use sstable::sst::{SST, SSTValue};
+use sstable::vlog::{ValueLog, ValuePointer};
+
+#[test]
+fn reader_backends_return_same_values() {
+    let path = std::env::temp_dir().join(format!("sstable_reader_backends_{}.sst", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let writer = SST::new(path.clone(), Arc::new(Options::default()));
+    let first_offset = writer.set("first", "value").unwrap();
+    let second_offset = writer.set("second", "").unwrap();
+
+    for reader in [ReaderBackend::Buffered, ReaderBackend::Mmap] {
+        let sst = SST::new(path.clone(), Arc::new(Options { reader, ..Options::default() }));
+        assert_eq!(sst.get("first", first_offset).unwrap(), "value");
+        assert_eq!(sst.get("second", second_offset).unwrap(), "");
+
+        // Offset points to another key
+        assert!(sst.get("first", second_offset).is_err());
+    }
+
+    let sst = SST::new(path.clone(), Arc::new(Options { reader: ReaderBackend::Mmap, ..Options::default() }));
+    let mapped = sst.get_mapped("first", first_offset).unwrap();
+    assert_eq!(mapped.as_bytes(), b"value");
+    assert!(sst.get_mapped("first", u32::MAX as u64).is_err());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn reader_backends_decode_invalid_utf8_alike() {
+    let path = std::env::temp_dir().join(format!("sstable_reader_utf8_{}.sst", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let writer = SST::new(path.clone(), Arc::new(Options::default()));
+    let offset = writer.set("bad", "ab").unwrap();
+    // Key length, key and value length come before the payload
+    let mut bytes = fs::read(&path).unwrap();
+    bytes[offset as usize + 1 + "bad".len() + 4] = 0xff;
+    fs::write(&path, bytes).unwrap();
+
+    for reader in [ReaderBackend::Buffered, ReaderBackend::Mmap] {
+        let sst = SST::new(path.clone(), Arc::new(Options { reader, ..Options::default() }));
+        assert_eq!(sst.get("bad", offset).unwrap(), "\u{fffd}b", "{reader:?}");
+    }
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn pointer_records_resolve_through_value_log() {
+    let dir = std::env::temp_dir().join(format!("sstable_value_log_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let value = "v".repeat(1024);
+    let segment = SST::new(dir.join(ValueLog::segment_path(7)), Arc::new(Options::default()));
+    let value_offset = segment.set("large", &value).unwrap();
+
+    let pointer = ValuePointer { segment: 7, offset: value_offset, len: value.len() as u32 };
+    assert_eq!(ValuePointer::decode(&pointer.encode()).unwrap(), pointer);
+
+    let table = SST::new(dir.join("1.sst"), Arc::new(Options::default()));
+    let small_offset = table.set("small", "inline").unwrap();
+    let large_offset = table.set_entry("large", &SSTValue::Pointer(pointer)).unwrap();
+
+    assert_eq!(table.get_entry("large", large_offset).unwrap(), SSTValue::Pointer(pointer));
+    for reader in [ReaderBackend::Buffered, ReaderBackend::Mmap] {
+        let table = SST::new(dir.join("1.sst"), Arc::new(Options { reader, ..Options::default() }));
+        assert_eq!(table.get("large", large_offset).unwrap(), value);
+        assert_eq!(table.get("small", small_offset).unwrap(), "inline");
+    }
+
+    let records = table.iter().unwrap().collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[1].offset, large_offset);
+    assert_eq!(records[1].value, SSTValue::Pointer(pointer));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn merge_records_keep_operands() {
+    let path = std::env::temp_dir().join(format!("sstable_merge_records_{}.sst", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let operands = vec!["1".to_string(), "".to_string(), "20".to_string()];
+    let table = SST::new(path.clone(), Arc::new(Options::default()));
+    let offset = table.set_entry("counter", &SSTValue::Merge(operands.clone())).unwrap();
+
+    for reader in [ReaderBackend::Buffered, ReaderBackend::Mmap] {
+        let table = SST::new(path.clone(), Arc::new(Options { reader, ..Options::default() }));
+        assert_eq!(table.get_entry("counter", offset).unwrap(), SSTValue::Merge(operands.clone()));
+        // Operands are not a value on their own
+        assert!(table.get("counter", offset).is_err());
+    }
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn idx_records_report_offsets() {
+    let dir = std::env::temp_dir().join(format!("sstable_idx_records_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let idx = IDX::new(&dir, Some("1".to_string()), Arc::new(Options::default()));
+    idx.set_entry("a", &SSTValue::Inline("1".to_string())).unwrap();
+    idx.set_entry("b", &SSTValue::Inline("22".to_string())).unwrap();
+
+    let records = idx.records().unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+    let offsets = records.iter().map(|record| (record.idx_offset, record.sst_offset)).collect::<Vec<_>>();
+    // Index entries are 1 + key + 8 bytes, records 1 + key + 4 + value bytes after the comparator record
+    assert_eq!(offsets, vec![(0, 24), (10, 31)]);
+    assert_eq!(records[1].value, SSTValue::Inline("22".to_string()));
+
+    // A cut off entry is left unread
+    fs::OpenOptions::new().append(true).open(idx.path()).unwrap().write_all(&[5, b'c']).unwrap();
+    let mut iter = idx.records().unwrap();
+    assert_eq!(iter.by_ref().count(), 2);
+    assert_eq!(iter.position(), 20);
+
+    fs::remove_dir_all(&dir).unwrap();
+}